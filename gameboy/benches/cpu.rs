@@ -0,0 +1,202 @@
+// CPU throughput benchmarks, run with
+//   cargo bench --bench cpu
+// Each workload is run a few times and the best and median times are printed:
+//   nop, ld, alu, cb  the same instruction over and over on flat test memory, NOP shows the cost of
+//                     everything around the instruction itself
+//   sm83   every case of the SM83 single step tests in tests/v1, so all opcodes are weighted equally
+//   loop   a program mixing loads, ALU, CB and stack instructions on flat test memory, only the CPU runs
+//   frame  whole frames of the same program from a ROM, with the PPU, APU and timers running and rewind off
+//   rewind the same frames with a rewind snapshot every other frame in 8 MB
+// Set SM83_OPCODES like for tests/sm83.rs to only run some of the single step tests
+
+use std::fs::{self, File};
+use std::hint::black_box;
+use std::io::BufReader;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use gameboy::cpu::CPU;
+use gameboy::debugger::Register;
+use gameboy::header::compute_header_checksum;
+use gameboy::memory::Memory;
+use gameboy::save::MemoryStore;
+use gameboy::state::CpuTest;
+use gameboy::GameBoy;
+
+const TEST_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/v1");
+const RUNS: usize = 7;
+const STREAM_INSTRUCTIONS: usize = 5_000_000;
+const LOOP_CYCLES: u64 = 20_000_000;
+const FRAMES: usize = 600;
+// M-cycles per second of a DMG
+const CLOCK: f64 = 1_048_576.0;
+
+// Starts at 0x0150, 0x0100 jumps here like in a real ROM.
+// Copies 256 bytes from 0xC000 to 0xD000 with some arithmetic on each byte, then starts over
+const PROGRAM: [u8; 0x3F] = [
+    0x31, 0xFE, 0xFF,       // 0150 LD SP, $FFFE
+    0x21, 0x00, 0xC0,       // 0153 LD HL, $C000
+    0x11, 0x00, 0xD0,       // 0156 LD DE, $D000
+    0x01, 0x00, 0x01,       // 0159 LD BC, $0100
+    0x2A,                   // 015C LD A, (HL+)
+    0x12,                   // 015D LD (DE), A
+    0x13,                   // 015E INC DE
+    0x80,                   // 015F ADD A, B
+    0xA9,                   // 0160 XOR C
+    0xCB, 0x37,             // 0161 SWAP A
+    0xCB, 0x47,             // 0163 BIT 0, A
+    0x28, 0x01,             // 0165 JR Z, $0168
+    0x3C,                   // 0167 INC A
+    0xC5,                   // 0168 PUSH BC
+    0xCD, 0x80, 0x01,       // 0169 CALL $0180
+    0xC1,                   // 016C POP BC
+    0x0B,                   // 016D DEC BC
+    0x78,                   // 016E LD A, B
+    0xB1,                   // 016F OR C
+    0x20, 0xEA,             // 0170 JR NZ, $015C
+    0xC3, 0x53, 0x01,       // 0172 JP $0153
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x87,                   // 0180 ADD A, A
+    0x8F,                   // 0181 ADC A, A
+    0xD6, 0x03,             // 0182 SUB $03
+    0xCB, 0x19,             // 0184 RR C
+    0xCB, 0x11,             // 0186 RL C
+    0xFE, 0x40,             // 0188 CP $40
+    0x30, 0x01,             // 018A JR NC, $018D
+    0x2F,                   // 018C CPL
+    0x77,                   // 018D LD (HL), A
+    0xC9,                   // 018E RET
+];
+const ENTRY: [u8; 4] = [0x00, 0xC3, 0x50, 0x01];
+
+fn main() {
+    println!("{:<6} {:>12} {:>12} {:>14}", "", "best", "median", "");
+    bench_stream("nop", &[0x00]);
+    bench_stream("ld", &[0x41]); // LD B, C
+    bench_stream("alu", &[0x80]); // ADD A, B
+    bench_stream("cb", &[0xCB, 0x11]); // RL C
+    bench_sm83();
+    bench_loop();
+    bench_frame("frame", 0, 1);
+    bench_frame("rewind", 8 * 1024 * 1024, 2);
+}
+
+// Prints the best and median of the runs, with rate turning a duration into a throughput line
+fn report<F: Fn(Duration) -> String>(name: &str, mut times: Vec<Duration>, rate: F) {
+    times.sort();
+    let best = times[0];
+    let median = times[times.len() / 2];
+    println!("{:<6} {:>10.2}ms {:>10.2}ms   {}", name, ms(best), ms(median), rate(best));
+}
+
+fn ms(d: Duration) -> f64 {
+    d.as_secs_f64() * 1000.0
+}
+
+// Fills the ROM area with the instruction and runs it, jumping back to the start before the end
+fn bench_stream(name: &str, instruction: &[u8]) {
+    let mut times = Vec::new();
+    for _ in 0..RUNS {
+        let mut mem = Memory::new(None);
+        for (i, b) in instruction.iter().cycle().take(0x8000).enumerate() {
+            mem.write(i as u16, *b);
+        }
+        let mut cpu = CPU::new();
+
+        let start = Instant::now();
+        for _ in 0..STREAM_INSTRUCTIONS {
+            cpu.run(&mut mem);
+            if cpu.pc() >= 0x7F00 {
+                cpu.set_register(Register::PC, 0);
+            }
+        }
+        times.push(start.elapsed());
+        black_box(&cpu);
+    }
+    report(name, times, |best| format!("{:.1} ns/instruction", best.as_nanos() as f64 / STREAM_INSTRUCTIONS as f64));
+}
+
+fn bench_sm83() {
+    let filter: Vec<String> = std::env::var("SM83_OPCODES")
+        .unwrap_or_default()
+        .split(',')
+        .map(|s| s.trim().to_lowercase())
+        .filter(|s| !s.is_empty())
+        .collect();
+    let mut files: Vec<PathBuf> = fs::read_dir(TEST_DIR)
+        .expect("SM83 test directory should exist")
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .filter(|path| {
+            let name = path.file_stem().unwrap().to_string_lossy().to_lowercase();
+            filter.is_empty() || filter.iter().any(|f| name.starts_with(f.as_str()))
+        })
+        .collect();
+    files.sort();
+
+    // Parsing the JSON takes far longer than running it, so only the runs are timed, a file at a time
+    let mut times = vec![Duration::ZERO; RUNS];
+    let mut instructions = 0;
+    let mut cpu = CPU::new();
+    let mut mem = Memory::new(None);
+    for path in &files {
+        let tests: Vec<CpuTest> = serde_json::from_reader(BufReader::new(File::open(path).unwrap())).unwrap();
+        instructions += tests.len();
+        for time in times.iter_mut() {
+            let start = Instant::now();
+            for test in &tests {
+                cpu.load_state(&test.initial);
+                mem.load_state(&test.initial);
+                black_box(cpu.run(&mut mem));
+            }
+            *time += start.elapsed();
+        }
+    }
+    report("sm83", times, |best| format!("{:.1} ns/instruction", best.as_nanos() as f64 / instructions as f64));
+}
+
+fn bench_loop() {
+    let mut times = Vec::new();
+    for _ in 0..RUNS {
+        let mut mem = Memory::new(None);
+        for (i, b) in ENTRY.iter().enumerate() {
+            mem.write(0x0100 + i as u16, *b);
+        }
+        for (i, b) in PROGRAM.iter().enumerate() {
+            mem.write(0x0150 + i as u16, *b);
+        }
+        let mut cpu = CPU::new();
+        cpu.simulate_bootloader();
+
+        let start = Instant::now();
+        let mut cycles = 0;
+        while cycles < LOOP_CYCLES {
+            cycles += cpu.run(&mut mem) as u64;
+        }
+        times.push(start.elapsed());
+        black_box(&cpu);
+    }
+    report("loop", times, |best| format!("{:.0}x real time", LOOP_CYCLES as f64 / CLOCK / best.as_secs_f64()));
+}
+
+// Rewind budget and interval as passed to GameBoy::set_rewind, a budget of 0 turns it off
+fn bench_frame(name: &str, rewind_budget: usize, rewind_interval: u32) {
+    let mut rom = vec![0; 0x8000];
+    rom[0x0100..0x0104].copy_from_slice(&ENTRY);
+    rom[0x0150..0x0150 + PROGRAM.len()].copy_from_slice(&PROGRAM);
+    rom[0x014D] = compute_header_checksum(&rom);
+
+    let mut times = Vec::new();
+    for _ in 0..RUNS {
+        let mut gb = GameBoy::from_rom(rom.clone(), "bench".to_string(), Box::new(MemoryStore::new())).unwrap();
+        gb.start();
+        gb.set_rewind(rewind_budget, rewind_interval);
+
+        let start = Instant::now();
+        for _ in 0..FRAMES {
+            black_box(gb.run());
+        }
+        times.push(start.elapsed());
+    }
+    report(name, times, |best| format!("{:.3} ms/frame", ms(best) / FRAMES as f64));
+}
@@ -0,0 +1,34 @@
+// Loads a ROM and waits for GDB to attach on localhost
+//   cargo run --example gdb_server -- game.gb 2345
+// then in GDB: target remote localhost:2345
+
+use std::{env, fs, path::Path, process};
+
+use gameboy::{gdb, save::FileStore, GameBoy};
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 2 {
+        eprintln!("Usage: {} <rom> [port]", args[0]);
+        process::exit(1);
+    }
+    let path = Path::new(&args[1]);
+    let port = args.get(2).and_then(|p| p.parse().ok()).unwrap_or(2345);
+
+    let data = fs::read(path).unwrap_or_else(|e| {
+        eprintln!("Couldn't read {}: {}", path.display(), e);
+        process::exit(1);
+    });
+    let name = path.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+    let save_dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let mut gb = GameBoy::from_rom(data, name, Box::new(FileStore::new(save_dir))).unwrap_or_else(|e| {
+        eprintln!("Couldn't load {}: {}", path.display(), e);
+        process::exit(1);
+    });
+    gb.start();
+
+    println!("Waiting for GDB on localhost:{}", port);
+    if let Err(e) = gdb::serve(&mut gb, port) {
+        eprintln!("GDB session ended: {}", e);
+    }
+}
@@ -0,0 +1,800 @@
+// Audio processing unit, 0xFF10-0xFF3F. Behaviour source: Pandocs and the "Game Boy Sound Hardware" wiki page
+//   0xFF10-0xFF14  Channel 1, square with frequency sweep
+//   0xFF16-0xFF19  Channel 2, square
+//   0xFF1A-0xFF1E  Channel 3, wave
+//   0xFF20-0xFF23  Channel 4, noise
+//   0xFF24         NR50 master volume
+//   0xFF25         NR51 panning
+//   0xFF26         NR52 power and channel status
+//   0xFF30-0xFF3F  Wave RAM, 32 4-bit samples
+// Channel timers run on T-cycles, the APU is stepped in M-cycles like the rest of the machine.
+// The frame sequencer runs at 512 Hz, clocked by bit 4 of DIV going from 1 to 0
+
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+const NR10: u16 = 0xFF10;
+const NR52: u16 = 0xFF26;
+const WAVE_RAM: u16 = 0xFF30;
+
+// Bits that always read back as 1, indexed from 0xFF10
+const READ_MASK: [u8; 0x20] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, // NR10-NR14
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF, // NR20-NR24
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF, // NR30-NR34
+    0xFF, 0xFF, 0x00, 0x00, 0xBF, // NR40-NR44
+    0x00, 0x00, 0x70,             // NR50-NR52
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+];
+
+const DUTY: [[u8; 8]; 4] = [
+    [0, 0, 0, 0, 0, 0, 0, 1],
+    [1, 0, 0, 0, 0, 0, 0, 1],
+    [1, 0, 0, 0, 0, 1, 1, 1],
+    [0, 1, 1, 1, 1, 1, 1, 0],
+];
+
+const NOISE_DIVISORS: [i32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+pub struct Apu {
+    regs: [u8; 0x20],
+    wave_ram: [u8; 16],
+    powered: bool,
+    ch1: Square,
+    ch2: Square,
+    ch3: Wave,
+    ch4: Noise,
+    frame_step: u8,
+    div_bit: bool,
+}
+
+impl Apu {
+    pub fn new() -> Apu {
+        Apu {
+            regs: [0; 0x20],
+            wave_ram: [0; 16],
+            powered: false,
+            ch1: Square::new(),
+            ch2: Square::new(),
+            ch3: Wave::new(),
+            ch4: Noise::new(),
+            frame_step: 0,
+            div_bit: false,
+        }
+    }
+
+    pub fn read(&self, loc: u16) -> u8 {
+        match loc {
+            NR52 => {
+                let status = (self.ch1.enabled as u8)
+                    | (self.ch2.enabled as u8) << 1
+                    | (self.ch3.enabled as u8) << 2
+                    | (self.ch4.enabled as u8) << 3;
+                ((self.powered as u8) << 7) | 0x70 | status
+            },
+            0xFF10..=0xFF2F => self.regs[(loc - NR10) as usize] | READ_MASK[(loc - NR10) as usize],
+            0xFF30..=0xFF3F => self.wave_ram[(loc - WAVE_RAM) as usize],
+            _ => 0xFF
+        }
+    }
+
+    pub fn write(&mut self, loc: u16, val: u8) {
+        if let 0xFF30..=0xFF3F = loc {
+            self.wave_ram[(loc - WAVE_RAM) as usize] = val;
+            return
+        }
+        if loc == NR52 {
+            self.set_power(val & 0x80 > 0);
+            return
+        }
+        // Everything except NR52 and wave RAM ignores writes while the APU is off
+        if !self.powered || !(0xFF10..=0xFF25).contains(&loc) {
+            return
+        }
+
+        self.regs[(loc - NR10) as usize] = val;
+        match loc {
+            0xFF10..=0xFF14 => self.ch1.write((loc - 0xFF10) as u8, val),
+            0xFF16..=0xFF19 => self.ch2.write((loc - 0xFF15) as u8, val),
+            0xFF1A..=0xFF1E => self.ch3.write((loc - 0xFF1A) as u8, val),
+            0xFF20..=0xFF23 => self.ch4.write((loc - 0xFF1F) as u8, val),
+            _ => {}
+        }
+    }
+
+    // Register values left behind by the boot ROM. Channel 1 is still on after the startup sound,
+    // but its envelope has faded out
+    pub fn simulate_bootloader(&mut self) {
+        self.write(0xff26, 0xf1);
+        self.write(0xff10, 0x80);
+        self.write(0xff11, 0xbf);
+        self.write(0xff12, 0xf3);
+        self.write(0xff13, 0xff);
+        self.write(0xff14, 0xbf);
+        self.write(0xff16, 0x3f);
+        self.write(0xff17, 0x00);
+        self.write(0xff18, 0xff);
+        self.write(0xff19, 0xbf);
+        self.write(0xff1a, 0x7f);
+        self.write(0xff1b, 0xff);
+        self.write(0xff1c, 0x9f);
+        self.write(0xff1d, 0xff);
+        self.write(0xff1e, 0xbf);
+        self.write(0xff20, 0xff);
+        self.write(0xff21, 0x00);
+        self.write(0xff22, 0x00);
+        self.write(0xff23, 0xbf);
+        self.write(0xff24, 0x77);
+        self.write(0xff25, 0xf3);
+        self.ch1.envelope.volume = 0;
+    }
+
+    fn set_power(&mut self, on: bool) {
+        if on && !self.powered {
+            self.frame_step = 0;
+        }
+        if !on {
+            // Powering off clears every register, wave RAM is kept
+            self.regs = [0; 0x20];
+            self.ch1 = Square::new();
+            self.ch2 = Square::new();
+            self.ch3 = Wave::new();
+            self.ch4 = Noise::new();
+        }
+        self.powered = on;
+    }
+
+    // Advances the APU by the given number of M-cycles. div is the current value of the DIV register.
+    // sample is called after every M-cycle, also while the APU is off so the output keeps its pace
+    pub fn tick(&mut self, cycles: u8, div: u8, mut sample: impl FnMut(&Apu)) {
+        let div_bit = div & 0x10 > 0;
+        if self.div_bit && !div_bit && self.powered {
+            self.step_frame_sequencer();
+        }
+        self.div_bit = div_bit;
+
+        for _ in 0..cycles {
+            if self.powered {
+                self.ch1.step(4);
+                self.ch2.step(4);
+                self.ch3.step(4);
+                self.ch4.step(4);
+            }
+            sample(self);
+        }
+    }
+
+    // Step  Length  Sweep  Envelope
+    //   0     x
+    //   2     x       x
+    //   4     x
+    //   6     x       x
+    //   7                    x
+    fn step_frame_sequencer(&mut self) {
+        if self.frame_step & 1 == 0 {
+            self.ch1.length.clock(&mut self.ch1.enabled);
+            self.ch2.length.clock(&mut self.ch2.enabled);
+            self.ch3.length.clock(&mut self.ch3.enabled);
+            self.ch4.length.clock(&mut self.ch4.enabled);
+        }
+        if self.frame_step == 2 || self.frame_step == 6 {
+            self.ch1.clock_sweep();
+        }
+        if self.frame_step == 7 {
+            self.ch1.envelope.clock();
+            self.ch2.envelope.clock();
+            self.ch4.envelope.clock();
+        }
+        self.frame_step = (self.frame_step + 1) % 8;
+    }
+
+    // Analog output of each channel in the range -1.0 to 1.0, before panning and master volume
+    pub fn channel_outputs(&self) -> [f32; 4] {
+        [
+            dac(self.ch1.dac_enabled(), self.ch1.output()),
+            dac(self.ch2.dac_enabled(), self.ch2.output()),
+            dac(self.ch3.dac_enabled, self.ch3.output(&self.wave_ram)),
+            dac(self.ch4.dac_enabled(), self.ch4.output()),
+        ]
+    }
+
+    // Current stereo sample, each side in the range -1.0 to 1.0
+    pub fn output(&self) -> (f32, f32) {
+        if !self.powered {
+            return (0.0, 0.0)
+        }
+        let channels = self.channel_outputs();
+        let nr50 = self.regs[0x14];
+        let nr51 = self.regs[0x15];
+
+        let mut left = 0.0;
+        let mut right = 0.0;
+        for (i, out) in channels.iter().enumerate() {
+            if nr51 & (0x10 << i) > 0 {
+                left += out;
+            }
+            if nr51 & (0x01 << i) > 0 {
+                right += out;
+            }
+        }
+        let left_volume = ((nr50 >> 4) & 0x07) as f32 + 1.0;
+        let right_volume = (nr50 & 0x07) as f32 + 1.0;
+        (left / 4.0 * left_volume / 8.0, right / 4.0 * right_volume / 8.0)
+    }
+
+    pub fn write_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.regs);
+        w.bytes(&self.wave_ram);
+        w.bool(self.powered);
+        w.u8(self.frame_step);
+        w.bool(self.div_bit);
+        self.ch1.write_state(w);
+        self.ch2.write_state(w);
+        self.ch3.write_state(w);
+        self.ch4.write_state(w);
+    }
+
+    pub fn read_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.bytes(&mut self.regs)?;
+        r.bytes(&mut self.wave_ram)?;
+        self.powered = r.bool()?;
+        self.frame_step = r.u8()? % 8;
+        self.div_bit = r.bool()?;
+        self.ch1.read_state(r)?;
+        self.ch2.read_state(r)?;
+        self.ch3.read_state(r)?;
+        self.ch4.read_state(r)
+    }
+}
+
+// Converts a 4-bit channel value to an analog level. A disabled DAC outputs silence
+fn dac(enabled: bool, digital: u8) -> f32 {
+    if !enabled {
+        return 0.0
+    }
+    digital as f32 / 7.5 - 1.0
+}
+
+struct Length {
+    counter: u16,
+    enabled: bool,
+}
+
+impl Length {
+    fn new() -> Length {
+        Length { counter: 0, enabled: false }
+    }
+
+    fn clock(&mut self, channel_enabled: &mut bool) {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            if self.counter == 0 {
+                *channel_enabled = false;
+            }
+        }
+    }
+
+    // On trigger an expired counter starts over at its maximum
+    fn trigger(&mut self, max: u16) {
+        if self.counter == 0 {
+            self.counter = max;
+        }
+    }
+
+    fn write_state(&self, w: &mut StateWriter) {
+        w.u16(self.counter);
+        w.bool(self.enabled);
+    }
+
+    fn read_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.counter = r.u16()?;
+        self.enabled = r.bool()?;
+        Ok(())
+    }
+}
+
+// NRx2: Bits 7-4 initial volume, Bit 3 direction (1 = increase), Bits 2-0 period
+struct Envelope {
+    register: u8,
+    volume: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    fn new() -> Envelope {
+        Envelope { register: 0, volume: 0, timer: 0 }
+    }
+
+    fn dac_enabled(&self) -> bool {
+        self.register & 0xF8 > 0
+    }
+
+    fn period(&self) -> u8 {
+        self.register & 0x07
+    }
+
+    fn trigger(&mut self) {
+        self.volume = self.register >> 4;
+        self.timer = self.period();
+    }
+
+    fn clock(&mut self) {
+        if self.period() == 0 {
+            return
+        }
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer == 0 {
+            self.timer = self.period();
+            if self.register & 0x08 > 0 && self.volume < 15 {
+                self.volume += 1;
+            } else if self.register & 0x08 == 0 && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+
+    fn write_state(&self, w: &mut StateWriter) {
+        w.u8(self.register);
+        w.u8(self.volume);
+        w.u8(self.timer);
+    }
+
+    fn read_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.register = r.u8()?;
+        self.volume = r.u8()?;
+        self.timer = r.u8()?;
+        Ok(())
+    }
+}
+
+// Channels 1 and 2. Registers are numbered from NRx0, channel 2 has no sweep register
+struct Square {
+    enabled: bool,
+    duty: u8,
+    duty_pos: u8,
+    frequency: u16,
+    timer: i32,
+    length: Length,
+    envelope: Envelope,
+
+    // NR10: Bits 6-4 sweep period, Bit 3 negate, Bits 2-0 shift
+    sweep: u8,
+    sweep_enabled: bool,
+    sweep_timer: u8,
+    shadow_frequency: u16,
+    sweep_negated: bool,
+}
+
+impl Square {
+    fn new() -> Square {
+        Square {
+            enabled: false,
+            duty: 0,
+            duty_pos: 0,
+            frequency: 0,
+            timer: 0,
+            length: Length::new(),
+            envelope: Envelope::new(),
+            sweep: 0,
+            sweep_enabled: false,
+            sweep_timer: 0,
+            shadow_frequency: 0,
+            sweep_negated: false,
+        }
+    }
+
+    fn write(&mut self, reg: u8, val: u8) {
+        match reg {
+            0 => {
+                // Leaving negate mode after a negated calculation disables the channel
+                if self.sweep_negated && val & 0x08 == 0 {
+                    self.enabled = false;
+                }
+                self.sweep = val & 0x7F;
+            },
+            1 => {
+                self.duty = val >> 6;
+                self.length.counter = 64 - (val & 0x3F) as u16;
+            },
+            2 => {
+                self.envelope.register = val;
+                if !self.dac_enabled() {
+                    self.enabled = false;
+                }
+            },
+            3 => self.frequency = (self.frequency & 0x700) | val as u16,
+            4 => {
+                self.frequency = (self.frequency & 0xFF) | ((val as u16 & 0x07) << 8);
+                self.length.enabled = val & 0x40 > 0;
+                if val & 0x80 > 0 {
+                    self.trigger();
+                }
+            },
+            _ => {}
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled();
+        self.length.trigger(64);
+        self.timer = self.period();
+        self.envelope.trigger();
+
+        self.shadow_frequency = self.frequency;
+        self.sweep_timer = self.sweep_period();
+        self.sweep_enabled = (self.sweep >> 4) & 0x07 != 0 || self.sweep_shift() != 0;
+        self.sweep_negated = false;
+        if self.sweep_shift() != 0 {
+            self.sweep_calculation();
+        }
+    }
+
+    fn period(&self) -> i32 {
+        (2048 - self.frequency as i32) * 4
+    }
+
+    // A period of 0 counts as 8 for the timer
+    fn sweep_period(&self) -> u8 {
+        match (self.sweep >> 4) & 0x07 {
+            0 => 8,
+            p => p
+        }
+    }
+
+    fn sweep_shift(&self) -> u8 {
+        self.sweep & 0x07
+    }
+
+    fn sweep_calculation(&mut self) -> u16 {
+        let delta = self.shadow_frequency >> self.sweep_shift();
+        let new = if self.sweep & 0x08 > 0 {
+            self.sweep_negated = true;
+            self.shadow_frequency - delta
+        } else {
+            self.shadow_frequency + delta
+        };
+        if new > 2047 {
+            self.enabled = false;
+        }
+        new
+    }
+
+    fn clock_sweep(&mut self) {
+        if self.sweep_timer > 0 {
+            self.sweep_timer -= 1;
+        }
+        if self.sweep_timer > 0 {
+            return
+        }
+        self.sweep_timer = self.sweep_period();
+        if !self.sweep_enabled || (self.sweep >> 4) & 0x07 == 0 {
+            return
+        }
+
+        let new = self.sweep_calculation();
+        if new <= 2047 && self.sweep_shift() != 0 {
+            self.frequency = new;
+            self.shadow_frequency = new;
+            // The new frequency is checked for overflow again but not used
+            self.sweep_calculation();
+        }
+    }
+
+    fn step(&mut self, cycles: i32) {
+        self.timer -= cycles;
+        while self.timer <= 0 {
+            self.timer += self.period();
+            self.duty_pos = (self.duty_pos + 1) % 8;
+        }
+    }
+
+    fn dac_enabled(&self) -> bool {
+        self.envelope.dac_enabled()
+    }
+
+    fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0
+        }
+        DUTY[self.duty as usize][self.duty_pos as usize] * self.envelope.volume
+    }
+
+    fn write_state(&self, w: &mut StateWriter) {
+        w.bool(self.enabled);
+        w.u8(self.duty);
+        w.u8(self.duty_pos);
+        w.u16(self.frequency);
+        w.i32(self.timer);
+        self.length.write_state(w);
+        self.envelope.write_state(w);
+        w.u8(self.sweep);
+        w.bool(self.sweep_enabled);
+        w.u8(self.sweep_timer);
+        w.u16(self.shadow_frequency);
+        w.bool(self.sweep_negated);
+    }
+
+    fn read_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = r.bool()?;
+        self.duty = r.u8()? & 0x03;
+        self.duty_pos = r.u8()? % 8;
+        self.frequency = r.u16()? & 0x7FF;
+        self.timer = r.i32()?;
+        self.length.read_state(r)?;
+        self.envelope.read_state(r)?;
+        self.sweep = r.u8()?;
+        self.sweep_enabled = r.bool()?;
+        self.sweep_timer = r.u8()?;
+        self.shadow_frequency = r.u16()?;
+        self.sweep_negated = r.bool()?;
+        Ok(())
+    }
+}
+
+// Channel 3, plays the 32 samples in wave RAM, high nibble first
+struct Wave {
+    enabled: bool,
+    dac_enabled: bool,
+    volume_code: u8,
+    frequency: u16,
+    timer: i32,
+    position: u8,
+    length: Length,
+}
+
+impl Wave {
+    fn new() -> Wave {
+        Wave {
+            enabled: false,
+            dac_enabled: false,
+            volume_code: 0,
+            frequency: 0,
+            timer: 0,
+            position: 0,
+            length: Length::new(),
+        }
+    }
+
+    fn write(&mut self, reg: u8, val: u8) {
+        match reg {
+            0 => {
+                self.dac_enabled = val & 0x80 > 0;
+                if !self.dac_enabled {
+                    self.enabled = false;
+                }
+            },
+            1 => self.length.counter = 256 - val as u16,
+            2 => self.volume_code = (val >> 5) & 0x03,
+            3 => self.frequency = (self.frequency & 0x700) | val as u16,
+            4 => {
+                self.frequency = (self.frequency & 0xFF) | ((val as u16 & 0x07) << 8);
+                self.length.enabled = val & 0x40 > 0;
+                if val & 0x80 > 0 {
+                    self.enabled = self.dac_enabled;
+                    self.length.trigger(256);
+                    self.timer = self.period();
+                    self.position = 0;
+                }
+            },
+            _ => {}
+        }
+    }
+
+    fn period(&self) -> i32 {
+        (2048 - self.frequency as i32) * 2
+    }
+
+    fn step(&mut self, cycles: i32) {
+        self.timer -= cycles;
+        while self.timer <= 0 {
+            self.timer += self.period();
+            self.position = (self.position + 1) % 32;
+        }
+    }
+
+    fn output(&self, wave_ram: &[u8; 16]) -> u8 {
+        if !self.enabled {
+            return 0
+        }
+        let byte = wave_ram[(self.position / 2) as usize];
+        let sample = if self.position & 1 == 0 { byte >> 4 } else { byte & 0x0F };
+        match self.volume_code {
+            0 => 0,
+            code => sample >> (code - 1)
+        }
+    }
+
+    fn write_state(&self, w: &mut StateWriter) {
+        w.bool(self.enabled);
+        w.bool(self.dac_enabled);
+        w.u8(self.volume_code);
+        w.u16(self.frequency);
+        w.i32(self.timer);
+        w.u8(self.position);
+        self.length.write_state(w);
+    }
+
+    fn read_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = r.bool()?;
+        self.dac_enabled = r.bool()?;
+        self.volume_code = r.u8()? & 0x03;
+        self.frequency = r.u16()? & 0x7FF;
+        self.timer = r.i32()?;
+        self.position = r.u8()? % 32;
+        self.length.read_state(r)
+    }
+}
+
+// Channel 4, pseudo-random noise from a 15-bit LFSR.
+// NR43: Bits 7-4 clock shift, Bit 3 width (1 = 7-bit), Bits 2-0 divisor code
+struct Noise {
+    enabled: bool,
+    polynomial: u8,
+    lfsr: u16,
+    timer: i32,
+    length: Length,
+    envelope: Envelope,
+}
+
+impl Noise {
+    fn new() -> Noise {
+        Noise {
+            enabled: false,
+            polynomial: 0,
+            lfsr: 0x7FFF,
+            timer: 0,
+            length: Length::new(),
+            envelope: Envelope::new(),
+        }
+    }
+
+    fn write(&mut self, reg: u8, val: u8) {
+        match reg {
+            1 => self.length.counter = 64 - (val & 0x3F) as u16,
+            2 => {
+                self.envelope.register = val;
+                if !self.dac_enabled() {
+                    self.enabled = false;
+                }
+            },
+            3 => self.polynomial = val,
+            4 => {
+                self.length.enabled = val & 0x40 > 0;
+                if val & 0x80 > 0 {
+                    self.enabled = self.dac_enabled();
+                    self.length.trigger(64);
+                    self.timer = self.period();
+                    self.envelope.trigger();
+                    self.lfsr = 0x7FFF;
+                }
+            },
+            _ => {}
+        }
+    }
+
+    fn period(&self) -> i32 {
+        NOISE_DIVISORS[(self.polynomial & 0x07) as usize] << (self.polynomial >> 4)
+    }
+
+    fn step(&mut self, cycles: i32) {
+        self.timer -= cycles;
+        while self.timer <= 0 {
+            self.timer += self.period();
+            let bit = (self.lfsr & 0x01) ^ ((self.lfsr >> 1) & 0x01);
+            self.lfsr = (self.lfsr >> 1) | (bit << 14);
+            if self.polynomial & 0x08 > 0 {
+                self.lfsr = (self.lfsr & !0x40) | (bit << 6);
+            }
+        }
+    }
+
+    fn dac_enabled(&self) -> bool {
+        self.envelope.dac_enabled()
+    }
+
+    fn output(&self) -> u8 {
+        if !self.enabled || self.lfsr & 0x01 > 0 {
+            return 0
+        }
+        self.envelope.volume
+    }
+
+    fn write_state(&self, w: &mut StateWriter) {
+        w.bool(self.enabled);
+        w.u8(self.polynomial);
+        w.u16(self.lfsr);
+        w.i32(self.timer);
+        self.length.write_state(w);
+        self.envelope.write_state(w);
+    }
+
+    fn read_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = r.bool()?;
+        self.polynomial = r.u8()?;
+        self.lfsr = r.u16()? & 0x7FFF;
+        self.timer = r.i32()?;
+        self.length.read_state(r)?;
+        self.envelope.read_state(r)
+    }
+}
+
+#[cfg(test)]
+mod apu_tests {
+    use crate::apu::Apu;
+
+    fn powered() -> Apu {
+        let mut apu = Apu::new();
+        apu.write(0xFF26, 0x80);
+        apu.write(0xFF24, 0x77);
+        apu.write(0xFF25, 0xFF);
+        apu
+    }
+
+    // Runs the APU for the given number of frame sequencer steps
+    fn run_frame_sequencer(apu: &mut Apu, steps: u32) {
+        for _ in 0..steps {
+            apu.tick(1, 0x10, |_| {});
+            apu.tick(1, 0x00, |_| {});
+        }
+    }
+
+    #[test]
+    fn power_off_clears_registers() {
+        let mut apu = powered();
+        apu.write(0xFF12, 0xF3);
+        apu.write(0xFF30, 0x12);
+        assert_eq!(apu.read(0xFF12), 0xF3);
+
+        apu.write(0xFF26, 0x00);
+        assert_eq!(apu.read(0xFF12), 0x00);
+        assert_eq!(apu.read(0xFF26), 0x70);
+        assert_eq!(apu.read(0xFF30), 0x12);
+
+        apu.write(0xFF12, 0xF3);
+        assert_eq!(apu.read(0xFF12), 0x00);
+    }
+
+    #[test]
+    fn trigger_and_length_expiry() {
+        let mut apu = powered();
+        apu.write(0xFF12, 0xF0);
+        apu.write(0xFF11, 0x3E); // Length of 2
+        apu.write(0xFF14, 0xC0);
+        assert_eq!(apu.read(0xFF26) & 0x01, 0x01);
+
+        run_frame_sequencer(&mut apu, 2);
+        assert_eq!(apu.read(0xFF26) & 0x01, 0x01);
+        run_frame_sequencer(&mut apu, 2);
+        assert_eq!(apu.read(0xFF26) & 0x01, 0x00);
+    }
+
+    #[test]
+    fn sweep_overflow_disables_channel() {
+        let mut apu = powered();
+        apu.write(0xFF12, 0xF0);
+        apu.write(0xFF10, 0x11); // Period 1, increase, shift 1
+        apu.write(0xFF13, 0xFF);
+        apu.write(0xFF14, 0x85); // Frequency 0x5FF, 0x5FF + 0x2FF overflows
+        assert_eq!(apu.read(0xFF26) & 0x01, 0x00);
+    }
+
+    #[test]
+    fn square_wave_output() {
+        let mut apu = powered();
+        apu.write(0xFF17, 0xF0);
+        apu.write(0xFF16, 0x80); // 50% duty
+        apu.write(0xFF18, 0x00);
+        apu.write(0xFF19, 0x87);
+
+        let mut high = 0;
+        let mut low = 0;
+        for _ in 0..(8 * 256) {
+            apu.tick(1, 0, |_| {});
+            let (left, right) = apu.output();
+            assert_eq!(left, right);
+            if left > 0.0 { high += 1 } else { low += 1 }
+        }
+        assert_eq!(high, low);
+    }
+}
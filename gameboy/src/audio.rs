@@ -0,0 +1,248 @@
+use std::collections::VecDeque;
+
+// The APU produces one sample per M-cycle
+pub const APU_SAMPLE_RATE: u32 = 1048576;
+pub const DEFAULT_SAMPLE_RATE: u32 = 48000;
+// Enough for a few frames of latency at any common rate, older samples are dropped when it fills up
+const DEFAULT_BUFFER_SECONDS: f32 = 0.2;
+
+// Length of the band-limited step in output samples, the output lags the APU by half of it
+const TAPS: usize = 32;
+// Positions a step can have between two output samples
+const PHASES: usize = 64;
+// Cutoff in cycles per output sample, the Blackman window's transition band ends just below 0.5
+const CUTOFF: f64 = 0.4;
+
+// Band-limited resampling of the APU output to a lower rate.
+// The APU output only changes in steps, so instead of filtering every APU sample each change is
+// added to the output as a windowed-sinc step at its position between two output samples.
+// Frequencies above the output Nyquist rate are removed before they can alias, and the cost is
+// per change rather than per APU sample
+pub struct Resampler {
+    sample_rate: u32,
+    channels: usize,
+    // Bresenham style counter deciding when an output sample is due. Between two output samples it
+    // is also the position of the current APU sample, phase / APU_SAMPLE_RATE of the way along
+    phase: u32,
+    // PHASES rows of TAPS parts of the step, see step_kernel. Every row sums to 1
+    kernel: Vec<f32>,
+    last: Vec<f32>,
+    // Parts of steps not yet added to the output, TAPS per channel starting at the next output sample
+    pending: Vec<f32>,
+    level: Vec<f32>,
+}
+
+impl Resampler {
+    pub fn new(sample_rate: u32, channels: usize) -> Resampler {
+        Resampler {
+            sample_rate: sample_rate.clamp(8000, APU_SAMPLE_RATE),
+            channels,
+            phase: 0,
+            kernel: step_kernel(),
+            last: vec![0.0; channels],
+            pending: vec![0.0; channels * TAPS],
+            level: vec![0.0; channels],
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    // Takes one APU sample of every channel. Returns the next output sample of every channel when one is due
+    pub fn push(&mut self, input: &[f32]) -> Option<&[f32]> {
+        let row = (self.phase as u64 * PHASES as u64 / APU_SAMPLE_RATE as u64) as usize * TAPS;
+        let kernel = &self.kernel[row..row + TAPS];
+        for (c, (&val, last)) in input.iter().zip(self.last.iter_mut()).enumerate() {
+            let delta = val - *last;
+            if delta == 0.0 {
+                continue
+            }
+            *last = val;
+            for (pending, k) in self.pending[c * TAPS..(c + 1) * TAPS].iter_mut().zip(kernel) {
+                *pending += delta * k;
+            }
+        }
+
+        self.phase += self.sample_rate;
+        if self.phase < APU_SAMPLE_RATE {
+            return None
+        }
+        self.phase -= APU_SAMPLE_RATE;
+
+        for c in 0..self.channels {
+            let pending = &mut self.pending[c * TAPS..(c + 1) * TAPS];
+            self.level[c] += pending[0];
+            pending.copy_within(1.., 0);
+            pending[TAPS - 1] = 0.0;
+        }
+        Some(&self.level)
+    }
+}
+
+// For each phase, how much of the step each output sample takes, delayed by half its length. Summed
+// up in the output this makes a step that rises within TAPS samples and has nothing above the cutoff
+fn step_kernel() -> Vec<f32> {
+    let half = (TAPS / 2) as f64;
+    let impulse = |x: f64| {
+        if x.abs() >= half {
+            return 0.0
+        }
+        let arg = std::f64::consts::PI * 2.0 * CUTOFF * x;
+        let sinc = if arg == 0.0 { 1.0 } else { arg.sin() / arg };
+        let n = (x + half) / TAPS as f64;
+        sinc * (0.42 - 0.5 * (2.0 * std::f64::consts::PI * n).cos() + 0.08 * (4.0 * std::f64::consts::PI * n).cos())
+    };
+
+    // The step itself, integrating the impulse from one output sample before it starts in 1/PHASES steps.
+    // A point sample of the impulse per output sample would not add up to a band-limited step
+    let mut step = vec![0.0];
+    let mut sum = 0.0;
+    for i in 0..(TAPS + 1) * PHASES {
+        sum += impulse((i as f64 + 0.5) / PHASES as f64 - half - 1.0);
+        step.push(sum);
+    }
+
+    let mut kernel = Vec::with_capacity(PHASES * TAPS);
+    for p in 0..PHASES {
+        // Output sample k covers the step from (k + 1) * PHASES - p to (k + 2) * PHASES - p
+        let total = step[(TAPS + 1) * PHASES - p] - step[PHASES - p];
+        for k in 0..TAPS {
+            let part = step[(k + 2) * PHASES - p] - step[(k + 1) * PHASES - p];
+            kernel.push((part / total) as f32);
+        }
+    }
+    kernel
+}
+
+// Converts the APU output to the host sample rate and keeps it until the frontend takes it.
+// Resampling is band-limited, see Resampler, followed by a high-pass filter like the capacitor on
+// the real hardware's output that removes the DC offset
+pub struct AudioOutput {
+    resampler: Resampler,
+    capacity: usize,
+    samples: VecDeque<f32>,
+
+    high_pass: f32,
+    prev_in: (f32, f32),
+    prev_out: (f32, f32),
+}
+
+impl AudioOutput {
+    pub fn new(sample_rate: u32) -> AudioOutput {
+        let mut output = AudioOutput {
+            resampler: Resampler::new(sample_rate, 2),
+            capacity: 0,
+            samples: VecDeque::new(),
+            high_pass: 0.0,
+            prev_in: (0.0, 0.0),
+            prev_out: (0.0, 0.0),
+        };
+        output.set_sample_rate(sample_rate);
+        output
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.resampler = Resampler::new(sample_rate, 2);
+        let sample_rate = self.resampler.sample_rate();
+        self.capacity = (sample_rate as f32 * DEFAULT_BUFFER_SECONDS) as usize;
+        // Cut off around 20 Hz
+        self.high_pass = 1.0 - (2.0 * std::f32::consts::PI * 20.0 / sample_rate as f32);
+        self.samples.clear();
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.resampler.sample_rate()
+    }
+
+    // Maximum number of stereo frames kept before the oldest are dropped
+    pub fn set_capacity(&mut self, frames: usize) {
+        self.capacity = frames.max(1);
+        while self.samples.len() > self.capacity * 2 {
+            self.samples.pop_front();
+        }
+    }
+
+    // Takes one APU sample
+    pub fn push(&mut self, left: f32, right: f32) {
+        let (left, right) = match self.resampler.push(&[left, right]) {
+            Some(out) => (out[0], out[1]),
+            None => return
+        };
+
+        let out_left = left - self.prev_in.0 + self.high_pass * self.prev_out.0;
+        let out_right = right - self.prev_in.1 + self.high_pass * self.prev_out.1;
+        self.prev_in = (left, right);
+        self.prev_out = (out_left, out_right);
+
+        if self.samples.len() >= self.capacity * 2 {
+            self.samples.pop_front();
+            self.samples.pop_front();
+        }
+        self.samples.push_back(out_left);
+        self.samples.push_back(out_right);
+    }
+
+    // Number of stereo frames waiting to be taken
+    pub fn buffered_frames(&self) -> usize {
+        self.samples.len() / 2
+    }
+
+    // All buffered samples, interleaved left and right
+    pub fn take(&mut self) -> Vec<f32> {
+        self.samples.drain(..).collect()
+    }
+}
+
+#[cfg(test)]
+mod audio_tests {
+    use crate::audio::{AudioOutput, APU_SAMPLE_RATE};
+
+    #[test]
+    fn resamples_to_output_rate() {
+        let mut audio = AudioOutput::new(48000);
+        for _ in 0..APU_SAMPLE_RATE / 8 {
+            audio.push(0.5, -0.5);
+        }
+        assert_eq!(audio.buffered_frames(), 6000);
+        let samples = audio.take();
+        assert_eq!(samples.len(), 12000);
+        assert_eq!(audio.buffered_frames(), 0);
+
+        // The step arrives after the resampler's delay, the high-pass filter lets it through and
+        // then settles towards 0
+        assert!(samples[0].abs() < 0.001 && samples[1].abs() < 0.001);
+        assert!(samples[80] > 0.4 && samples[81] < -0.4);
+        assert!(samples[11998].abs() < 0.01);
+    }
+
+    // RMS of the last half of a sine wave resampled to 8 kHz
+    fn resampled_rms(frequency: f64) -> f32 {
+        let mut audio = AudioOutput::new(8000);
+        for i in 0..APU_SAMPLE_RATE / 4 {
+            let t = i as f64 / APU_SAMPLE_RATE as f64;
+            let val = 0.5 * (2.0 * std::f64::consts::PI * frequency * t).sin() as f32;
+            audio.push(val, val);
+        }
+        let samples = audio.take();
+        let tail = &samples[samples.len() / 2..];
+        (tail.iter().map(|s| s * s).sum::<f32>() / tail.len() as f32).sqrt()
+    }
+
+    #[test]
+    fn removes_frequencies_above_nyquist() {
+        assert!((resampled_rms(1000.0) - 0.5 / 2f32.sqrt()).abs() < 0.01);
+        // Would alias to 1 kHz, a plain average of the APU samples only takes it down by about 17 dB
+        assert!(resampled_rms(7000.0) < 0.001);
+    }
+
+    #[test]
+    fn buffer_is_bounded() {
+        let mut audio = AudioOutput::new(48000);
+        audio.set_capacity(100);
+        for _ in 0..APU_SAMPLE_RATE {
+            audio.push(0.0, 0.0);
+        }
+        assert_eq!(audio.buffered_frames(), 100);
+    }
+}
@@ -1,56 +1,133 @@
-use crate::{cartridge::CType::*, save};
+use std::{error::Error, fmt};
+
+use crate::{cartridge::CType::*, header::CartridgeHeader, rtc::{self, Rtc}, save::{MemoryStore, SaveError, SaveStore}, savestate::{self, StateError, StateReader, StateWriter}};
+
+// MBC2 has 512 half-byte RAM cells built into the mapper chip
+const MBC2_RAM_SIZE: usize = 512;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LoadError {
+    TooShort { len: usize },
+    UnsupportedMapper(u8),
+    BadHeaderChecksum { expected: u8, actual: u8 },
+    InvalidRomSize(u8),
+    RomSizeMismatch { expected: usize, actual: usize },
+    InvalidRamSize(u8),
+    // The battery save found for the game does not match the RAM size in the header
+    RamSizeMismatch { expected: usize, actual: usize },
+    // An imported save was loaded but could not be persisted
+    Store(SaveError),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::TooShort { len } =>
+                write!(f, "ROM is too short to contain a cartridge header ({} bytes)", len),
+            LoadError::UnsupportedMapper(code) =>
+                write!(f, "Unsupported cartridge type: {:#04x}", code),
+            LoadError::BadHeaderChecksum { expected, actual } =>
+                write!(f, "Bad header checksum: header says {:#04x}, computed {:#04x}", expected, actual),
+            LoadError::InvalidRomSize(code) =>
+                write!(f, "Invalid ROM size code: {:#04x}", code),
+            LoadError::RomSizeMismatch { expected, actual } =>
+                write!(f, "ROM size mismatch: header says {} bytes, file has {} bytes", expected, actual),
+            LoadError::InvalidRamSize(code) =>
+                write!(f, "Invalid RAM size code: {:#04x}", code),
+            LoadError::RamSizeMismatch { expected, actual } =>
+                write!(f, "Save data size mismatch: expected {} bytes, found {} bytes", expected, actual),
+            LoadError::Store(e) => e.fmt(f),
+        }
+    }
+}
+
+impl Error for LoadError {}
 
 pub struct Cartridge {
     name: String,
+    pub header: CartridgeHeader,
     pub data: Vec<u8>,
+    pub rom_hash: u64,
     pub ram: Vec<u8>,
     c_type: CType,
 
     ram_enable: bool,
     ram_bank: u8,
-    rom_bank: u8,
+    rom_bank: u16,
     num_banks: u8,
 
     banking_mode: u8,
 
+    rtc: Option<Rtc>,
+    store: Box<dyn SaveStore>,
+    // Why the stored battery save couldn't be used as is, see new
+    load_warning: Option<LoadError>,
+    // Result of the last battery save, None if it succeeded
+    save_error: Option<SaveError>,
 }
 
 pub enum CType {
     Rom,
     Mbc1,
+    Mbc2,
     Mbc3,
+    Mbc5,
+    Mbc5Rumble,
 }
 
 impl Cartridge {
-    pub fn new(data: Vec<u8>, name: String) -> Self {
-        let c_type = match data[0x0147] {
+    pub fn new(data: Vec<u8>, name: String, store: Box<dyn SaveStore>) -> Result<Self, LoadError> {
+        let header = CartridgeHeader::parse(&data)?;
+
+        let c_type = match header.cartridge_type {
             0x00 => Rom,
             0x01 | 0x02 | 0x03 => Mbc1,
-            0x11 | 0x12 | 0x13 => Mbc3, // No support for timers
-            _ => {
-                panic!("Rom type not implemented!")
-            },
-        };
-        let num_banks = data[0x0148] + 1;
-        let ram_size = match data[0x0149] {
-            0x00 => 0,
-            0x02 => 8,
-            0x03 => 32,
-            0x04 => 128,
-            0x05 => 64,
-            _ => 0
+            0x05 | 0x06 => Mbc2,
+            0x0F..=0x13 => Mbc3,
+            0x19..=0x1B => Mbc5,
+            0x1C..=0x1E => Mbc5Rumble,
+            code => return Err(LoadError::UnsupportedMapper(code)),
         };
 
-        let ram = match save::get_item(&name) {
-            Some(val) => val,
-            None => vec![0; ram_size as usize * 1024],
-        };
+        header.validate()?;
 
-        //let ram: Vec<u8> = vec![0; ram_size as usize * 1024];
+        let num_banks = header.rom_size + 1;
+        let rom_bytes = header.rom_bytes().ok_or(LoadError::InvalidRomSize(header.rom_size))?;
+        if data.len() < rom_bytes {
+            return Err(LoadError::RomSizeMismatch { expected: rom_bytes, actual: data.len() });
+        }
+
+        let ram_size = header.ram_bytes().ok_or(LoadError::InvalidRamSize(header.ram_size))?;
+
+        let mut rtc = match header.cartridge_type {
+            0x0F | 0x10 => Some(Rtc::new()),
+            _ => None
+        };
 
+        let ram_bytes = match c_type {
+            Mbc2 => MBC2_RAM_SIZE, // Built-in, not declared in the header
+            _ => ram_size,
+        };
+        // A save that doesn't fit, like one written with other RAM sizing or by another ROM with the same name,
+        // shouldn't keep the game from booting. It is padded or cut to size and reported as a warning instead
+        let mut load_warning = None;
+        let ram = match store.load(&name) {
+            Some(val) => match split_battery_data(val.clone(), ram_bytes, rtc.as_mut()) {
+                Ok(ram) => ram,
+                Err(e) => {
+                    load_warning = Some(e);
+                    let mut ram = val;
+                    ram.resize(ram_bytes, 0);
+                    ram
+                }
+            },
+            None => vec![0; ram_bytes],
+        };
 
-        return Cartridge{
+        Ok(Cartridge{
             name,
+            header,
+            rom_hash: savestate::rom_hash(&data),
             data,
             c_type,
             ram,
@@ -58,7 +135,32 @@ impl Cartridge {
             rom_bank: 1,
             num_banks,
             ram_bank: 0,
-            banking_mode: 0
+            banking_mode: 0,
+            rtc,
+            store,
+            load_warning,
+            save_error: None,
+        })
+    }
+
+    // A plain 32 KB ROM without a header, used when memory is in test mode
+    pub fn empty() -> Self {
+        Cartridge{
+            name: "test".to_string(),
+            header: CartridgeHeader::default(),
+            data: vec![0; 1024 * 32],
+            rom_hash: 0,
+            c_type: Rom,
+            ram: Vec::new(),
+            ram_enable: false,
+            rom_bank: 1,
+            num_banks: 1,
+            ram_bank: 0,
+            banking_mode: 0,
+            rtc: None,
+            store: Box::new(MemoryStore::new()),
+            load_warning: None,
+            save_error: None,
         }
     }
 
@@ -70,9 +172,14 @@ impl Cartridge {
             return self.data[new_address + (self.rom_bank as usize * 0x4000)];
         }
         if (loc >= 0xA000) && (loc <= 0xbfff) {
-            let new_address = loc as usize - 0xA000; //Setting it to 0, in case the bank is 0
-            let val = self.ram[new_address + (self.ram_bank as usize * 0x4000)];
-            return val;
+            if let Some(rtc) = self.selected_rtc() {
+                return rtc.read(self.ram_bank);
+            }
+            return match (self.ram_address(loc), &self.c_type) {
+                (Some(addr), Mbc2) => self.ram[addr] | 0xF0, // Only the lower 4 bits exist
+                (Some(addr), _) => self.ram[addr],
+                (None, _) => 0xFF,
+            };
         }
 
         return self.data[loc as usize]
@@ -85,48 +192,228 @@ impl Cartridge {
     }
 
     pub fn write(&mut self, loc: u16, val: u8){
-        if loc < 0x2000 {
-            if val & 0x0F == 0x0A {
-                self.ram_enable = true;
-            }else{
-                self.ram_enable = false;
-                // Save data
-                save::set_item(&self.name, &self.ram).unwrap();
+        if loc < 0x8000 {
+            match self.c_type {
+                Mbc2 => self.write_mbc2_register(loc, val),
+                _ => self.write_register(loc, val),
             }
+            return
+        }
+
+        if (loc >= 0xA000) && (loc <= 0xBFFF) {
+            
+            if self.ram_enable {
+                if self.selected_rtc().is_some() {
+                    let reg = self.ram_bank;
+                    if let Some(rtc) = self.rtc.as_mut() {
+                        rtc.write(reg, val);
+                    }
+                    return
+                }
+                if let Some(addr) = self.ram_address(loc) {
+                    self.ram[addr] = match self.c_type {
+                        Mbc2 => val & 0x0F,
+                        _ => val,
+                    };
+                }
+            }
+
+            return
+        }
+        //self.data[loc as usize] = val
+    }
+
+    fn write_register(&mut self, loc: u16, val: u8) {
+        if loc < 0x2000 {
+            self.set_ram_enable(val);
         } else if loc < 0x4000 {
             match self.c_type {
                 Rom | Mbc1 => {
                     let mut bank = if val == 0 {0x01} else {val};
                     bank = bank & ((1 << self.num_banks) - 1);
 
-                    self.rom_bank = bank;
+                    self.rom_bank = bank as u16;
                 },
                 Mbc3 => {
-                    self.rom_bank = val
+                    // 7-bit bank number, 0 maps bank 1 like MBC1
+                    let bank = match val & 0x7F {
+                        0 => 1,
+                        bank => bank as u16
+                    };
+                    self.rom_bank = bank & ((1 << self.num_banks) - 1);
                 },
+                Mbc5 | Mbc5Rumble => {
+                    // The 9-bit bank number is split: 0x2000-0x2FFF holds the low 8 bits,
+                    // 0x3000-0x3FFF holds bit 8. Unlike MBC1, bank 0 can be mapped here.
+                    let bank = if loc < 0x3000 {
+                        (self.rom_bank & 0x100) | val as u16
+                    } else {
+                        (self.rom_bank & 0xFF) | ((val as u16 & 0x01) << 8)
+                    };
+                    self.rom_bank = bank & ((1 << self.num_banks) - 1);
+                },
+                Mbc2 => {}, // Never gets here, see write_mbc2_register
             }
             
         } else if loc < 0x6000 {
-            self.ram_bank = val & 0x03;
+            self.ram_bank = val & self.ram_bank_mask();
         } else if loc < 0x8000 {
-            self.banking_mode = val & 0x1;
+            match self.rtc.as_mut() {
+                Some(rtc) => rtc.write_latch(val),
+                None => self.banking_mode = val & 0x1,
+            }
         }
-        
+    }
 
-        if loc < 0x8000 {
+    // MBC2 only decodes 0x0000-0x3FFF, using address bit 8 to tell RAM enable and ROM bank writes apart
+    fn write_mbc2_register(&mut self, loc: u16, val: u8) {
+        if loc >= 0x4000 {
             return
         }
+        if loc & 0x100 == 0 {
+            self.set_ram_enable(val);
+        } else {
+            let bank = if val & 0x0F == 0 {0x01} else {val & 0x0F} as u16;
+            self.rom_bank = bank & ((1 << self.num_banks) - 1);
+        }
+    }
 
-        if (loc >= 0xA000) && (loc <= 0xBFFF) {
-            
-            if self.ram_enable {
-                let new_address = loc as usize - 0xA000; //Setting it to 0, in case the bank is 0
-                self.ram[new_address + (self.ram_bank as usize * 0x4000)] = val;
+    fn set_ram_enable(&mut self, val: u8) {
+        if val & 0x0F == 0x0A {
+            self.ram_enable = true;
+        }else{
+            self.ram_enable = false;
+            // Save data. A failure is kept for the frontend to report, the game keeps running
+            let data = self.battery_data();
+            self.save_error = self.store.store(&self.name, &data).err();
+        }
+    }
+
+    // The ROM bank mapped at loc, None outside the ROM
+    pub fn rom_bank(&self, loc: u16) -> Option<u16> {
+        match loc {
+            0x0000..=0x3FFF => Some(0),
+            0x4000..=0x7FFF => Some(self.rom_bank),
+            _ => None
+        }
+    }
+
+    // Advances the cartridge clock, if any, by the given number of M-cycles
+    pub fn tick(&mut self, cycles: u8) {
+        if let Some(rtc) = self.rtc.as_mut() {
+            rtc.tick(cycles);
+        }
+    }
+
+    pub fn set_rtc_host_clock(&mut self, enabled: bool) {
+        if let Some(rtc) = self.rtc.as_mut() {
+            rtc.set_host_clock(enabled);
+        }
+    }
+
+    // Banking registers, RAM and the clock. The ROM itself is identified by the hash in the state header
+    pub fn write_state(&self, w: &mut StateWriter) {
+        w.bool(self.ram_enable);
+        w.u8(self.ram_bank);
+        w.u16(self.rom_bank);
+        w.u8(self.banking_mode);
+        w.vec(&self.ram);
+        if let Some(rtc) = &self.rtc {
+            rtc.write_state(w);
+        }
+    }
+
+    pub fn read_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.ram_enable = r.bool()?;
+        self.ram_bank = r.u8()?;
+        self.rom_bank = r.u16()?;
+        // RAM banks past the end wrap in ram_address, so only values the register can't hold are rejected
+        if self.ram_bank & !self.ram_bank_mask() != 0 {
+            return Err(StateError::Corrupt("ram bank"));
+        }
+        if self.rom_bank as usize * 0x4000 >= self.data.len() {
+            return Err(StateError::Corrupt("rom bank"));
+        }
+        self.banking_mode = r.u8()?;
+        let ram = r.vec()?;
+        if ram.len() != self.ram.len() {
+            return Err(StateError::Corrupt("cartridge RAM size"));
+        }
+        self.ram = ram;
+        if let Some(rtc) = self.rtc.as_mut() {
+            rtc.read_state(r)?;
+        }
+        Ok(())
+    }
+
+    // Set when the stored battery save didn't match the cartridge and was padded or cut to fit
+    pub fn load_warning(&self) -> Option<&LoadError> {
+        self.load_warning.as_ref()
+    }
+
+    // Why the last battery save couldn't be stored, cleared by the next one that succeeds
+    pub fn last_save_error(&self) -> Option<&SaveError> {
+        self.save_error.as_ref()
+    }
+
+    // Raw .sav contents as used by other emulators: battery RAM, followed by the
+    // 48 byte RTC footer for MBC3 carts with a timer
+    pub fn export_save(&self) -> Vec<u8> {
+        self.battery_data()
+    }
+
+    // Replaces battery RAM (and the clock, if the file has an RTC footer) with a .sav file,
+    // and persists it right away
+    pub fn import_save(&mut self, data: &[u8]) -> Result<(), LoadError> {
+        let mut ram = split_battery_data(data.to_vec(), self.ram.len(), self.rtc.as_mut())?;
+        if let Mbc2 = self.c_type {
+            for cell in ram.iter_mut() {
+                *cell &= 0x0F;
             }
+        }
+        self.ram = ram;
+        let data = self.battery_data();
+        self.store.store(&self.name, &data).map_err(LoadError::Store)
+    }
 
-            return
+    // Battery RAM followed by the RTC footer for carts with a timer
+    fn battery_data(&self) -> Vec<u8> {
+        let mut data = self.ram.clone();
+        if let Some(rtc) = &self.rtc {
+            data.extend_from_slice(&rtc.to_footer());
         }
-        //self.data[loc as usize] = val
+        data
+    }
+
+    // Bits of the RAM bank register the mapper latches
+    fn ram_bank_mask(&self) -> u8 {
+        match self.c_type {
+            Mbc5 => 0x0F,
+            Mbc5Rumble => 0x07, // Bit 3 drives the rumble motor
+            Mbc3 => 0x0F, // 0x08-0x0C selects an RTC register
+            _ => 0x03,
+        }
+    }
+
+    // The RTC is mapped into 0xA000-0xBFFF instead of RAM when bank 0x08-0x0C is selected
+    fn selected_rtc(&self) -> Option<&Rtc> {
+        match self.ram_bank {
+            0x08..=0x0C => self.rtc.as_ref(),
+            _ => None
+        }
+    }
+
+    // Maps an address in 0xA000-0xBFFF to an index in the external RAM, wrapping banks
+    // that are larger than the RAM actually present. None if the cartridge has no RAM
+    fn ram_address(&self, loc: u16) -> Option<usize> {
+        if self.ram.is_empty() {
+            return None
+        }
+        if let Mbc2 = self.c_type {
+            return Some((loc as usize - 0xA000) & (MBC2_RAM_SIZE - 1));
+        }
+        let new_address = loc as usize - 0xA000;
+        Some((new_address + (self.ram_bank as usize * 0x2000)) % self.ram.len())
     }
 
     pub fn write_16(&mut self, loc: u16, val: u16) {
@@ -137,3 +424,173 @@ impl Cartridge {
     }
 
 }
+
+// Splits a battery save into RAM and, for carts with a timer, the RTC footer. The footer is
+// optional, but anything else that doesn't match the RAM size is rejected
+fn split_battery_data(mut data: Vec<u8>, ram_bytes: usize, rtc: Option<&mut Rtc>) -> Result<Vec<u8>, LoadError> {
+    if let Some(rtc) = rtc {
+        if data.len() > ram_bytes {
+            match Rtc::from_footer(&data[ram_bytes..]) {
+                Some(mut saved) => {
+                    saved.sync(rtc::unix_time());
+                    *rtc = saved;
+                    data.truncate(ram_bytes);
+                },
+                None => return Err(LoadError::RamSizeMismatch { expected: ram_bytes + rtc::FOOTER_SIZE, actual: data.len() }),
+            }
+        }
+    }
+    if data.len() != ram_bytes {
+        return Err(LoadError::RamSizeMismatch { expected: ram_bytes, actual: data.len() });
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod cartridge_tests {
+    use crate::cartridge::{Cartridge, LoadError};
+    use crate::header::test_rom as rom;
+    use crate::save::{FileStore, MemoryStore};
+    use crate::savestate::{StateError, StateReader, StateWriter};
+
+    #[test]
+    fn rejects_short_rom() {
+        let result = Cartridge::new(vec![0; 0x100], "short".to_string(), Box::new(MemoryStore::new()));
+        assert_eq!(result.err(), Some(LoadError::TooShort { len: 0x100 }));
+    }
+
+    #[test]
+    fn rejects_unsupported_mapper() {
+        let result = Cartridge::new(rom(0xFC, 0, 0), "camera".to_string(), Box::new(MemoryStore::new()));
+        assert_eq!(result.err(), Some(LoadError::UnsupportedMapper(0xFC)));
+    }
+
+    #[test]
+    fn rejects_bad_header_checksum() {
+        let mut data = rom(0x00, 0, 0);
+        data[0x0134] = b'X';
+        let result = Cartridge::new(data, "corrupt".to_string(), Box::new(MemoryStore::new()));
+        assert!(matches!(result.err(), Some(LoadError::BadHeaderChecksum { .. })));
+    }
+
+    #[test]
+    fn rejects_truncated_rom() {
+        let mut data = rom(0x19, 2, 0);
+        data.truncate(0x8000);
+        let result = Cartridge::new(data, "truncated".to_string(), Box::new(MemoryStore::new()));
+        assert_eq!(result.err(), Some(LoadError::RomSizeMismatch { expected: 0x20000, actual: 0x8000 }));
+    }
+
+    #[test]
+    fn loads_and_stores_battery_ram_through_store() {
+        let store = MemoryStore::with_save("zelda", vec![0x42; 8 * 1024]);
+        let mut cart = Cartridge::new(rom(0x03, 0, 0x02), "zelda".to_string(), Box::new(store)).unwrap();
+        assert_eq!(cart.read(0xA000), 0x42);
+
+        cart.write(0x0000, 0x0A);
+        cart.write(0xA000, 0x17);
+        cart.write(0x0000, 0x00);
+        assert_eq!(cart.store.load("zelda").unwrap()[0], 0x17);
+    }
+
+    #[test]
+    fn pads_save_of_wrong_size() {
+        let store = MemoryStore::with_save("zelda", vec![0x42; 100]);
+        let cart = Cartridge::new(rom(0x03, 0, 0x02), "zelda".to_string(), Box::new(store)).unwrap();
+        assert_eq!(cart.load_warning(), Some(&LoadError::RamSizeMismatch { expected: 8 * 1024, actual: 100 }));
+        assert_eq!(cart.ram.len(), 8 * 1024);
+        assert_eq!((cart.read(0xA063), cart.read(0xA064)), (0x42, 0x00));
+    }
+
+    #[test]
+    fn keeps_last_save_error() {
+        let dir = std::env::temp_dir().join(format!("gameboy-save-error-{}", std::process::id()));
+        std::fs::write(&dir, []).unwrap(); // A file where the save directory should be
+        let mut cart = Cartridge::new(rom(0x03, 0, 0x02), "zelda".to_string(), Box::new(FileStore::new(&dir))).unwrap();
+        cart.write(0x0000, 0x0A);
+        cart.write(0x0000, 0x00);
+        assert!(cart.last_save_error().is_some());
+        std::fs::remove_file(dir).unwrap();
+    }
+
+    #[test]
+    fn reports_store_failure_on_import() {
+        let dir = std::env::temp_dir().join(format!("gameboy-import-error-{}", std::process::id()));
+        std::fs::write(&dir, []).unwrap();
+        let mut cart = Cartridge::new(rom(0x03, 0, 0x02), "zelda".to_string(), Box::new(FileStore::new(&dir))).unwrap();
+        assert!(matches!(cart.import_save(&[0xAB; 8 * 1024]), Err(LoadError::Store(_))));
+        assert_eq!(cart.ram[0], 0xAB);
+        std::fs::remove_file(dir).unwrap();
+    }
+
+    #[test]
+    fn mbc3_rom_bank_is_masked() {
+        // 8 banks of 16 KB
+        let mut cart = Cartridge::new(rom(0x11, 2, 0), "mbc3".to_string(), Box::new(MemoryStore::new())).unwrap();
+        cart.write(0x2000, 0x00);
+        assert_eq!(cart.rom_bank(0x4000), Some(1));
+        cart.write(0x2000, 0x83);
+        assert_eq!(cart.rom_bank(0x4000), Some(3));
+        cart.write(0x2000, 0x7F);
+        assert_eq!(cart.rom_bank(0x4000), Some(7));
+        cart.read(0x7FFF);
+    }
+
+    #[test]
+    fn rejects_state_with_bad_banks() {
+        let mut cart = Cartridge::new(rom(0x03, 0, 0x02), "zelda".to_string(), Box::new(MemoryStore::new())).unwrap();
+        for (ram_bank, rom_bank, what) in [(0, 2, "rom bank"), (4, 1, "ram bank")] {
+            let mut w = StateWriter::new(cart.rom_hash);
+            w.bool(false);
+            w.u8(ram_bank);
+            w.u16(rom_bank);
+            w.u8(0);
+            w.vec(&[0; 8 * 1024]);
+            let data = w.finish();
+            let mut r = StateReader::new(&data, cart.rom_hash).unwrap();
+            assert_eq!(cart.read_state(&mut r), Err(StateError::Corrupt(what)));
+        }
+    }
+
+    #[test]
+    fn restores_rtc_from_save() {
+        let mut cart = Cartridge::new(rom(0x10, 0, 0x03), "gold".to_string(), Box::new(MemoryStore::new())).unwrap();
+        cart.write(0x0000, 0x0A);
+        cart.write(0x4000, 0x0A); // Hours
+        cart.write(0xA000, 13);
+        cart.write(0x0000, 0x00);
+
+        let saved = cart.store.load("gold").unwrap();
+        assert_eq!(saved.len(), 32 * 1024 + 48);
+
+        let mut cart = Cartridge::new(rom(0x10, 0, 0x03), "gold".to_string(), Box::new(MemoryStore::with_save("gold", saved))).unwrap();
+        cart.write(0x6000, 0x00);
+        cart.write(0x6000, 0x01);
+        cart.write(0x4000, 0x0A);
+        assert_eq!(cart.read(0xA000), 13);
+    }
+
+    #[test]
+    fn exports_and_imports_sav_files() {
+        let mut cart = Cartridge::new(rom(0x10, 0, 0x02), "crystal".to_string(), Box::new(MemoryStore::new())).unwrap();
+        let mut sav = vec![0x55; 8 * 1024];
+        sav.extend_from_slice(&[0; 44]); // RTC footer with a 32-bit timestamp
+        cart.import_save(&sav).unwrap();
+
+        assert_eq!(cart.read(0xA123), 0x55);
+        let exported = cart.export_save();
+        assert_eq!(exported.len(), 8 * 1024 + 48);
+        assert_eq!(cart.store.load("crystal"), Some(exported));
+
+        assert_eq!(cart.import_save(&[0; 100]).err(), Some(LoadError::RamSizeMismatch { expected: 8 * 1024, actual: 100 }));
+        assert!(cart.import_save(&vec![0; 8 * 1024 + 10]).is_err());
+    }
+
+    #[test]
+    fn mbc2_import_keeps_lower_nibbles() {
+        let mut cart = Cartridge::new(rom(0x06, 0, 0), "mbc2".to_string(), Box::new(MemoryStore::new())).unwrap();
+        cart.import_save(&[0xAB; 512]).unwrap();
+        assert_eq!(cart.export_save(), vec![0x0B; 512]);
+        assert_eq!(cart.read(0xA200), 0xFB);
+    }
+}
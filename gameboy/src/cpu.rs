@@ -1,19 +1,111 @@
-use web_sys::console;
+use wasm_bindgen::prelude::*;
 
-use crate::{memory::Memory, state::{InitialState, FinalState}};
+use crate::{debugger, memory::Memory, savestate::{StateError, StateReader, StateWriter}, state::{InitialState, FinalState}, trace::TraceSink};
 
 
+// Raised when the CPU executes an illegal opcode and locks up
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EmulationFault {
+    pub pc: u16,
+    pub opcode: u8,
+}
+
+// A copy of the CPU registers for debuggers, logging and frontends. Changing it doesn't affect the CPU
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Registers {
+    #[wasm_bindgen(readonly)]
+    pub a: u8,
+    #[wasm_bindgen(readonly)]
+    pub f: u8,
+    #[wasm_bindgen(readonly)]
+    pub b: u8,
+    #[wasm_bindgen(readonly)]
+    pub c: u8,
+    #[wasm_bindgen(readonly)]
+    pub d: u8,
+    #[wasm_bindgen(readonly)]
+    pub e: u8,
+    #[wasm_bindgen(readonly)]
+    pub h: u8,
+    #[wasm_bindgen(readonly)]
+    pub l: u8,
+    #[wasm_bindgen(readonly)]
+    pub sp: u16,
+    #[wasm_bindgen(readonly)]
+    pub pc: u16,
+    #[wasm_bindgen(readonly)]
+    pub ime: bool,
+    #[wasm_bindgen(readonly)]
+    pub halted: bool,
+}
+
+#[wasm_bindgen]
+impl Registers {
+    #[wasm_bindgen(getter)]
+    pub fn af(&self) -> u16 {
+        u16::from_be_bytes([self.a, self.f])
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn bc(&self) -> u16 {
+        u16::from_be_bytes([self.b, self.c])
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn de(&self) -> u16 {
+        u16::from_be_bytes([self.d, self.e])
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn hl(&self) -> u16 {
+        u16::from_be_bytes([self.h, self.l])
+    }
+}
+
+impl Registers {
+    // Any register by name, 8-bit registers are zero extended
+    pub fn get(&self, reg: debugger::Register) -> u16 {
+        use debugger::Register as R;
+        match reg {
+            R::A => self.a as u16,
+            R::F => self.f as u16,
+            R::B => self.b as u16,
+            R::C => self.c as u16,
+            R::D => self.d as u16,
+            R::E => self.e as u16,
+            R::H => self.h as u16,
+            R::L => self.l as u16,
+            R::AF => self.af(),
+            R::BC => self.bc(),
+            R::DE => self.de(),
+            R::HL => self.hl(),
+            R::SP => self.sp,
+            R::PC => self.pc,
+        }
+    }
+}
+
 pub struct CPU {
     a: u8,
     flags: Flags,
-    bc: Register,
-    de: Register,
-    hl: Register,
+    bc: RegisterPair,
+    de: RegisterPair,
+    hl: RegisterPair,
     sp: u16,
     pc: u16,
     ime: bool,
     ie: bool,
     halt: bool,
+    // HALT executed with IME off while an interrupt was pending: the next opcode fetch doesn't increment PC
+    halt_bug: bool,
+    // Low power mode entered by STOP, left when a joypad line goes low
+    stopped: bool,
+    locked: bool,
+    fault: Option<EmulationFault>,
+    // Gameboy Doctor log of every instruction, see trace
+    trace: Option<Box<dyn TraceSink>>,
 }
 
 impl CPU {
@@ -27,14 +119,19 @@ impl CPU {
                 cy: 0,
                 lower: 0
             },
-            bc: Register::new(),
-            de: Register::new(),
-            hl: Register::new(),
+            bc: RegisterPair::default(),
+            de: RegisterPair::default(),
+            hl: RegisterPair::default(),
             sp: 0,
             pc: 0,
             ime: false,
             ie: false,
             halt: false,
+            halt_bug: false,
+            stopped: false,
+            locked: false,
+            fault: None,
+            trace: None,
         }
     }
 
@@ -50,7 +147,8 @@ impl CPU {
         self.set_register_8(&Register8::H, state.h);
         self.set_register_8(&Register8::L, state.l);
         self.ime = if state.ime != 0 {true} else {false};
-        self.ie = if state.ie != 0 {true} else {false};
+        // ie in the tests doesn't affect the results, and no EI is pending at the start
+        self.ie = false;
     }
 
     pub fn compare_state(&self, state: &FinalState) -> Result<(), String> {
@@ -58,43 +156,78 @@ impl CPU {
             return Err(format!("Expected PC: {}, actual: {}", state.pc, self.get_register_16(&Register16::PC)))
         }
         if self.get_register_16(&Register16::SP) != state.sp {
-            return Err(format!("Expected PC: {}, actual: {}", state.sp, self.get_register_16(&Register16::SP)))
+            return Err(format!("Expected SP: {}, actual: {}", state.sp, self.get_register_16(&Register16::SP)))
         }
         if self.get_register_8(&Register8::A) != state.a {
             return Err(format!("Expected A: {}, actual: {}", state.a, self.get_register_8(&Register8::A)))
         }
         if self.get_register_8(&Register8::B) != state.b {
-            return Err(format!("Expected A: {}, actual: {}", state.b, self.get_register_8(&Register8::B)))
+            return Err(format!("Expected B: {}, actual: {}", state.b, self.get_register_8(&Register8::B)))
         }
         if self.get_register_8(&Register8::C) != state.c {
-            return Err(format!("Expected A: {}, actual: {}", state.c, self.get_register_8(&Register8::C)))
+            return Err(format!("Expected C: {}, actual: {}", state.c, self.get_register_8(&Register8::C)))
         }
         if self.get_register_8(&Register8::D) != state.d {
-            return Err(format!("Expected A: {}, actual: {}", state.d, self.get_register_8(&Register8::D)))
+            return Err(format!("Expected D: {}, actual: {}", state.d, self.get_register_8(&Register8::D)))
         }
         if self.get_register_8(&Register8::E) != state.e {
-            return Err(format!("Expected A: {}, actual: {}", state.e, self.get_register_8(&Register8::E)))
+            return Err(format!("Expected E: {}, actual: {}", state.e, self.get_register_8(&Register8::E)))
         }
         if self.get_register_8(&Register8::F) != state.f {
-            return Err(format!("Expected A: {}, actual: {}", state.f, self.get_register_8(&Register8::F)))
+            return Err(format!("Expected F: {}, actual: {}", state.f, self.get_register_8(&Register8::F)))
         }
         if self.get_register_8(&Register8::H) != state.h {
-            return Err(format!("Expected A: {}, actual: {}", state.h, self.get_register_8(&Register8::H)))
+            return Err(format!("Expected H: {}, actual: {}", state.h, self.get_register_8(&Register8::H)))
         }
         if self.get_register_8(&Register8::L) != state.l {
-            return Err(format!("Expected A: {}, actual: {}", state.l, self.get_register_8(&Register8::L)))
+            return Err(format!("Expected L: {}, actual: {}", state.l, self.get_register_8(&Register8::L)))
+        }
+
+        // IME and whether an EI is still waiting to take effect
+        let ime = state.ime != 0;
+        if self.ime != ime {
+            return Err(format!("Expected IME: {}, actual: {}", ime, self.ime))
+        }
+        let ei = state.ei != 0;
+        if self.ie != ei {
+            return Err(format!("Expected EI pending: {}, actual: {}", ei, self.ie))
         }
 
-        // Should be ignored
-        // let ime = if state.ime != 0 {true} else {false};
-        // let ie = if state.ei != 0 {true} else {false};
-        // if self.ime != ime {
-        //     return Err(format!("Expected IME: {}, actual: {}", ime, self.ime))
-        // }
-        // if self.ie != ime {
-        //     return Err(format!("Expected IE: {}, actual: {}", ie, self.ie))
-        // }
+        Ok(())
+    }
 
+    pub fn write_state(&self, w: &mut StateWriter) {
+        w.u8(self.a);
+        w.u8(self.get_register_8(&Register8::F));
+        w.u8(self.flags.lower);
+        w.u16(self.get_register_16(&Register16::BC));
+        w.u16(self.get_register_16(&Register16::DE));
+        w.u16(self.get_register_16(&Register16::HL));
+        w.u16(self.sp);
+        w.u16(self.pc);
+        w.bool(self.ime);
+        w.bool(self.ie);
+        w.bool(self.halt);
+        w.bool(self.halt_bug);
+        w.bool(self.stopped);
+        w.bool(self.locked);
+    }
+
+    pub fn read_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.a = r.u8()?;
+        self.set_register_8(&Register8::F, r.u8()?);
+        self.flags.lower = r.u8()?;
+        self.set_register_16(&Register16::BC, r.u16()?);
+        self.set_register_16(&Register16::DE, r.u16()?);
+        self.set_register_16(&Register16::HL, r.u16()?);
+        self.sp = r.u16()?;
+        self.pc = r.u16()?;
+        self.ime = r.bool()?;
+        self.ie = r.bool()?;
+        self.halt = r.bool()?;
+        self.halt_bug = r.bool()?;
+        self.stopped = r.bool()?;
+        self.locked = r.bool()?;
         Ok(())
     }
 
@@ -110,12 +243,12 @@ impl CPU {
 
     pub fn simulate_bootloader(&mut self) {
         self.a = 0x01;
-        self.bc.sub.high = 0;
-        self.bc.sub.low = 0x13;
-        self.de.sub.high = 0;
-        self.de.sub.low = 0xd8;
-        self.hl.sub.high = 0x01;
-        self.hl.sub.low = 0x4d;
+        self.bc.high = 0;
+        self.bc.low = 0x13;
+        self.de.high = 0;
+        self.de.low = 0xd8;
+        self.hl.high = 0x01;
+        self.hl.low = 0x4d;
         self.flags.z = 1;
         self.flags.n = 0;
         self.flags.h = 1;
@@ -141,43 +274,37 @@ impl CPU {
     }
 
     fn get_register_8(&self, reg: &Register8) -> u8 {
-        unsafe {
-            match reg {
-                Register8::A => self.a,
-                Register8::B => self.bc.sub.high,
-                Register8::C => self.bc.sub.low,
-                Register8::D => self.de.sub.high,
-                Register8::E => self.de.sub.low,
-                Register8::H => self.hl.sub.high,
-                Register8::L => self.hl.sub.low,
-                Register8::F => {
-                    return ((self.flags.z << 7) | (self.flags.n << 6) | (self.flags.h << 5) | (self.flags.cy << 4) | self.flags.lower) as u8;
-                }
-            }
+        match reg {
+            Register8::A => self.a,
+            Register8::B => self.bc.high,
+            Register8::C => self.bc.low,
+            Register8::D => self.de.high,
+            Register8::E => self.de.low,
+            Register8::H => self.hl.high,
+            Register8::L => self.hl.low,
+            Register8::F => (self.flags.z << 7) | (self.flags.n << 6) | (self.flags.h << 5) | (self.flags.cy << 4) | self.flags.lower,
         }
     }
 
     fn get_register_16(&self, reg: &Register16) -> u16 {
-        unsafe {
-            match reg {
-                Register16::BC => self.bc.full,
-                Register16::DE => self.de.full,
-                Register16::HL => self.hl.full,
-                Register16::SP => self.sp,
-                Register16::PC => self.pc
-            }
+        match reg {
+            Register16::BC => self.bc.get(),
+            Register16::DE => self.de.get(),
+            Register16::HL => self.hl.get(),
+            Register16::SP => self.sp,
+            Register16::PC => self.pc
         }
     }
 
     fn set_register_8(&mut self, reg: &Register8, val: u8) {
         match reg {
             Register8::A => self.a = val,
-            Register8::B => self.bc.sub.high = val,
-            Register8::C => self.bc.sub.low = val,
-            Register8::D => self.de.sub.high = val,
-            Register8::E => self.de.sub.low = val,
-            Register8::H => self.hl.sub.high = val,
-            Register8::L => self.hl.sub.low = val,
+            Register8::B => self.bc.high = val,
+            Register8::C => self.bc.low = val,
+            Register8::D => self.de.high = val,
+            Register8::E => self.de.low = val,
+            Register8::H => self.hl.high = val,
+            Register8::L => self.hl.low = val,
             Register8::F => {
                 self.flags.z = val>>7;
                 self.flags.n = (val & 0b01000000) >> 6;
@@ -189,91 +316,130 @@ impl CPU {
 
     fn set_register_16(&mut self, reg: &Register16, val: u16) {
         match reg {
-            Register16::BC => self.bc.full = val,
-            Register16::DE => self.de.full = val,
-            Register16::HL => self.hl.full = val,
+            Register16::BC => self.bc.set(val),
+            Register16::DE => self.de.set(val),
+            Register16::HL => self.hl.set(val),
             Register16::SP => self.sp = val,
             Register16::PC => self.pc = val
         }
     }
 
-    fn inc_register_8(&mut self, reg: &Register8){
-        let orig = self.get_register_8(reg);
-        let value = if orig == 0xff {
-            0
-        } else {
-            orig + 1
-        };
-        self.flags.z = if value == 0 { 1 } else { 0 };
-        self.flags.n = 0;
-        self.flags.h = if CPU::h_test(orig, 1) { 1 } else { 0 };
-        self.set_register_8(reg, value);
+    #[inline]
+    fn fetch(&mut self, mem: &mut Memory) -> u8 {
+        let val = mem.cpu_read(self.pc);
+        self.pc = self.pc.wrapping_add(1);
+        val
+    }
+
+    #[inline]
+    fn fetch_16(&mut self, mem: &mut Memory) -> u16 {
+        let val = mem.cpu_read_16(self.pc);
+        self.pc = self.pc.wrapping_add(2);
+        val
+    }
+
+    // The operand tables of the opcode grid, see disasm for how the opcode is split up
+    //   r8        B C D E H L (HL) A
+    //   r16       BC DE HL SP
+    //   r16_stack BC DE HL AF, for PUSH and POP
+    //   cond      NZ Z NC C
+    // (HL) goes through the bus and takes a M-cycle
+    #[inline]
+    fn r8(&mut self, mem: &mut Memory, i: u8) -> u8 {
+        match i {
+            0 => self.bc.high,
+            1 => self.bc.low,
+            2 => self.de.high,
+            3 => self.de.low,
+            4 => self.hl.high,
+            5 => self.hl.low,
+            6 => mem.cpu_read(self.hl.get()),
+            _ => self.a,
+        }
     }
 
-    fn inc_register_16(&mut self, reg: &Register16) {
-        let orig = self.get_register_16(reg);
-        let value = if orig == 0xffff {
-            0
-        } else {
-            orig + 1
-        };
-        self.set_register_16(reg, value);
+    #[inline]
+    fn set_r8(&mut self, mem: &mut Memory, i: u8, val: u8) {
+        match i {
+            0 => self.bc.high = val,
+            1 => self.bc.low = val,
+            2 => self.de.high = val,
+            3 => self.de.low = val,
+            4 => self.hl.high = val,
+            5 => self.hl.low = val,
+            6 => mem.cpu_write(self.hl.get(), val),
+            _ => self.a = val,
+        }
     }
 
-    fn dec_register_8(&mut self, reg: &Register8) {
-        let orig = self.get_register_8(reg);
-        let value = if orig == 0 {
-            0xff
-        } else {
-            orig - 1
-        };
-        self.flags.z = if value == 0 { 1 } else { 0 };
-        self.flags.n = 1;
-        self.flags.h = if CPU::h_test_sub(orig, 1) {1} else {0};
-        self.set_register_8(reg, value);
+    #[inline]
+    fn r16(&self, p: u8) -> u16 {
+        match p {
+            0 => self.bc.get(),
+            1 => self.de.get(),
+            2 => self.hl.get(),
+            _ => self.sp,
+        }
     }
 
-    fn dec_register_16(&mut self, reg: &Register16) {
-        let orig = self.get_register_16(reg);
-        let value = if orig == 0 {
-            0xffff
-        } else {
-            orig - 1
-        };
-        self.set_register_16(reg, value);
+    #[inline]
+    fn set_r16(&mut self, p: u8, val: u16) {
+        match p {
+            0 => self.bc.set(val),
+            1 => self.de.set(val),
+            2 => self.hl.set(val),
+            _ => self.sp = val,
+        }
     }
 
-    fn inc_mem(&mut self, mem: &mut Memory, addr: u16) {
-        let orig = mem.read(addr);
-        let value = if orig == 0xff {
-            0
-        } else {
-            orig + 1
-        };
+    fn r16_stack(&self, p: u8) -> u16 {
+        match p {
+            3 => u16::from_be_bytes([self.a, self.get_register_8(&Register8::F)]),
+            _ => self.r16(p),
+        }
+    }
+
+    fn set_r16_stack(&mut self, p: u8, val: u16) {
+        match p {
+            3 => {
+                let [a, f] = val.to_be_bytes();
+                self.a = a;
+                self.set_register_8(&Register8::F, f);
+            },
+            _ => self.set_r16(p, val),
+        }
+    }
+
+    #[inline]
+    fn cond(&self, i: u8) -> bool {
+        match i & 0x03 {
+            0 => self.flags.z == 0,
+            1 => self.flags.z == 1,
+            2 => self.flags.cy == 0,
+            _ => self.flags.cy == 1,
+        }
+    }
+
+    fn inc(&mut self, orig: u8) -> u8 {
+        let value = orig.wrapping_add(1);
         self.flags.z = if value == 0 { 1 } else { 0 };
         self.flags.n = 0;
         self.flags.h = if CPU::h_test(orig, 1) { 1 } else { 0 };
-        mem.write(addr, value);
+        value
     }
 
-    fn dec_mem(&mut self, mem: &mut Memory, addr: u16) {
-        let orig = mem.read(addr);
-        let value = if orig == 0 {
-            0xff
-        } else {
-            orig - 1
-        };
+    fn dec(&mut self, orig: u8) -> u8 {
+        let value = orig.wrapping_sub(1);
         self.flags.z = if value == 0 { 1 } else { 0 };
         self.flags.n = 1;
         self.flags.h = if CPU::h_test_sub(orig, 1) {1} else {0};
-        mem.write(addr, value);
+        value
     }
 
-    fn add_hl(&mut self, reg: &Register16) {
-        let reg_val = self.get_register_16(reg);
-        let hl = self.get_register_16(&Register16::HL);
+    fn add_hl(&mut self, reg_val: u16) {
+        let hl = self.hl.get();
         let (value, overflow) = hl.overflowing_add(reg_val);
-        self.set_register_16(&Register16::HL, value);
+        self.hl.set(value);
 
         self.flags.n = 0;
         if overflow {self.flags.cy = 1} else { self.flags.cy = 0 }
@@ -284,32 +450,30 @@ impl CPU {
         }
     }
 
-    fn jump_relative(&mut self, s8: u8){
-        let mut s8 = s8;
-        if s8 >> 7 == 1 {
-            s8 = (!s8) + 1;
-            self.pc -= s8 as u16;
-        } else {
-            self.pc += s8 as u16;
+    // ADD SP, s8 and LD HL, SP+s8. H and C come from adding s8 to the low byte of SP as if it were unsigned
+    fn sp_plus_s8(&mut self, s8: u8) -> u16 {
+        let sp = self.sp;
+        self.flags.z = 0;
+        self.flags.n = 0;
+        self.flags.h = if (sp & 0x0F) + (s8 as u16 & 0x0F) > 0x0F {1} else {0};
+        self.flags.cy = if (sp & 0xFF) + s8 as u16 > 0xFF {1} else {0};
+        sp.wrapping_add(s8 as i8 as u16)
+    }
+
+    // ALU A, operand in the order of y
+    fn alu(&mut self, y: u8, val: u8) {
+        match y {
+            0 => self.add_to_a(val),
+            1 => self.adc_to_a(val),
+            2 => self.sub_from_a(val),
+            3 => self.sbc_from_a(val),
+            4 => self.and_a(val),
+            5 => self.xor_a(val),
+            6 => self.or_a(val),
+            _ => self.cp(self.a, val),
         }
     }
 
-    fn ld_r_r(&mut self, reg1: &Register8, reg2: &Register8) {
-        let val = self.get_register_8(reg2);
-        self.set_register_8(reg1, val);
-    }
-
-    fn ld_r_hl(&mut self, mem: &Memory, reg: &Register8) {
-        let val = mem.read(self.get_register_16(&Register16::HL));
-        self.set_register_8(reg, val);
-    }
-
-    fn ld_hl_r(&self, mem: &mut Memory, reg: &Register8) {
-        let val = self.get_register_8(reg);
-        let addr = self.get_register_16(&Register16::HL);
-        mem.write(addr, val);
-    }
-
     fn add_to_a(&mut self, val: u8){
         let orig = self.get_register_8(&Register8::A);
         let (value, carry) = orig.overflowing_add(val);
@@ -469,207 +633,286 @@ impl CPU {
 
     fn pop(&mut self, mem: &mut Memory) -> u16{
         let addr = self.get_register_16(&Register16::SP);
-        let value = mem.read_16(addr);
+        let value = mem.cpu_read_16(addr);
         self.set_register_16(&Register16::SP, addr + 2);
         return value
     }
 
+    // An internal cycle to decrement SP, then the high byte is written first
     fn push(&mut self, mem: &mut Memory, value: u16) {
-        let addr = self.get_register_16(&Register16::SP) - 2;
-        mem.write_16(addr, value);
+        let addr = self.get_register_16(&Register16::SP).wrapping_sub(2);
+        mem.cpu_idle();
+        mem.cpu_write(addr.wrapping_add(1), (value >> 8) as u8);
+        mem.cpu_write(addr, value as u8);
         self.set_register_16(&Register16::SP, addr);
     }
 
+    // The address is read even when the condition fails
+    fn jp_cc(&mut self, mem: &mut Memory, cond: bool) -> u8 {
+        let a16 = mem.cpu_read_16(self.pc);
+        self.pc = self.pc.wrapping_add(2);
+        if !cond {
+            return 3
+        }
+        self.pc = a16;
+        4
+    }
+
+    fn call_cc(&mut self, mem: &mut Memory, cond: bool) -> u8 {
+        let a16 = mem.cpu_read_16(self.pc);
+        self.pc = self.pc.wrapping_add(2);
+        if !cond {
+            return 3
+        }
+        self.push(mem, self.pc);
+        self.pc = a16;
+        6
+    }
+
+    // The condition is checked in an internal cycle before popping
+    fn ret_cc(&mut self, mem: &mut Memory, cond: bool) -> u8 {
+        mem.cpu_idle();
+        if !cond {
+            return 2
+        }
+        self.pc = self.pop(mem);
+        5
+    }
+
     fn set_interrupt(&mut self, value: bool){
         self.ime = value;
     }
 
-    fn rlc(&mut self, reg: &Register8, check_z: bool) {
-        let value = self.get_register_8(reg).rotate_left(1);
-        self.set_register_8(reg, value);
-        self.flags.cy = value & 0x01;
+    // The CB rotates and shifts in the order of y: RLC RRC RL RR SLA SRA SWAP SRL
+    fn rotate(&mut self, y: u8, orig: u8) -> u8 {
+        let (value, carry) = match y {
+            0 => (orig.rotate_left(1), orig >> 7),
+            1 => (orig.rotate_right(1), orig & 0x01),
+            2 => ((orig << 1) | self.flags.cy, orig >> 7),
+            3 => ((orig >> 1) | (self.flags.cy << 7), orig & 0x01),
+            4 => (orig << 1, orig >> 7),
+            5 => ((orig >> 1) | (orig & 0x80), orig & 0x01),
+            6 => (orig.rotate_left(4), 0),
+            _ => (orig >> 1, orig & 0x01),
+        };
+        self.flags.z = if value == 0 {1} else {0};
+        self.flags.n = 0;
         self.flags.h = 0;
-        if check_z {
-            self.flags.z = if value == 0 {1} else {0};
+        self.flags.cy = carry;
+        value
+    }
+
+    fn daa(&mut self) {
+        let mut a = self.a as u16;
+
+        if self.flags.n == 0 {
+            if self.flags.h > 0 || a & 0x0f > 0x09 {
+                a = a.wrapping_add(0x6);
+            }
+            if self.flags.cy > 0 || a > 0x9F {
+                a = a.wrapping_add(0x60);
+                self.flags.cy = 1;
+            }
         } else {
-            self.flags.z = 0;
+            if self.flags.h > 0 {
+                a = a.wrapping_sub(0x6);
+            }
+            if self.flags.cy > 0 {
+                a = a.wrapping_sub(0x60);
+            }
         }
-        self.flags.n = 0;
-    }
 
-    fn rl(&mut self, reg: &Register8, check_z: bool) {
-        let orig = self.get_register_8(reg);
-        let mut value = orig << 1;
-        value = value | self.flags.cy;
-        self.flags.cy = orig >> 7;
-        self.set_register_8(reg, value);
-        self.flags.h = 0;
-        if check_z {
-            self.flags.z = if value == 0 {1} else {0};
+        if a & 0xff == 0 {
+            self.flags.z = 1;
         } else {
             self.flags.z = 0;
         }
-        self.flags.n = 0;
-    }
 
-    fn rrc(&mut self, reg: &Register8, check_z: bool){
-        let value = self.get_register_8(reg).rotate_right(1);
-        self.set_register_8(reg, value);
-        self.flags.cy = (value>>7) & 0x01;
         self.flags.h = 0;
-        if check_z {
-            self.flags.z = if value == 0 {1} else {0};
+
+        self.a = (a & 0xff) as u8;
+    }
+
+    fn stop(&mut self, mem: &mut Memory) -> u8 {
+        // Encoded as 0x10 0x00, the second byte is skipped
+        self.pc = self.pc.wrapping_add(1);
+        mem.write(0xFF04, 0);
+        if mem.speed_switch_armed() {
+            // CGB speed switch requested through KEY1, execution continues
+            mem.switch_speed();
         } else {
-            self.flags.z = 0;
+            self.stopped = true;
         }
-        self.flags.n = 0;
+        1
     }
 
-    fn rr(&mut self, reg: &Register8, check_z: bool){
-        let orig = self.get_register_8(reg);
-        let mut value = orig >> 1;
-        value = (value & 0b01111111) | (self.flags.cy << 7);
-        self.flags.cy = orig & 0x01;
-        self.set_register_8(reg, value);
-        self.flags.h = 0;
-        if check_z {
-            self.flags.z = if value == 0 {1} else {0};
+    fn enter_halt(&mut self, mem: &mut Memory) -> u8 {
+        let pending = mem.read(0xFF0F) & mem.read(0xFFFF) & 0x1F;
+        if !self.ime && pending != 0 {
+            // HALT bug: the CPU doesn't halt and the next byte is read twice
+            self.halt_bug = true;
         } else {
-            self.flags.z = 0;
+            self.halt = true;
         }
-        self.flags.n = 0;
+        1
     }
 
-    fn sl(&mut self, reg: &Register8) {
-        let orig = self.get_register_8(reg);
-        let val = orig << 1;
-        self.set_register_8(reg, val);
-        self.flags.cy = if (orig & 0x80) == 0x80 {1}else{0};
-        self.flags.z = if val == 0 {1} else {0};
-        self.flags.n = 0;
-        self.flags.h = 0;
+    // Illegal opcodes lock up the CPU until it is reset. Only the CPU stops, the rest of the system keeps running
+    fn lock(&mut self, opcode: u8) -> u8 {
+        self.locked = true;
+        self.fault = Some(EmulationFault{ pc: self.pc.wrapping_sub(1), opcode });
+        1
     }
 
-    fn sra(&mut self, reg: &Register8) {
-        let orig = self.get_register_8(reg);
-        let val = (orig >> 1) | (orig & 0x80);
-        self.set_register_8(reg, val);
-        self.flags.cy = if (orig & 0x01) == 0x01 {1} else {0};
-        self.flags.z = if val == 0 {1} else {0};
-        self.flags.n = 0;
-        self.flags.h = 0;
+    // Interrupt sources in priority order, the lowest bit of IF & IE wins
+    //   Bit 0  VBlank  0x0040
+    //   Bit 1  STAT    0x0048
+    //   Bit 2  Timer   0x0050
+    //   Bit 3  Serial  0x0058
+    //   Bit 4  Joypad  0x0060
+    // Returns the M-cycles spent waking from HALT and dispatching
+    fn handle_interrupt(&mut self, mem: &mut Memory) -> u8 {
+        let pending = mem.read(0xFF0F) & mem.read(0xFFFF) & 0x1F;
+        let mut cycles = 0;
+
+        if self.halt {
+            if pending == 0 {
+                return 1;
+            }
+            // Leaving HALT takes an extra M-cycle
+            self.halt = false;
+            mem.cpu_idle();
+            cycles += 1;
+        }
+
+        if !self.ime || pending == 0 {
+            return cycles;
+        }
+        self.ime = false;
+
+        // Dispatch takes 5 M-cycles: two idle cycles, two pushes and the jump.
+        // The high byte of PC is pushed first and the vector is only chosen after that push,
+        // so pushing into IE at 0xFFFF can change or cancel the interrupt. Cancelled dispatches jump to 0x0000
+        let pc = self.pc;
+        mem.cpu_idle();
+        mem.cpu_idle();
+        self.sp = self.sp.wrapping_sub(1);
+        mem.cpu_write(self.sp, (pc >> 8) as u8);
+        let pending = mem.read(0xFF0F) & mem.read(0xFFFF) & 0x1F;
+        self.sp = self.sp.wrapping_sub(1);
+        mem.cpu_write(self.sp, pc as u8);
+
+        if pending == 0 {
+            self.pc = 0x0000;
+        } else {
+            let bit = pending.trailing_zeros() as u16;
+            mem.write(0xFF0F, mem.read(0xFF0F) & !(1 << bit));
+            self.pc = 0x0040 + bit * 8;
+        }
+        mem.cpu_idle();
+        cycles + 5
     }
 
-    fn srl(&mut self, reg: &Register8) {
-        let orig = self.get_register_8(reg);
-        let val = orig >> 1;
-        self.set_register_8(reg, val);
-        self.flags.cy = if (orig & 0x01) == 0x01 {1} else {0};
-        self.flags.z = if val == 0 {1} else {0};
-        self.flags.n = 0;
-        self.flags.h = 0;
+    pub fn pc(&self) -> u16 {
+        self.pc
     }
 
-    fn bit(&mut self, reg: &Register8, bit: u8) {
-        let val = self.get_register_8(reg);
-        let test = 0x01 << bit;
-        let result = val & test;
-        self.flags.z = if result == 0 {1} else {0};
-        self.flags.n = 0;
-        self.flags.h = 1;
+    // Logs every instruction to the sink before it runs, None stops logging.
+    // Memory::set_ly_stub should be on as well for traces comparable with Gameboy Doctor
+    pub fn set_trace_sink(&mut self, sink: Option<Box<dyn TraceSink>>) {
+        self.trace = sink;
     }
 
-    fn res(&mut self, reg: &Register8, bit: u8) {
-        let orig = self.get_register_8(reg);
-        let val = orig & !(1 << bit);
-        self.set_register_8(reg, val);
+    pub fn tracing(&self) -> bool {
+        self.trace.is_some()
     }
 
-    fn set(&mut self, reg: &Register8, bit: u8) {
-        let orig = self.get_register_8(reg);
-        let val = orig | (1 << bit);
-        self.set_register_8(reg, val);
+    fn doctor_line(&self, mem: &Memory) -> String {
+        let r = self.registers();
+        let pcmem = [0, 1, 2, 3].map(|i| mem.read(r.pc.wrapping_add(i)));
+        format!("A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+                r.a, r.f, r.b, r.c, r.d, r.e, r.h, r.l, r.sp, r.pc, pcmem[0], pcmem[1], pcmem[2], pcmem[3])
     }
 
-    fn swap(&mut self, reg: &Register8) {
-        let orig = self.get_register_8(reg);
-        let value = (orig << 4) | (orig >> 4);
-        self.set_register_8(reg, value);
-        self.flags.z = if value == 0 {1} else {0};
-        self.flags.cy = 0;
-        self.flags.n = 0;
-        self.flags.h = 0;
+    pub fn halted(&self) -> bool {
+        self.halt
     }
 
-    pub fn get_timer_rate(timer_control: u8) -> u16 {
-        match timer_control & 0b11 {
-            0 => 256,
-            1 => 4,
-            2 => 16,
-            3 => 64,
-            _ => unreachable!()
+    pub fn registers(&self) -> Registers {
+        Registers {
+            a: self.a,
+            f: self.get_register_8(&Register8::F),
+            b: self.bc.high,
+            c: self.bc.low,
+            d: self.de.high,
+            e: self.de.low,
+            h: self.hl.high,
+            l: self.hl.low,
+            sp: self.sp,
+            pc: self.pc,
+            ime: self.ime,
+            halted: self.halt,
         }
     }
 
-    fn handle_interrupt(&mut self, mem: &mut Memory) -> u8 {
-        let i_flags = mem.read(0xFF0F);
-        let ie = mem.read(0xFFFF);
-
-        if self.halt {
-            if (i_flags & ie) != 0 {
-                self.halt = false
-            } else {
-                return 1;
-            }
+    // Sets a register by name, 8-bit registers take the low byte
+    pub fn set_register(&mut self, reg: debugger::Register, val: u16) {
+        use debugger::Register as R;
+        let [high, low] = val.to_be_bytes();
+        match reg {
+            R::A => self.set_register_8(&Register8::A, low),
+            R::F => self.set_register_8(&Register8::F, low),
+            R::B => self.set_register_8(&Register8::B, low),
+            R::C => self.set_register_8(&Register8::C, low),
+            R::D => self.set_register_8(&Register8::D, low),
+            R::E => self.set_register_8(&Register8::E, low),
+            R::H => self.set_register_8(&Register8::H, low),
+            R::L => self.set_register_8(&Register8::L, low),
+            R::AF => {
+                self.set_register_8(&Register8::A, high);
+                self.set_register_8(&Register8::F, low);
+            },
+            R::BC => self.set_register_16(&Register16::BC, val),
+            R::DE => self.set_register_16(&Register16::DE, val),
+            R::HL => self.set_register_16(&Register16::HL, val),
+            R::SP => self.set_register_16(&Register16::SP, val),
+            R::PC => self.set_register_16(&Register16::PC, val),
         }
+    }
 
-        if !self.ime {
-            return 0
-        }
-        if i_flags == 0 {
-            return 0;
-        }
+    pub fn locked(&self) -> bool {
+        self.locked
+    }
 
-        // VBlank interrupt
-        if i_flags & ie & 0b1 > 0 {
-            self.ime = false;
-            self.push(mem, self.get_register_16(&Register16::PC));
-            self.set_register_16(&Register16::PC, 0x0040);
-            mem.write(0xFF0F, mem.read(0xFF0F) & !1);
-            return 5;
-        }
+    // The fault that locked the CPU, returned once
+    pub fn take_fault(&mut self) -> Option<EmulationFault> {
+        self.fault.take()
+    }
 
-        // STAT interrupt
-        if i_flags & ie & 0b10 > 0 {
-            self.ime = false;
-            self.push(mem, self.get_register_16(&Register16::PC));
-            self.set_register_16(&Register16::PC, 0x0048);
-            mem.write(0xFF0F, mem.read(0xFF0F) & !0b10);
-            return 5;
+    // Runs one instruction, or a single M-cycle while halted, stopped or locked, and returns the M-cycles taken.
+    // Memory and everything clocked with it advance a M-cycle at a time as the instruction uses the bus
+    pub fn run(&mut self, mem: &mut Memory) -> u8 {
+        let start = mem.cycles();
+        let cycles = self.execute(mem);
+        // Internal cycles that aren't spelled out in the instruction
+        while mem.cycles() - start < cycles as u64 {
+            mem.cpu_idle();
         }
+        (mem.cycles() - start) as u8
+    }
 
-        // Timer
-        if i_flags & ie & 0b100 > 0{
-            self.ime = false;
-            self.push(mem, self.get_register_16(&Register16::PC));
-            self.set_register_16(&Register16::PC, 0x0050);
-            mem.write(0xFF0F, mem.read(0xFF0F) & !0b100);
-            return 5;
+    fn execute(&mut self, mem: &mut Memory) -> u8{
+        if self.locked {
+            return 1;
         }
 
-        // Input
-        if i_flags & ie & 0b10000 > 0{
-            self.ime = false;
-            self.push(mem, self.get_register_16(&Register16::PC));
-            self.set_register_16(&Register16::PC, 0x0060);
-            mem.write(0xFF0F, mem.read(0xFF0F) & !0b10000);
-            return 5;
+        if self.stopped {
+            if mem.read(0xFF00) & 0x0F == 0x0F {
+                return 1;
+            }
+            self.stopped = false;
         }
 
-        return 0
-    }
-
-    pub fn run(&mut self, mem: &mut Memory) -> u8{
         let v = self.handle_interrupt(mem);
         if self.ie {
             self.set_interrupt(true);
@@ -678,1707 +921,315 @@ impl CPU {
         if v != 0 || self.halt {
             return v;
         }
-        let instruction = mem.read(self.pc);
-        self.pc += 1;
-
-        match instruction {
-            0x00 => { // NOP
-                return 1
-            }
-            0x01 => { // LD BC, d16
-                let value = mem.read_16(self.pc);
-                self.pc += 2;
-                self.set_register_16(&Register16::BC, value);
-                return 3
-            }
-            0x02 => { // LD (BC), A
-                mem.write(self.get_register_16(&Register16::BC), self.get_register_8(&Register8::A));
-                return 2
-            }
-            0x03 => { // INC BC
-                self.inc_register_16(&Register16::BC);
-                return 2
-            }
-            0x04 => { // INC B
-                self.inc_register_8(&Register8::B);
-                return 1
-            }
-            0x05 => { // DEC B
-                self.dec_register_8(&Register8::B);
-                return 1
-            }
-            0x06 => { // LD B, d8
-                let d8 = mem.read(self.pc);
-                self.pc += 1;
-                self.set_register_8(&Register8::B, d8);
-                return 2
-            }
-            0x07 => { // RLCA
-                self.rlc(&Register8::A, false);
-                return 1
-            }
-            0x08 => { // LD (a16), SP
-                let a16 = mem.read_16(self.pc);
-                self.pc += 2;
-                mem.write_16(a16, self.get_register_16(&Register16::SP));
-                return 5
-            }
-            0x09 => { // ADD HL, BC
-                self.add_hl(&Register16::BC);
-                return 2
-            }
-            0x0A => { // LD A, (BC)
-                let val = mem.read(self.get_register_16(&Register16::BC));
-                self.set_register_8(&Register8::A, val);
-                return 2
-            }
-            0x0B => { // DEC BC
-                self.dec_register_16(&Register16::BC);
-                return 2
-            }
-            0x0C => { // INC C
-                self.inc_register_8(&Register8::C);
-                return 1
-            }
-            0x0D => { // DEC C
-                self.dec_register_8(&Register8::C);
-                return 1
-            }
-            0x0E => { // LD C, d8
-                let d8 = mem.read(self.pc);
-                self.pc += 1;
-                self.set_register_8(&Register8::C, d8);
-                return 2
+        if self.trace.is_some() {
+            let line = self.doctor_line(mem);
+            if let Some(sink) = self.trace.as_mut() {
+                sink.write_line(&line);
             }
-            0x0F => { // RRCA
-                self.rrc(&Register8::A, false);
-                return 1
-            }
-            // TODO 0x10 STOP
-            0x11 => { // LD DE, d16
-                let value = mem.read_16(self.pc);
-                self.pc += 2;
-                self.set_register_16(&Register16::DE, value);
-                return 3
-            }
-            0x12 => { // LD (DE), A
-                mem.write(self.get_register_16(&Register16::DE), self.get_register_8(&Register8::A));
-                return 2
-            }
-            0x13 => { // INC DE
-                self.inc_register_16(&Register16::DE);
-                return 2
-            }
-            0x14 => { // INC D
-                self.inc_register_8(&Register8::D);
-                return 1
-            }
-            0x15 => { // DEC D
-                self.dec_register_8(&Register8::D);
-                return 1
-            }
-            0x16 => { // LD D, d8
-                let d8 = mem.read(self.pc);
-                self.pc += 1;
-                self.set_register_8(&Register8::D, d8);
-                return 2
-            }
-            0x17 => { // RLA
-                self.rl(&Register8::A, false);
-                return 1
-            }
-            0x18 => { // JR s8
-                let s8 = mem.read(self.pc);
-                self.pc += 1;
-                self.jump_relative(s8);
-                return 3
-            }
-            0x19 => { // ADD HL, DE
-                self.add_hl(&Register16::DE);
-                return 2
-            }
-            0x1A => { // LD A, (DE)
-                let val = mem.read(self.get_register_16(&Register16::DE));
-                self.set_register_8(&Register8::A, val);
-                return 2
-            }
-            0x1B => { // DEC DE
-                self.dec_register_16(&Register16::DE);
-                return 2
-            }
-            0x1C => { // INC E
-                self.inc_register_8(&Register8::E);
-                return 1
-            }
-            0x1D => { // DEC E
-                self.dec_register_8(&Register8::E);
-                return 1
-            }
-            0x1E => { // LD E, d8
-                let d8 = mem.read(self.pc);
-                self.pc += 1;
-                self.set_register_8(&Register8::E, d8);
-                return 2
-            }
-            0x1F => { // RRA
-                self.rr(&Register8::A, false);
-                return 1
-            }
-            0x20 => { // JR NZ, s8
-                let s8 = mem.read(self.pc);
-                self.pc += 1;
-                if self.flags.z == 0 {
-                    self.jump_relative(s8);
-                    return 3
+        }
+        let opcode = mem.cpu_read(self.pc);
+        if self.halt_bug {
+            self.halt_bug = false;
+        } else {
+            self.pc = self.pc.wrapping_add(1);
+        }
+        OPCODES[(opcode >> 4) as usize][(opcode & 0x0F) as usize](self, mem)
+    }
+
+    // Runs an opcode that has already been fetched. Always inlined into the handlers in OPCODES, where the opcode
+    // is a constant so all of the decoding below is done at compile time.
+    #[inline(always)]
+    fn execute_opcode(&mut self, mem: &mut Memory, opcode: u8) -> u8 {
+        // Decoded like in disasm: x picks the block, z the operation and y, p and q the operands
+        let x = opcode >> 6;
+        let y = (opcode >> 3) & 0x07;
+        let z = opcode & 0x07;
+        let p = y >> 1;
+        let q = y & 0x01;
+
+        match (x, z) {
+            (0, 0) => match y {
+                0 => 1, // NOP
+                1 => { // LD (a16), SP
+                    let a16 = self.fetch_16(mem);
+                    mem.cpu_write_16(a16, self.sp);
+                    5
                 }
-                return 2
-            }
-            0x21 => { // LD HL, d16
-                let d16 = mem.read_16(self.pc);
-                self.pc += 2;
-                self.set_register_16(&Register16::HL, d16);
-                return 3
-            }
-            0x22 => { // LD (HL+), A
-                mem.write(self.get_register_16(&Register16::HL), self.get_register_8(&Register8::A));
-                self.inc_register_16(&Register16::HL);
-                return 2
-            }
-            0x23 => { // INC HL
-                self.inc_register_16(&Register16::HL);
-                return 2
-            }
-            0x24 => { // INC H
-                self.inc_register_8(&Register8::H);
-                return 1
-            }
-            0x25 => { // DEC H
-                self.dec_register_8(&Register8::H);
-                return 1
-            }
-            0x26 => { // LD H, d8
-                let d8 = mem.read(self.pc);
-                self.pc += 1;
-                self.set_register_8(&Register8::H, d8);
-                return 2
-            }
-            0x27 => { // DAA
-                let mut a = self.get_register_8(&Register8::A) as u16;
-
-                if self.flags.n == 0 {
-                    if self.flags.h > 0 || a & 0x0f > 0x09 {
-                        a = a.wrapping_add(0x6);
-                        //a += 0x6;
-                    }
-                    if self.flags.cy > 0 || a > 0x9F {
-                        a = a.wrapping_add(0x60);
-                        //a += 0x60;
-                        self.flags.cy = 1;
+                2 => self.stop(mem),
+                _ => { // JR s8, JR cc, s8
+                    let s8 = self.fetch(mem);
+                    if y == 3 || self.cond(y) {
+                        self.pc = self.pc.wrapping_add(s8 as i8 as u16);
+                        return 3
                     }
+                    2
+                }
+            },
+            (0, 1) if q == 0 => { // LD r16, d16
+                let d16 = self.fetch_16(mem);
+                self.set_r16(p, d16);
+                3
+            }
+            (0, 1) => { // ADD HL, r16
+                self.add_hl(self.r16(p));
+                2
+            }
+            (0, 2) => { // LD (r16), A and LD A, (r16). (HL+) and (HL-) change HL after the access
+                let addr = match p {
+                    0 => self.bc.get(),
+                    1 => self.de.get(),
+                    _ => self.hl.get(),
+                };
+                if q == 0 {
+                    mem.cpu_write(addr, self.a);
                 } else {
-                    if self.flags.h > 0 {
-                        a = a.wrapping_sub(0x6);
-                        //a -= 0x6;
+                    self.a = mem.cpu_read(addr);
+                }
+                match p {
+                    2 => self.hl.set(addr.wrapping_add(1)),
+                    3 => self.hl.set(addr.wrapping_sub(1)),
+                    _ => {}
+                }
+                2
+            }
+            (0, 3) => { // INC r16, DEC r16
+                let val = self.r16(p);
+                self.set_r16(p, if q == 0 { val.wrapping_add(1) } else { val.wrapping_sub(1) });
+                2
+            }
+            (0, 4) => { // INC r8
+                let val = self.r8(mem, y);
+                let val = self.inc(val);
+                self.set_r8(mem, y, val);
+                if y == 6 {3} else {1}
+            }
+            (0, 5) => { // DEC r8
+                let val = self.r8(mem, y);
+                let val = self.dec(val);
+                self.set_r8(mem, y, val);
+                if y == 6 {3} else {1}
+            }
+            (0, 6) => { // LD r8, d8
+                let d8 = self.fetch(mem);
+                self.set_r8(mem, y, d8);
+                if y == 6 {3} else {2}
+            }
+            (0, _) => {
+                match y {
+                    0..=3 => { // RLCA, RRCA, RLA, RRA work like the CB rotates but always clear Z
+                        self.a = self.rotate(y, self.a);
+                        self.flags.z = 0;
+                    }
+                    4 => self.daa(),
+                    5 => { // CPL inverts A
+                        self.a = !self.a;
+                        self.flags.n = 1;
+                        self.flags.h = 1;
+                    }
+                    6 => { // SCF
+                        self.flags.cy = 1;
+                        self.flags.h = 0;
+                        self.flags.n = 0;
                     }
-                    if self.flags.cy > 0 {
-                        a = a.wrapping_sub(0x60);
-                        //a -= 0x60;
+                    _ => { // CCF
+                        self.flags.cy ^= 1;
+                        self.flags.n = 0;
+                        self.flags.h = 0;
                     }
                 }
-
-                if a & 0xff == 0 {
-                    self.flags.z = 1;
-                } else {
-                    self.flags.z = 0;
+                1
+            }
+            (1, 6) if y == 6 => self.enter_halt(mem), // HALT takes the place of LD (HL), (HL)
+            (1, _) => { // LD r8, r8
+                let val = self.r8(mem, z);
+                self.set_r8(mem, y, val);
+                if y == 6 || z == 6 {2} else {1}
+            }
+            (2, _) => { // ALU A, r8
+                let val = self.r8(mem, z);
+                self.alu(y, val);
+                if z == 6 {2} else {1}
+            }
+            (3, 0) => match y {
+                0..=3 => { // RET cc
+                    let cond = self.cond(y);
+                    self.ret_cc(mem, cond)
                 }
-
-                self.flags.h = 0;
-
-                self.set_register_8(&Register8::A, (a & 0xff) as u8);
-
-                return 1
-            }
-            0x28 => { // JR Z, s8
-                let s8 = mem.read(self.pc);
-                self.pc += 1;
-                if self.flags.z == 1 {
-                    self.jump_relative(s8);
-                    return 3
+                4 => { // LD (a8), A
+                    let addr = 0xFF00 | self.fetch(mem) as u16;
+                    mem.cpu_write(addr, self.a);
+                    3
                 }
-                return 2
-            }
-            0x29 => { // ADD HL, HL
-                self.add_hl(&Register16::HL);
-                return 2
-            }
-            0x2A => { // LD A, (HL+)
-                let val = mem.read(self.get_register_16(&Register16::HL));
-                self.set_register_8(&Register8::A, val);
-                self.inc_register_16(&Register16::HL);
-                return 2
-            }
-            0x2B => { // DEC HL
-                self.dec_register_16(&Register16::HL);
-                return 2
-            }
-            0x2C => { // INC L
-                self.inc_register_8(&Register8::L);
-                return 1
-            }
-            0x2D => { // DEC L
-                self.dec_register_8(&Register8::L);
-                return 1
-            }
-            0x2E => { // LD L, d8
-                let d8 = mem.read(self.pc);
-                self.pc += 1;
-                self.set_register_8(&Register8::L, d8);
-                return 2
-            }
-            0x2F => { // CPL inverts A
-                let value = !self.get_register_8(&Register8::A);
-                self.set_register_8(&Register8::A, value);
-                self.flags.n = 1;
-                self.flags.h = 1;
-                return 1
-            }
-            0x30 => { // JR NC, s8
-                let s8 = mem.read(self.pc);
-                self.pc += 1;
-                if self.flags.cy == 0 {
-                    self.jump_relative(s8);
-                    return 3
+                5 => { // ADD SP, s8
+                    let s8 = self.fetch(mem);
+                    self.sp = self.sp_plus_s8(s8);
+                    4
                 }
-                return 2
-            }
-            0x31 => { // LD SP, d16
-                let d16 = mem.read_16(self.pc);
-                self.pc += 2;
-                self.set_register_16(&Register16::SP, d16);
-                return 3
-            }
-            0x32 => { // LD (HL-), A
-                mem.write(self.get_register_16(&Register16::HL), self.get_register_8(&Register8::A));
-                self.dec_register_16(&Register16::HL);
-                return 2
-            }
-            0x33 => { // INC SP
-                self.inc_register_16(&Register16::SP);
-                return 2
-            }
-            0x34 => { // INC (HL)
-                let addr = self.get_register_16(&Register16::HL);
-                self.inc_mem(mem, addr);
-                return 3
-            }
-            0x35 => { // DEC (HL)
-                let addr = self.get_register_16(&Register16::HL);
-                self.dec_mem(mem, addr);
-                return 3
-            }
-            0x36 => { // LD (HL), d8
-                let d8 = mem.read(self.pc);
-                self.pc += 1;
-                let addr = self.get_register_16(&Register16::HL);
-                mem.write(addr, d8);
-                return 3
-            }
-            0x37 => { // SCF
-                self.flags.cy = 1;
-                self.flags.h = 0;
-                self.flags.n = 0;
-                return 1
-            }
-            0x38 => { // JR C, s8
-                let s8 = mem.read(self.pc);
-                self.pc += 1;
-                if self.flags.cy == 1 {
-                    self.jump_relative(s8);
-                    return 3
+                6 => { // LD A, (a8)
+                    let addr = 0xFF00 | self.fetch(mem) as u16;
+                    self.a = mem.cpu_read(addr);
+                    3
                 }
-                return 2
-            }
-            0x39 => { // ADD, HL, SP
-                self.add_hl(&Register16::SP);
-                return 2
-            }
-            0x3A => { // LD A, (HL-)
-                let val = mem.read(self.get_register_16(&Register16::HL));
-                self.set_register_8(&Register8::A, val);
-                self.dec_register_16(&Register16::HL);
-                return 2
-            }
-            0x3B => { // DEC SP
-                self.dec_register_16(&Register16::SP);
-                return 2
-            }
-            0x3C => { // INC A
-                self.inc_register_8(&Register8::A);
-                return 1
-            }
-            0x3D => { // DEC A
-                self.dec_register_8(&Register8::A);
-                return 1
-            }
-            0x3E => { // LD A, d8
-                let d8 = mem.read(self.pc);
-                self.pc += 1;
-                self.set_register_8(&Register8::A, d8);
-                return 2
-            }
-            0x3F => { // CCF
-                if self.flags.cy > 0 {
-                    self.flags.cy = 0;
-                } else {
-                    self.flags.cy = 1;
+                _ => { // LD HL, SP+s8
+                    let s8 = self.fetch(mem);
+                    let val = self.sp_plus_s8(s8);
+                    self.hl.set(val);
+                    3
                 }
-                self.flags.n = 0;
-                self.flags.h = 0;
-                return 1
-            }
-            0x40 => { // LD B, B
-                self.ld_r_r(&Register8::B, &Register8::B);
-                return 1
+            },
+            (3, 1) if q == 0 => { // POP r16
+                let val = self.pop(mem);
+                self.set_r16_stack(p, val);
+                3
             }
-            0x41 => { // LD B, C
-                self.ld_r_r(&Register8::B, &Register8::C);
-                return 1
+            (3, 1) => match p {
+                0 => { // RET
+                    self.pc = self.pop(mem);
+                    4
+                }
+                1 => { // RETI
+                    self.set_interrupt(true);
+                    self.pc = self.pop(mem);
+                    4
+                }
+                2 => { // JP HL
+                    self.pc = self.hl.get();
+                    1
+                }
+                _ => { // LD SP, HL
+                    self.sp = self.hl.get();
+                    2
+                }
+            },
+            (3, 2) => match y {
+                0..=3 => { // JP cc, a16
+                    let cond = self.cond(y);
+                    self.jp_cc(mem, cond)
+                }
+                4 => { // LD (C), A
+                    mem.cpu_write(0xFF00 | self.bc.low as u16, self.a);
+                    2
+                }
+                5 => { // LD (a16), A
+                    let a16 = self.fetch_16(mem);
+                    mem.cpu_write(a16, self.a);
+                    4
+                }
+                6 => { // LD A, (C)
+                    self.a = mem.cpu_read(0xFF00 | self.bc.low as u16);
+                    2
+                }
+                _ => { // LD A, (a16)
+                    let a16 = self.fetch_16(mem);
+                    self.a = mem.cpu_read(a16);
+                    4
+                }
+            },
+            (3, 3) => match y {
+                0 => self.jp_cc(mem, true), // JP a16
+                1 => self.execute_cb(mem),
+                6 => { // DI
+                    self.set_interrupt(false);
+                    1
+                }
+                7 => { // EI, IME is only set after the next instruction
+                    self.ie = true;
+                    1
+                }
+                _ => self.lock(opcode),
+            },
+            (3, 4) if y < 4 => { // CALL cc, a16
+                let cond = self.cond(y);
+                self.call_cc(mem, cond)
             }
-            0x42 => { // LD B, D
-                self.ld_r_r(&Register8::B, &Register8::D);
-                return 1
+            (3, 5) if q == 0 => { // PUSH r16
+                let val = self.r16_stack(p);
+                self.push(mem, val);
+                4
             }
-            0x43 => { // LD B, E
-                self.ld_r_r(&Register8::B, &Register8::E);
-                return 1
+            (3, 5) if p == 0 => self.call_cc(mem, true), // CALL a16
+            (3, 6) => { // ALU A, d8
+                let d8 = self.fetch(mem);
+                self.alu(y, d8);
+                2
             }
-            0x44 => { // LD B, H
-                self.ld_r_r(&Register8::B, &Register8::H);
-                return 1
+            (3, 7) => { // RST
+                self.push(mem, self.pc);
+                self.pc = y as u16 * 8;
+                4
             }
-            0x45 => { // LD B, L
-                self.ld_r_r(&Register8::B, &Register8::L);                      
-                return 1
+            _ => self.lock(opcode),
+        }
+    }
+
+    fn execute_cb(&mut self, mem: &mut Memory) -> u8 {
+        let opcode = self.fetch(mem);
+        CB_OPCODES[(opcode >> 4) as usize][(opcode & 0x0F) as usize](self, mem)
+    }
+
+    // CB prefixed opcodes, the operand is always r8[z]. Inlined into CB_OPCODES like execute_opcode
+    #[inline(always)]
+    fn execute_cb_opcode(&mut self, mem: &mut Memory, opcode: u8) -> u8 {
+        let y = (opcode >> 3) & 0x07;
+        let z = opcode & 0x07;
+
+        let val = self.r8(mem, z);
+        match opcode >> 6 {
+            0 => { // Rotates and shifts
+                let val = self.rotate(y, val);
+                self.set_r8(mem, z, val);
             }
-            0x46 => { // LD B, (HL)
-                self.ld_r_hl(mem, &Register8::B);
-                return 2
-            }
-            0x47 => { // LD B, A
-                self.ld_r_r(&Register8::B, &Register8::A);
-                return 1
-            }
-            0x48 => { // LD C, B
-                self.ld_r_r(&Register8::C, &Register8::B);
-                return 1
-            }
-            0x49 => { // LD C, C
-                self.ld_r_r(&Register8::C, &Register8::C);
-                return 1
-            }
-            0x4A => { // LD C, D
-                self.ld_r_r(&Register8::C, &Register8::D);
-                return 1
-            }
-            0x4B => { // LD C, E
-                self.ld_r_r(&Register8::C, &Register8::E);
-                return 1
-            }
-            0x4C => { // LD C, H
-                self.ld_r_r(&Register8::C, &Register8::H);
-                return 1
-            }
-            0x4D => { // LD C, L
-                self.ld_r_r(&Register8::C, &Register8::L);
-                return 1
-            }
-            0x4E => { // LD C, (HL)
-                self.ld_r_hl(mem, &Register8::C);
-                return 2
-            }
-            0x4F => { // LD C, A
-                self.ld_r_r(&Register8::C, &Register8::A);
-                return 1
-            }
-            0x50 => { // LD D, B
-                self.ld_r_r(&Register8::D, &Register8::B);
-                return 1
-            }
-            0x51 => { // LD D, C
-                self.ld_r_r(&Register8::D, &Register8::C);
-                return 1
-            }
-            0x52 => { // LD D, D
-                self.ld_r_r(&Register8::D, &Register8::D);
-                return 1
-            }
-            0x53 => { // LD D, E
-                self.ld_r_r(&Register8::D, &Register8::E);
-                return 1
-            }
-            0x54 => { // LD D, H
-                self.ld_r_r(&Register8::D, &Register8::H);
-                return 1
-            }
-            0x55 => { // LD D, L
-                self.ld_r_r(&Register8::D, &Register8::L);
-                return 1
-            }
-            0x56 => { // LD D, (HL)
-                self.ld_r_hl(mem, &Register8::D);
-                return 2
-            }
-            0x57 => { // LD D, A
-                self.ld_r_r(&Register8::D, &Register8::A);
-                return 1
-            }
-            0x58 => { // LD E, B
-                self.ld_r_r(&Register8::E, &Register8::B);
-                return 1
-            }
-            0x59 => { // LD E, C
-                self.ld_r_r(&Register8::E, &Register8::C);
-                return 1
-            }
-            0x5A => { // LD E, D
-                self.ld_r_r(&Register8::E, &Register8::D);
-                return 1
-            }
-            0x5B => { // LD E, E
-                self.ld_r_r(&Register8::E, &Register8::E);
-                return 1
-            }
-            0x5C => { // LD E, H
-                self.ld_r_r(&Register8::E, &Register8::H);
-                return 1
-            }
-            0x5D => { // LD E, L
-                self.ld_r_r(&Register8::E, &Register8::L);
-                return 1
-            }
-            0x5E => { // LD E, (HL)
-                self.ld_r_hl(mem, &Register8::E);
-                return 2
-            }
-            0x5F => { // LD E, A
-                self.ld_r_r(&Register8::E, &Register8::A);
-                return 1
-            }
-            0x60 => { // LD H, B
-                self.ld_r_r(&Register8::H, &Register8::B);
-                return 1
-            }
-            0x61 => { // LD H, C
-                self.ld_r_r(&Register8::H, &Register8::C);
-                return 1
-            }
-            0x62 => { // LD H, D
-                self.ld_r_r(&Register8::H, &Register8::D);
-                return 1
-            }
-            0x63 => { // LD H, E
-                self.ld_r_r(&Register8::H, &Register8::E);
-                return 1
-            }
-            0x64 => { // LD H, H
-                self.ld_r_r(&Register8::H, &Register8::H);
-                return 1
-            }
-            0x65 => { // LD H, L
-                self.ld_r_r(&Register8::H, &Register8::L);
-                return 1
-            }
-            0x66 => { // LD H, (HL)
-                self.ld_r_hl(mem, &Register8::H);
-                return 2
-            }
-            0x67 => { // LD H, A
-                self.ld_r_r(&Register8::H, &Register8::A);
-                return 1
-            }
-            0x68 => { // LD L, B
-                self.ld_r_r(&Register8::L, &Register8::B);
-                return 1
-            }
-            0x69 => { // LD L, C
-                self.ld_r_r(&Register8::L, &Register8::C);
-                return 1
-            }
-            0x6A => { // LD L, D
-                self.ld_r_r(&Register8::L, &Register8::D);
-                return 1
-            }
-            0x6B => { // LD L, E
-                self.ld_r_r(&Register8::L, &Register8::E);
-                return 1
-            }
-            0x6C => { // LD L, H
-                self.ld_r_r(&Register8::L, &Register8::H);
-                return 1
-            }
-            0x6D => { // LD L, L
-                self.ld_r_r(&Register8::L, &Register8::L);
-                return 1
-            }
-            0x6E => { // LD L, (HL)
-                self.ld_r_hl(mem, &Register8::L);
-                return 2
-            }
-            0x6F => { // LD L, A
-                self.ld_r_r(&Register8::L, &Register8::A);
-                return 1
-            }
-            0x70 => { // LD (HL), B
-                self.ld_hl_r(mem, &Register8::B);
-                return 2
-            }
-            0x71 => { // LD (HL), C
-                self.ld_hl_r(mem, &Register8::C);
-                return 2
-            }
-            0x72 => { // LD (HL), D
-                self.ld_hl_r(mem, &Register8::D);
-                return 2
-            }
-            0x73 => { // LD (HL), E
-                self.ld_hl_r(mem, &Register8::E);
-                return 2
-            }
-            0x74 => { // LD (HL), H
-                self.ld_hl_r(mem, &Register8::H);
-                return 2
-            }
-            0x75 => { // LD (HL), L
-                self.ld_hl_r(mem, &Register8::L);
-                return 2
-            }
-            // TODO 0x76 HALT
-            0x76 => {
-                self.halt = true;
-                return 1
-            }
-            0x77 => { // LD (HL), A
-                self.ld_hl_r(mem, &Register8::A);
-                return 2
-            }
-            0x78 => { // LD A, B
-                self.ld_r_r(&Register8::A, &Register8::B);
-                return 1
-            }
-            0x79 => { // LD A, C
-                self.ld_r_r(&Register8::A, &Register8::C);
-                return 1
-            }
-            0x7A => { // LD A, D
-                self.ld_r_r(&Register8::A, &Register8::D);
-                return 1
-            }
-            0x7B => { // LD A, E
-                self.ld_r_r(&Register8::A, &Register8::E);
-                return 1
-            }
-            0x7C => { // LD A, H
-                self.ld_r_r(&Register8::A, &Register8::H);
-                return 1
-            }
-            0x7D => { // LD A, L
-                self.ld_r_r(&Register8::A, &Register8::L);
-                return 1
-            }
-            0x7E => { // LD A, (HL)
-                self.ld_r_hl(mem, &Register8::A);
-                return 2
-            }
-            0x7F => { // LD A, A
-                self.ld_r_r(&Register8::A, &Register8::A);
-                return 1
-            }
-            0x80 => { // ADD A, B
-                self.add_to_a(self.get_register_8(&Register8::B));
-                return 1
-            }
-            0x81 => { // ADD A, C
-                self.add_to_a(self.get_register_8(&Register8::C));
-                return 1
-            }
-            0x82 => { // ADD A, D
-                self.add_to_a(self.get_register_8(&Register8::D));
-                return 1
-            }
-            0x83 => { // ADD A, E
-                self.add_to_a(self.get_register_8(&Register8::E));
-                return 1
-            }
-            0x84 => { // ADD A, H
-                self.add_to_a(self.get_register_8(&Register8::H));
-                return 1
-            }
-            0x85 => { // ADD A, L
-                self.add_to_a(self.get_register_8(&Register8::L));
-                return 1
-            }
-            0x86 => { // ADD A, (HL)
-                let val = mem.read(self.get_register_16(&Register16::HL));
-                self.add_to_a(val);
-                return 2
-            }
-            0x87 => { // ADD A, A
-                self.add_to_a(self.get_register_8(&Register8::A));
-                return 1
-            }
-            0x88 => { // ADC A, B
-                self.adc_to_a(self.get_register_8(&Register8::B));
-                return 1
-            }
-            0x89 => { // ADC A, C
-                self.adc_to_a(self.get_register_8(&Register8::C));
-                return 1
-            }
-            0x8A => { // ADC A, D
-                self.adc_to_a(self.get_register_8(&Register8::D));
-                return 1
-            }
-            0x8B => { // ADC A, E
-                self.adc_to_a(self.get_register_8(&Register8::E));
-                return 1
-            }
-            0x8C => { // ADC A, H
-                self.adc_to_a(self.get_register_8(&Register8::H));
-                return 1
-            }
-            0x8D => { // ADC A, L
-                self.adc_to_a(self.get_register_8(&Register8::L));
-                return 1
-            }
-            0x8E => { // ADC A, (HL)
-                let val = mem.read(self.get_register_16(&Register16::HL));
-                self.adc_to_a(val);
-                return 2
-            }
-            0x8F => { // ADC A, A
-                self.adc_to_a(self.get_register_8(&Register8::A));
-                return 1
-            }
-            0x90 => { // SUB A, B
-                self.sub_from_a(self.get_register_8(&Register8::B));
-                return 1
-            }
-            0x91 => { // SUB A, C
-                self.sub_from_a(self.get_register_8(&Register8::C));
-                return 1
-            }
-            0x92 => { // SUB A, D
-                self.sub_from_a(self.get_register_8(&Register8::D));
-                return 1
-            }
-            0x93 => { // SUB A, E
-                self.sub_from_a(self.get_register_8(&Register8::E));
-                return 1
-            }
-            0x94 => { // SUB A, H
-                self.sub_from_a(self.get_register_8(&Register8::H));
-                return 1
-            }
-            0x95 => { // SUB A, L
-                self.sub_from_a(self.get_register_8(&Register8::L));
-                return 1
-            }
-            0x96 => { // SUB A, (HL)
-                let val = mem.read(self.get_register_16(&Register16::HL));
-                self.sub_from_a(val);
-                return 2
-            }
-            0x97 => { // SUB A, A
-                self.sub_from_a(self.get_register_8(&Register8::A));
-                return 1
-            }
-            0x98 => { // SBC A, B
-                self.sbc_from_a(self.get_register_8(&Register8::B));
-                return 1
-            }
-            0x99 => { // SBC A, C
-                self.sbc_from_a(self.get_register_8(&Register8::C));
-                return 1
-            }
-            0x9A => { // SBC A, D
-                self.sbc_from_a(self.get_register_8(&Register8::D));
-                return 1
-            }
-            0x9B => { // SBC A, E
-                self.sbc_from_a(self.get_register_8(&Register8::E));
-                return 1
-            }
-            0x9C => { // SBC A, H
-                self.sbc_from_a(self.get_register_8(&Register8::H));
-                return 1
-            }
-            0x9D => { // SBC A, L
-                self.sbc_from_a(self.get_register_8(&Register8::L));
-                return 1
-            }
-            0x9E => { // SBC A, (HL)
-                let val = mem.read(self.get_register_16(&Register16::HL));
-                self.sbc_from_a(val);
-                return 2
-            }
-            0x9F => { // SBC A, A
-                self.sbc_from_a(self.get_register_8(&Register8::A));
-                return 1
-            }
-            0xA0 => { // AND B
-                self.and_a(self.get_register_8(&Register8::B));
-                return 1
-            }
-            0xA1 => { // AND C
-                self.and_a(self.get_register_8(&Register8::C));
-                return 1
-            }
-            0xA2 => { // AND D
-                self.and_a(self.get_register_8(&Register8::D));
-                return 1
-            }
-            0xA3 => { // AND E
-                self.and_a(self.get_register_8(&Register8::E));
-                return 1
-            }
-            0xA4 => { // AND H
-                self.and_a(self.get_register_8(&Register8::H));
-                return 1
-            }
-            0xA5 => { // AND L
-                self.and_a(self.get_register_8(&Register8::L));
-                return 1
-            }
-            0xA6 => { // AND (HL)
-                let val = mem.read(self.get_register_16(&Register16::HL));
-                self.and_a(val);
-                return 2
-            }
-            0xA7 => { // AND A
-                self.and_a(self.get_register_8(&Register8::A));
-                return 1
-            }
-            0xA8 => { // XOR B
-                self.xor_a(self.get_register_8(&Register8::B));
-                return 1
-            }
-            0xA9 => { // XOR C
-                self.xor_a(self.get_register_8(&Register8::C));
-                return 1
-            }
-            0xAA => { // XOR D
-                self.xor_a(self.get_register_8(&Register8::D));
-                return 1
-            }
-            0xAB => { // XOR E
-                self.xor_a(self.get_register_8(&Register8::E));
-                return 1
-            }
-            0xAC => { // XOR H
-                self.xor_a(self.get_register_8(&Register8::H));
-                return 1
-            }
-            0xAD => { // XOR L
-                self.xor_a(self.get_register_8(&Register8::L));
-                return 1
-            }
-            0xAE => { // XOR (HL)
-                let val = mem.read(self.get_register_16(&Register16::HL));
-                self.xor_a(val);
-                return 2
-            }
-            0xAF => { // XOR A
-                self.xor_a(self.get_register_8(&Register8::A));
-                return 1
-            }
-            0xB0 => { // OR B
-                self.or_a(self.get_register_8(&Register8::B));
-                return 1
-            }
-            0xB1 => { // OR C
-                self.or_a(self.get_register_8(&Register8::C));
-                return 1
-            }
-            0xB2 => { // OR D
-                self.or_a(self.get_register_8(&Register8::D));
-                return 1
-            }
-            0xB3 => { // OR E
-                self.or_a(self.get_register_8(&Register8::E));
-                return 1
-            }
-            0xB4 => { // OR H
-                self.or_a(self.get_register_8(&Register8::H));
-                return 1
-            }
-            0xB5 => { // OR L
-                self.or_a(self.get_register_8(&Register8::L));
-                return 1
-            }
-            0xB6 => { // OR (HL)
-                let val = mem.read(self.get_register_16(&Register16::HL));
-                self.or_a(val);
-                return 2
-            }
-            0xB7 => { // OR A
-                self.or_a(self.get_register_8(&Register8::A));
-                return 1
-            }
-            0xB8 => { // CP B
-                self.cp(self.get_register_8(&Register8::A), self.get_register_8(&Register8::B));
-                return 1
-            }
-            0xB9 => { // CP C
-                self.cp(self.get_register_8(&Register8::A), self.get_register_8(&Register8::C));
-                return 1
-            }
-            0xBA => { // CP D
-                self.cp(self.get_register_8(&Register8::A), self.get_register_8(&Register8::D));
-                return 1
-            }
-            0xBB => { // CP E
-                self.cp(self.get_register_8(&Register8::A), self.get_register_8(&Register8::E));
-                return 1
-            }
-            0xBC => { // CP H
-                self.cp(self.get_register_8(&Register8::A), self.get_register_8(&Register8::H));
-                return 1
-            }
-            0xBD => { // CP L
-                self.cp(self.get_register_8(&Register8::A), self.get_register_8(&Register8::L));
-                return 1
-            }
-            0xBE => { // CP (HL)
-                let val = mem.read(self.get_register_16(&Register16::HL));
-                self.cp(self.get_register_8(&Register8::A), val);
-                return 2
-            }
-            0xBF => { // CP A
-                self.cp(self.get_register_8(&Register8::A), self.get_register_8(&Register8::A));
-                return 1
-            }
-            0xC0 => { // RET NZ
-                if self.flags.z == 0 {
-                    let value = self.pop(mem);
-                    self.pc = value;
-                    return 5
-                }
-                return 2
-            }
-            0xC1 => { // POP BC
-                let value = self.pop(mem);
-                self.set_register_16(&Register16::BC, value);
-                return 3
-            }
-            0xC2 => { // JP NZ, a16
-                if self.flags.z == 0 {
-                    let a16 = mem.read_16(self.pc);
-                    self.pc = a16;
-                    return 4
-                } else {
-                    self.pc += 2;
-                    return 3
-                }
-            }
-            0xC3 => { // JP a16
-                let a16 = mem.read_16(self.pc);
-                self.pc = a16;
-                return 4
-            }
-            0xC4 => { // CALL NZ, a16
-                if self.flags.z == 0 {
-                    let next = self.pc + 2;
-                    self.push(mem, next);
-                    self.pc = mem.read_16(self.pc);
-                    return 6
-                } else {
-                    self.pc += 2;
-                    return 3
-                }
-            }
-            0xC5 => { // PUSH BC
-                let val = self.get_register_16(&Register16::BC);
-                self.push(mem, val);
-                return 4
-            }
-            0xC6 => { // ADD A, d8
-                let val = mem.read(self.get_register_16(&Register16::PC));
-                self.pc += 1;
-                self.add_to_a(val);
-                return 2
-            }
-            0xC7 => { // RST 0
-                self.push(mem, self.get_register_16(&Register16::PC));
-                self.set_register_16(&Register16::PC, 0x00);
-                return 4
-            }
-            0xC8 => { // RET Z
-                if self.flags.z == 1 {
-                    let value = self.pop(mem);
-                    self.pc = value;
-                    return 5
-                }
-                return 2
-            }
-            0xC9 => { // RET
-                let value = self.pop(mem);
-                self.pc = value;
-                return 4
-            }
-            0xCA => { // JP Z, a16
-                if self.flags.z == 1 {
-                    let a16 = mem.read_16(self.pc);
-                    self.pc = a16;
-                    return 4
-                } else {
-                    self.pc += 2;
-                    return 3
-                }
-            }
-            0xCB => { // 16-bit opcodes
-                return self.op_16(mem)
-            }
-            0xCC => { // CALL Z, a16
-                if self.flags.z == 1 {
-                    let next = self.pc + 2;
-                    self.push(mem, next);
-                    self.pc = mem.read_16(self.pc);
-                    return 6
-                } else {
-                    self.pc += 2;
-                    return 3
-                }
-            }
-            0xCD => { // CALL a16
-                let next = self.pc + 2;
-                self.push(mem, next);
-                let val = mem.read_16(self.pc);
-                self.pc = val;
-                return 6
-            }
-            0xCE => { // ADC A, d8
-                let val = mem.read(self.pc);
-                self.pc += 1;
-                self.adc_to_a(val);
-                return 2
-            }
-            0xCF => { // RST 1
-                self.push(mem, self.get_register_16(&Register16::PC));
-                self.set_register_16(&Register16::PC, 0x08);
-                return 4
-            }
-            0xD0 => { // RET NC
-                if self.flags.cy == 0 {
-                    let value = self.pop(mem);
-                    self.pc = value;
-                    return 5
-                }
-                return 2
-            }
-            0xD1 => { // POP DE
-                let value = self.pop(mem);
-                self.set_register_16(&Register16::DE, value);
-                return 3
-            }
-            0xD2 => { // JP NC, a16
-                if self.flags.cy == 0 {
-                    let a16 = mem.read_16(self.pc);
-                    self.pc = a16;
-                    return 4
-                } else {
-                    self.pc += 2;
-                    return 3
-                }
-            }
-            0xD4 => { // CALL NC, a16
-                if self.flags.cy == 0 {
-                    let next = self.pc + 2;
-                    self.push(mem, next);
-                    self.pc = mem.read_16(self.pc);
-                    return 6
-                } else {
-                    self.pc += 2;
-                    return 3
-                }
-            }
-            0xD5 => { // PUSH DE
-                let val = self.get_register_16(&Register16::DE);
-                self.push(mem, val);
-                return 4
-            }
-            0xD6 => { // SUB d8
-                let val = mem.read(self.get_register_16(&Register16::PC));
-                self.pc += 1;
-                self.sub_from_a(val);
-                return 2
-            }
-            0xD7 => { // RST 2
-                self.push(mem, self.get_register_16(&Register16::PC));
-                self.set_register_16(&Register16::PC, 0x10);
-                return 4
-            }
-            0xD8 => { // RET C
-                if self.flags.cy == 1 {
-                    let value = self.pop(mem);
-                    self.pc = value;
-                    return 5
-                }
-                return 2
-            }
-            0xD9 => { // RETI
-                self.set_interrupt(true);
-                let value = self.pop(mem);
-                self.pc = value;
-                return 4
-            }
-            0xDA => { // JP C, a16
-                if self.flags.cy == 1 {
-                    let a16 = mem.read_16(self.pc);
-                    self.pc = a16;
-                    return 4
-                } else {
-                    self.pc += 2;
-                    return 3
-                }
-            }
-            0xDC => { // CALL C, a16
-                if self.flags.cy == 1 {
-                    let next = self.pc + 2;
-                    self.push(mem, next);
-                    self.pc = mem.read_16(self.pc);
-                    return 6
-                } else {
-                    self.pc += 2;
-                    return 3
-                }
-            }
-            0xDE => { // SBC A, d8
-                let val = mem.read(self.pc);
-                self.pc += 1;
-                self.sbc_from_a(val);
-                return 2
-            }
-            0xDF => { // RST 3
-                self.push(mem, self.get_register_16(&Register16::PC));
-                self.set_register_16(&Register16::PC, 0x18);
-                return 4
-            }
-            0xE0 => { // LD (a8) A
-                let val = self.get_register_8(&Register8::A);
-                let addr = 0xff00 + (mem.read(self.pc) as u16);
-                self.pc += 1;
-                mem.write(addr, val);
-                return 3
-            }
-            0xE1 => { // POP HL
-                let value = self.pop(mem);
-                self.set_register_16(&Register16::HL, value);
-                return 3
-            }
-            0xE2 => { // LD (C), A
-                let val = self.get_register_8(&Register8::A);
-                let addr = 0xff00 + (self.get_register_8(&Register8::C) as u16);
-                mem.write(addr, val);
-                return 2
-            }
-            0xE5 => { // PUSH HL
-                let val = self.get_register_16(&Register16::HL);
-                self.push(mem, val);
-                return 4
-            }
-            0xE6 => { // AND d8
-                let val = mem.read(self.pc);
-                self.pc += 1;
-                self.and_a(val);
-                return 2
-            }
-            0xE7 => { // RST 4
-                self.push(mem, self.get_register_16(&Register16::PC));
-                self.set_register_16(&Register16::PC, 0x20);
-                return 4
-            }
-            0xE8 => { // ADD SP, s8
-                let val = mem.read(self.pc);
-                self.pc += 1;
-                let orig = self.get_register_16(&Register16::SP);
-                let sub = val & 0x80 != 0;
-                let abs = (if sub {!val + 1} else {val}) as u16;
-                let value = if sub {orig.wrapping_sub(abs)} else {orig.wrapping_add(abs)};
-                self.set_register_16(&Register16::SP, value);
-                self.flags.z = 0;
-                self.flags.n = 0;
-                if sub {
-                    let result = orig as i32 - abs as i32;
-                    let flag_xor = orig as i32 ^ (-(abs as i32)) ^ result;
-                    self.flags.h = if (flag_xor & 0x10) != 0 {1} else {0};
-                    self.flags.cy = if (flag_xor & 0x100) != 0 {1} else {0};
-                } else {
-                    let result = orig as i32 + abs as i32;
-                    let flag_xor = orig as i32 ^ (abs as i32) ^ result;
-                    self.flags.h = if (flag_xor & 0x10) != 0 {1} else {0};
-                    self.flags.cy = if (flag_xor & 0x100) != 0 {1} else {0};
-                }
-                return 4
-            }
-            0xE9 => { // JP HL
-                self.pc = self.get_register_16(&Register16::HL);
-                return 1
-            }
-            0xEA => { // LD (a16), A
-                let val = self.get_register_8(&Register8::A);
-                let addr = mem.read_16(self.pc);
-                self.pc += 2;
-                mem.write(addr, val);
-                return 4
-            }
-            0xEE => { // XOR d8
-                let val = mem.read(self.pc);
-                self.pc += 1;
-                self.xor_a(val);
-                return 2
-            }
-            0xEF => { // RST 5
-                self.push(mem, self.get_register_16(&Register16::PC));
-                self.set_register_16(&Register16::PC, 0x28);
-                return 4
-            }
-            0xF0 => { // LD A, (a8)
-                let a8 = 0xFF00 + (mem.read(self.pc) as u16);
-                self.pc += 1;
-                let val = mem.read(a8);
-                self.set_register_8(&Register8::A, val);
-                return 3
-            }
-            0xF1 => { // POP AF
-                let val = self.pop(mem);
-                let upper = (val >> 8) as u8;
-                self.set_register_8(&Register8::A, upper);
-                let lower = (val & 0xFF) as u8;
-                self.flags.z = lower>>7;
-                self.flags.n = (lower & 0b01000000) >> 6;
-                self.flags.h = (lower & 0b00100000) >> 5;
-                self.flags.cy = (lower & 0b00010000) >> 4;
-                //self.flags.lower = lower & 0x0F;
-                return 3
-            }
-            0xF2 => { // LD A, (C)
-                let addr = 0xFF00 + (self.get_register_8(&Register8::C) as u16);
-                let val = mem.read(addr);
-                self.set_register_8(&Register8::A, val);
-                return 2
-            }
-            0xF3 => { // DI
-                self.set_interrupt(false);
-                return 1
-            }
-            0xF5 => { // PUSH AF
-                let f = ((self.flags.z << 7) | (self.flags.n << 6) | (self.flags.h << 5) | (self.flags.cy << 4) | self.flags.lower) as u16;
-                let val = ((self.get_register_8(&Register8::A) as u16) << 8) | f;
-                self.push(mem, val);
-                return 4
-            }
-            0xF6 => { // OR d8
-                let val = mem.read(self.pc);
-                self.pc += 1;
-                self.or_a(val);
-                return 2
-            }
-            0xF7 => { // RST 6
-                self.push(mem, self.get_register_16(&Register16::PC));
-                self.set_register_16(&Register16::PC, 0x30);
-                return 4
-            }
-            0xF8 => { // LD HL, SP+s8
-                let orig = self.get_register_16(&Register16::SP);
-                let s8 = mem.read(self.pc);
-                self.pc += 1;
-                let sub = s8 & 0x80 != 0;
-                let abs = (if sub {!s8 + 1} else {s8}) as u16;
-                let value = if sub {orig.wrapping_sub(abs)} else {orig.wrapping_add(abs)};
-                self.set_register_16(&Register16::HL, value);
-                self.flags.z = 0;
-                self.flags.n = 0;
-                if sub {
-                    let result = orig as i32 - abs as i32;
-                    let flag_xor = orig as i32 ^ (-(abs as i32)) ^ result;
-                    self.flags.h = if (flag_xor & 0x10) != 0 {1} else {0};
-                    self.flags.cy = if (flag_xor & 0x100) != 0 {1} else {0};
-                } else {
-                    let result = orig as i32 + abs as i32;
-                    let flag_xor = orig as i32 ^ (abs as i32) ^ result;
-                    self.flags.h = if (flag_xor & 0x10) != 0 {1} else {0};
-                    self.flags.cy = if (flag_xor & 0x100) != 0 {1} else {0};
-                }
-                return 3
-            }
-            0xF9 => { // LD SP, HL
-                let val = self.get_register_16(&Register16::HL);
-                self.set_register_16(&Register16::SP, val);
-                return 2
-            }
-            0xFA => { // LD A, (a16)
-                let addr = mem.read_16(self.pc);
-                self.pc += 2;
-                let val = mem.read(addr);
-                self.set_register_8(&Register8::A, val);
-                return 4
-            }
-            0xFB => { // EI
-                //self.set_interrupt(true);
-                self.ie = true;
-                return 1
-            }
-            0xFE => { // CP d8
-                let val = mem.read(self.pc);
-                self.pc += 1;
-                self.cp(self.get_register_8(&Register8::A), val);
-                return 2
-            }
-            0xFF => { // RST 7
-                self.push(mem, self.get_register_16(&Register16::PC));
-                self.set_register_16(&Register16::PC, 0x38);
-                return 4
-            }
-
-            _ => {
-                console::log_1(&format!("Unsupported instruction: 0x{:02x} Address: {:#x}", instruction, self.pc - 1).into());
-                println!("Unsupported instruction: 0x{:02x}", instruction);
-                panic!("Unsupported instruction: 0x{:02x}", instruction);
+            1 => { // BIT y doesn't write back, so BIT y, (HL) is a M-cycle shorter
+                self.flags.z = if val & (1 << y) == 0 {1} else {0};
+                self.flags.n = 0;
+                self.flags.h = 1;
+                return if z == 6 {3} else {2}
             }
+            2 => self.set_r8(mem, z, val & !(1 << y)), // RES y
+            _ => self.set_r8(mem, z, val | (1 << y)), // SET y
         }
+        if z == 6 {4} else {2}
     }
+}
 
-    fn op_16(&mut self, mem: &mut Memory) -> u8{
-        let instruction = mem.read(self.pc);
-        self.pc = self.pc.wrapping_add(1);
-        match instruction {
-            0x00 => { // RLC B
-                self.rlc(&Register8::B, true);
-                return 2
-            }
-            0x01 => { // RLC C
-                self.rlc(&Register8::C, true);
-                return 2
-            }
-            0x02 => { // RLC D
-                self.rlc(&Register8::D, true);
-                return 2
-            }
-            0x03 => { // RLC E
-                self.rlc(&Register8::E, true);
-                return 2
-            }
-            0x04 => { // RLC H
-                self.rlc(&Register8::H, true);
-                return 2
-            }
-            0x05 => { // RLC L
-                self.rlc(&Register8::L, true);
-                return 2
-            }
-            0x06 => { // RLC (HL)
-                let addr = self.get_register_16(&Register16::HL);
-                let value = mem.read(addr).rotate_left(1);
-                mem.write(addr, value);
-                self.flags.cy = value & 0x01;
-                self.flags.h = 0;
-                self.flags.z = if value == 0 {1} else {0};
-                self.flags.n = 0;
-                return 4
-            }
-            0x07 => { // RLC A
-                self.rlc(&Register8::A, true);
-                return 2
-            }
-            0x08 => { // RRC B
-                self.rrc(&Register8::B, true);
-                return 2
-            }
-            0x09 => { // RRC C
-                self.rrc(&Register8::C, true);
-                return 2
-            }
-            0x0A => { // RRC D
-                self.rrc(&Register8::D, true);
-                return 2
-            }
-            0x0B => { // RRC E
-                self.rrc(&Register8::E, true);
-                return 2
-            }
-            0x0C => { // RRC H
-                self.rrc(&Register8::H, true);
-                return 2
-            }
-            0x0D => { // RRC L
-                self.rrc(&Register8::L, true);
-                return 2
-            }
-            0x0E => { // RRC (HL)
-                let addr = self.get_register_16(&Register16::HL);
-                let value = mem.read(addr).rotate_right(1);
-                mem.write(addr, value);
-                self.flags.cy = value>>7;
-                self.flags.h = 0;
-                self.flags.z = if value == 0 {1} else {0};
-                self.flags.n = 0;
-                return 4
-            }
-            0x0F => { // RRC A
-                self.rrc(&Register8::A, true);
-                return 2
-            }
-            0x10 => { // RL B
-                self.rl(&Register8::B, true);
-                return 2
-            }
-            0x11 => { // RL C
-                self.rl(&Register8::C, true);
-                return 2
-            }
-            0x12 => { // RL D
-                self.rl(&Register8::D, true);
-                return 2
-            }
-            0x13 => { // RL E
-                self.rl(&Register8::E, true);
-                return 2
-            }
-            0x14 => { // RL H
-                self.rl(&Register8::H, true);
-                return 2
-            }
-            0x15 => { // RL L
-                self.rl(&Register8::L, true);
-                return 2
-            }
-            0x16 => { // RL (HL)
-                let addr = self.get_register_16(&Register16::HL);
-                let orig = mem.read(addr);
-                let mut value = orig << 1;
-                value = value | self.flags.cy;
-                self.flags.cy = orig >> 7;
-                mem.write(addr, value);
-                self.flags.h = 0;
-                self.flags.z = if value == 0 {1} else {0};
-                self.flags.n = 0;
-                return 4
-            }
-            0x17 => { // RL A
-                self.rl(&Register8::A, true);
-                return 2
-            }
-            0x18 => { // RR B
-                self.rr(&Register8::B, true);
-                return 2
-            }
-            0x19 => { // RR C
-                self.rr(&Register8::C, true);
-                return 2
-            }
-            0x1A => { // RR D
-                self.rr(&Register8::D, true);
-                return 2
-            }
-            0x1B => { // RR E
-                self.rr(&Register8::E, true);
-                return 2
-            }
-            0x1C => { // RR H
-                self.rr(&Register8::H, true);
-                return 2
-            }
-            0x1D => { // RR L
-                self.rr(&Register8::L, true);
-                return 2
-            }
-            0x1E => { // RR (HL)
-                let addr = self.get_register_16(&Register16::HL);
-                let orig = mem.read(addr);
-                let mut value = orig >> 1;
-                value = (value & 0b01111111) | (self.flags.cy << 7);
-                self.flags.cy = orig & 0x01;
-                mem.write(addr, value);
-                self.flags.h = 0;
-                self.flags.z = if value == 0 {1} else {0};
-                self.flags.n = 0;
-                return 4
-            }
-            0x1F => { // RR A
-                self.rr(&Register8::A, true);
-                return 2
-            }
-            0x20 => { // SLA B
-                self.sl(&Register8::B);
-                return 2
-            }
-            0x21 => { // SLA C
-                self.sl(&Register8::C);
-                return 2
-            }
-            0x22 => { // SLA D
-                self.sl(&Register8::D);
-                return 2
-            }
-            0x23 => { // SLA E
-                self.sl(&Register8::E);
-                return 2
-            }
-            0x24 => { // SLA H
-                self.sl(&Register8::H);
-                return 2
-            }
-            0x25 => { // SLA L
-                self.sl(&Register8::L);
-                return 2
-            }
-            0x26 => { // SLA (HL)
-                let addr = self.get_register_16(&Register16::HL);
-                let orig = mem.read(addr);
-                let val = orig << 1;
-                mem.write(addr,val);
-                self.flags.cy = if (orig & 0x80) == 0x80 {1}else{0};
-                self.flags.z = if val == 0 {1} else {0};
-                self.flags.n = 0;
-                self.flags.h = 0;
-                return 4
-            }
-            0x27 => { // SLA A
-                self.sl(&Register8::A);
-                return 2
-            }
-            0x28 => { // SRA B
-                self.sra(&Register8::B);
-                return 2
-            }
-            0x29 => { // SRA C
-                self.sra(&Register8::C);
-                return 2
-            }
-            0x2A => { // SRA D
-                self.sra(&Register8::D);
-                return 2
-            }
-            0x2B => { // SRA E
-                self.sra(&Register8::E);
-                return 2
-            }
-            0x2C => { // SRA H
-                self.sra(&Register8::H);
-                return 2
-            }
-            0x2D => { // SRA L
-                self.sra(&Register8::L);
-                return 2
-            }
-            0x2E => { // SRA (HL)
-                let addr = self.get_register_16(&Register16::HL);
-                let orig = mem.read(addr);
-                let val = (orig >> 1) | (orig & 0x80);
-                mem.write(addr,val);
-                self.flags.cy = if (orig & 0x01) == 0x01 {1} else {0};
-                self.flags.z = if val == 0 {1} else {0};
-                self.flags.n = 0;
-                self.flags.h = 0;
-                return 4
-            }
-            0x2F => { // SRA A
-                self.sra(&Register8::A);
-                return 2
-            }
-            0x30 => { // SWAP B
-                self.swap(&Register8::B);
-                return 2
-            }
-            0x31 => { // SWAP C
-                self.swap(&Register8::C);
-                return 2
-            }
-            0x32 => { // SWAP D
-                self.swap(&Register8::D);
-                return 2
-            }
-            0x33 => { // SWAP E
-                self.swap(&Register8::E);
-                return 2
-            }
-            0x34 => { // SWAP H
-                self.swap(&Register8::H);
-                return 2
-            }
-            0x35 => { // SWAP L
-                self.swap(&Register8::L);
-                return 2
-            }
-            0x36 => { // SWAP (HL)
-                let addr = self.get_register_16(&Register16::HL);
-                let orig = mem.read(addr);
-                let value = (orig << 4) | (orig >> 4);
-                mem.write(addr,value);
-                self.flags.z = if value == 0 {1} else {0};
-                self.flags.cy = 0;
-                self.flags.n = 0;
-                self.flags.h = 0;
-                return 4
-            }
-            0x37 => { // SWAP A
-                self.swap(&Register8::A);
-                return 2
-            }
-            0x38 => { // SRL B
-                self.srl(&Register8::B);
-                return 2
-            }
-            0x39 => { // SRL C
-                self.srl(&Register8::C);
-                return 2
-            }
-            0x3A => { // SRL D
-                self.srl(&Register8::D);
-                return 2
-            }
-            0x3B => { // SRL E
-                self.srl(&Register8::E);
-                return 2
-            }
-            0x3C => { // SRL H
-                self.srl(&Register8::H);
-                return 2
-            }
-            0x3D => { // SRL L
-                self.srl(&Register8::L);
-                return 2
-            }
-            0x3E => { // SRL (HL)
-                let addr = self.get_register_16(&Register16::HL);
-                let orig = mem.read(addr);
-                let val = orig >> 1;
-                mem.write(addr,val);
-                self.flags.cy = if (orig & 0x01) == 0x01 {1} else {0};
-                self.flags.z = if val == 0 {1} else {0};
-                self.flags.n = 0;
-                self.flags.h = 0;
-                return 4
-            }
-            0x3F => { // SRL A
-                self.srl(&Register8::A);
-                return 2
-            }
-            0x40 | 0x48 | 0x50 | 0x58 | 0x60 | 0x68 | 0x70 | 0x78 => { // BIT B
-                self.bit(&Register8::B, (instruction - 0x40) / 0x08);
-                return 2
-            }
-            0x41 | 0x49 | 0x51 | 0x59 | 0x61 | 0x69 | 0x71 | 0x79 => { // BIT C
-                self.bit(&Register8::C, (instruction - 0x41) / 0x08);
-                return 2
-            }
-            0x42 | 0x4A | 0x52 | 0x5A | 0x62 | 0x6A | 0x72 | 0x7A => { // BIT D
-                self.bit(&Register8::D, (instruction - 0x42) / 0x08);
-                return 2
-            }
-            0x43 | 0x4B | 0x53 | 0x5B | 0x63 | 0x6B | 0x73 | 0x7B => { // BIT E
-                self.bit(&Register8::E, (instruction - 0x43) / 0x08);
-                return 2
-            }
-            0x44 | 0x4C | 0x54 | 0x5C | 0x64 | 0x6C | 0x74 | 0x7C => { // BIT H
-                self.bit(&Register8::H, (instruction - 0x44) / 0x08);
-                return 2
-            }
-            0x45 | 0x4D | 0x55 | 0x5D | 0x65 | 0x6D | 0x75 | 0x7D => { // BIT L
-                self.bit(&Register8::L, (instruction - 0x45) / 0x08);
-                return 2
-            }
-            0x46 | 0x4E | 0x56 | 0x5E | 0x66 | 0x6E | 0x76 | 0x7E => { // BIT (HL)
-                let bit = (instruction - 0x46) / 0x08;
-                let addr = self.get_register_16(&Register16::HL);
-                let val = mem.read(addr);
-                let test = 0x01 << bit;
-                let result = val & test;
-                self.flags.z = if result == 0 {1} else {0};
-                self.flags.n = 0;
-                self.flags.h = 1;
-                return 3
-            }
-            0x47 | 0x4F | 0x57 | 0x5F | 0x67 | 0x6F | 0x77 | 0x7F => { // BIT A
-                self.bit(&Register8::A, (instruction - 0x47) / 0x08);
-                return 2
-            }
-            0x80 | 0x88 | 0x90 | 0x98 | 0xA0 | 0xA8 | 0xB0 | 0xB8 => { // RES B
-                self.res(&Register8::B, (instruction - 0x80) / 0x08);
-                return 2
-            }
-            0x81 | 0x89 | 0x91 | 0x99 | 0xA1 | 0xA9 | 0xB1 | 0xB9 => { // RES C
-                self.res(&Register8::C, (instruction - 0x81) / 0x08);
-                return 2
-            }
-            0x82 | 0x8A | 0x92 | 0x9A | 0xA2 | 0xAA | 0xB2 | 0xBA => { // RES D
-                self.res(&Register8::D, (instruction - 0x82) / 0x08);
-                return 2
-            }
-            0x83 | 0x8B | 0x93 | 0x9B | 0xA3 | 0xAB | 0xB3 | 0xBB => { // RES E
-                self.res(&Register8::E, (instruction - 0x83) / 0x08);
-                return 2
-            }
-            0x84 | 0x8C | 0x94 | 0x9C | 0xA4 | 0xAC | 0xB4 | 0xBC => { // RES H
-                self.res(&Register8::H, (instruction - 0x84) / 0x08);
-                return 2
-            }
-            0x85 | 0x8D | 0x95 | 0x9D | 0xA5 | 0xAD | 0xB5 | 0xBD => { // RES L
-                self.res(&Register8::L, (instruction - 0x85) / 0x08);
-                return 2
-            }
-            0x86 | 0x8E | 0x96 | 0x9E | 0xA6 | 0xAE | 0xB6 | 0xBE => { // RES (HL)
-                let bit = (instruction - 0x86) / 0x08;
-                let addr = self.get_register_16(&Register16::HL);
-                let orig = mem.read(addr);
-                let val = orig & !(1 << bit);
-                mem.write(addr,val);
-                return 4
-            }
-            0x87 | 0x8F | 0x97 | 0x9F | 0xA7 | 0xAF | 0xB7 | 0xBF => { // RES A
-                self.res(&Register8::A, (instruction - 0x87) / 0x08);
-                return 2
-            }
-            0xC0 | 0xC8 | 0xD0 | 0xD8 | 0xE0 | 0xE8 | 0xF0 | 0xF8 => { // SET B
-                self.set(&Register8::B, (instruction - 0xC0) / 0x08);
-                return 2
-            }
-            0xC1 | 0xC9 | 0xD1 | 0xD9 | 0xE1 | 0xE9 | 0xF1 | 0xF9 => { // SET C
-                self.set(&Register8::C, (instruction - 0xC1) / 0x08);
-                return 2
-            }
-            0xC2 | 0xCA | 0xD2 | 0xDA | 0xE2 | 0xEA | 0xF2 | 0xFA => { // SET D
-                self.set(&Register8::D, (instruction - 0xC2) / 0x08);
-                return 2
-            }
-            0xC3 | 0xCB | 0xD3 | 0xDB | 0xE3 | 0xEB | 0xF3 | 0xFB => { // SET E
-                self.set(&Register8::E, (instruction - 0xC3) / 0x08);
-                return 2
-            }
-            0xC4 | 0xCC | 0xD4 | 0xDC | 0xE4 | 0xEC | 0xF4 | 0xFC => { // SET H
-                self.set(&Register8::H, (instruction - 0xC4) / 0x08);
-                return 2
-            }
-            0xC5 | 0xCD | 0xD5 | 0xDD | 0xE5 | 0xED | 0xF5 | 0xFD => { // SET L
-                self.set(&Register8::L, (instruction - 0xC5) / 0x08);
-                return 2
-            }
-            0xC6 | 0xCE | 0xD6 | 0xDE | 0xE6 | 0xEE | 0xF6 | 0xFE => { // SET (HL)
-                let bit = (instruction - 0xC6) / 0x08;
-                let addr = self.get_register_16(&Register16::HL);
-                let orig = mem.read(addr);
-                let val = orig | (1 << bit);
-                mem.write(addr,val);
-                return 4
-            }
-            0xC7 | 0xCF | 0xD7 | 0xDF | 0xE7 | 0xEF | 0xF7 | 0xFF => { // SET A
-                self.set(&Register8::A, (instruction - 0xC7) / 0x08);
-                return 2
-            }
-        }
-    }
+// Handlers for every opcode, indexed by the high and low nibble. Each one is execute_opcode specialised for
+// its opcode, so there is a single indirect call per instruction instead of matching on the opcode fields.
+// The operand helpers like r8 are marked inline so their matches fold away as well
+type Handler = fn(&mut CPU, &mut Memory) -> u8;
+
+fn opcode<const OPCODE: u8>(cpu: &mut CPU, mem: &mut Memory) -> u8 {
+    cpu.execute_opcode(mem, OPCODE)
+}
+
+fn cb_opcode<const OPCODE: u8>(cpu: &mut CPU, mem: &mut Memory) -> u8 {
+    cpu.execute_cb_opcode(mem, OPCODE)
+}
+
+macro_rules! handler_row {
+    ($handler:ident, $high:literal) => {
+        [
+            $handler::<{ $high }>, $handler::<{ $high + 0x1 }>, $handler::<{ $high + 0x2 }>, $handler::<{ $high + 0x3 }>,
+            $handler::<{ $high + 0x4 }>, $handler::<{ $high + 0x5 }>, $handler::<{ $high + 0x6 }>, $handler::<{ $high + 0x7 }>,
+            $handler::<{ $high + 0x8 }>, $handler::<{ $high + 0x9 }>, $handler::<{ $high + 0xA }>, $handler::<{ $high + 0xB }>,
+            $handler::<{ $high + 0xC }>, $handler::<{ $high + 0xD }>, $handler::<{ $high + 0xE }>, $handler::<{ $high + 0xF }>,
+        ]
+    };
+}
+
+macro_rules! handler_table {
+    ($handler:ident) => {
+        [
+            handler_row!($handler, 0x00), handler_row!($handler, 0x10), handler_row!($handler, 0x20), handler_row!($handler, 0x30),
+            handler_row!($handler, 0x40), handler_row!($handler, 0x50), handler_row!($handler, 0x60), handler_row!($handler, 0x70),
+            handler_row!($handler, 0x80), handler_row!($handler, 0x90), handler_row!($handler, 0xA0), handler_row!($handler, 0xB0),
+            handler_row!($handler, 0xC0), handler_row!($handler, 0xD0), handler_row!($handler, 0xE0), handler_row!($handler, 0xF0),
+        ]
+    };
 }
 
+static OPCODES: [[Handler; 16]; 16] = handler_table!(opcode);
+static CB_OPCODES: [[Handler; 16]; 16] = handler_table!(cb_opcode);
+
 #[derive(Debug)]
 struct Flags {
     z: u8,
@@ -2407,47 +1258,179 @@ enum Register16 {
     PC
 }
 
-#[repr(C)]
-union Register {
-    sub: SubRegister,
-    full: u16
+// A register pair like BC, stored as its two halves so it doesn't depend on the host's byte order
+#[derive(Copy, Clone, Default)]
+struct RegisterPair {
+    high: u8,
+    low: u8,
 }
 
-impl Register {
-    fn new() -> Register {
-        return Register{sub: SubRegister::new()}
+impl RegisterPair {
+    fn get(&self) -> u16 {
+        u16::from_be_bytes([self.high, self.low])
     }
-}
 
-#[derive(Copy, Clone)]
-struct SubRegister {
-    low: u8,
-    high: u8
-}
-
-impl SubRegister {
-    fn new() -> SubRegister {
-        return SubRegister{high: 0, low: 0}
+    fn set(&mut self, val: u16) {
+        let [high, low] = val.to_be_bytes();
+        self.high = high;
+        self.low = low;
     }
 }
 
 #[cfg(test)]
 mod cpu_tests {
-    use crate::cpu::{CPU, Register, Register16, Register8};
-    use crate::memory::Memory;
+    use crate::cpu::{CPU, EmulationFault, Register16, Register8, RegisterPair};
+    use crate::debugger::Register;
+    use crate::memory::{BusAccess, BusCycle, Memory};
 
     #[test]
-    fn register_union_works() {
-        let mut reg = Register::new();
-        reg.sub.low = 8;
-        unsafe {
-            assert_eq!(8, reg.full);
-        }
-        reg.full = 0x0100;
-        unsafe {
-            assert_eq!(0, reg.sub.low);
-            assert_eq!(1, reg.sub.high);
-        }
+    fn register_pairs_split_into_halves() {
+        let mut reg = RegisterPair { high: 0, low: 8 };
+        assert_eq!(8, reg.get());
+        reg.set(0x0100);
+        assert_eq!(0, reg.low);
+        assert_eq!(1, reg.high);
+    }
+
+    #[test]
+    fn registers_snapshot() {
+        let mut cpu = CPU::new();
+        cpu.simulate_bootloader();
+        let r = cpu.registers();
+        assert_eq!((r.af(), r.bc(), r.de(), r.hl()), (0x01B0, 0x0013, 0x00D8, 0x014D));
+        assert_eq!((r.sp, r.pc), (0xFFFE, 0x0100));
+        assert_eq!(r.get(Register::HL), 0x014D);
+        assert_eq!(r.get(Register::F), 0xB0);
+    }
+
+    #[test]
+    fn interrupts_dispatch_in_priority_order() {
+        let mut mem = Memory::new(None);
+        let mut cpu = CPU::new();
+        cpu.pc = 0x1234;
+        cpu.sp = 0xD000;
+        cpu.ime = true;
+        mem.write(0xFFFF, 0b11111);
+        mem.write(0xFF0F, 0b11100);
+
+        assert_eq!(cpu.run(&mut mem), 5);
+        assert_eq!(cpu.pc, 0x0050);
+        assert_eq!(mem.read(0xFF0F), 0b11000);
+        assert_eq!(mem.read_16(0xCFFE), 0x1234);
+        assert!(!cpu.ime);
+    }
+
+    #[test]
+    fn interrupt_push_into_ie_cancels_dispatch() {
+        let mut mem = Memory::new(None);
+        let mut cpu = CPU::new();
+        // The high byte of PC (0x00) is pushed to 0xFFFF and clears IE
+        cpu.pc = 0x0042;
+        cpu.sp = 0x0000;
+        cpu.ime = true;
+        mem.write(0xFFFF, 0b1);
+        mem.write(0xFF0F, 0b1);
+
+        assert_eq!(cpu.run(&mut mem), 5);
+        assert_eq!(cpu.pc, 0x0000);
+        assert_eq!(mem.read(0xFF0F), 0b1);
+        assert_eq!(mem.read(0xFFFE), 0x42);
+    }
+
+    #[test]
+    fn halt_wake_up_costs_a_cycle() {
+        let mut mem = Memory::new(None);
+        let mut cpu = CPU::new();
+        cpu.sp = 0xD000;
+        cpu.halt = true;
+        cpu.ime = true;
+        mem.write(0xFFFF, 0b1000);
+        assert_eq!(cpu.run(&mut mem), 1);
+
+        mem.write(0xFF0F, 0b1000);
+        assert_eq!(cpu.run(&mut mem), 6);
+        assert_eq!(cpu.pc, 0x0058);
+        assert!(!cpu.halt);
+    }
+
+    #[test]
+    fn call_traces_each_bus_cycle() {
+        let mut mem = Memory::new(None);
+        let mut cpu = CPU::new();
+        cpu.pc = 0xC000;
+        cpu.sp = 0xD000;
+        mem.write(0xC000, 0xCD); // CALL 0x1234
+        mem.write(0xC001, 0x34);
+        mem.write(0xC002, 0x12);
+
+        mem.start_trace();
+        assert_eq!(cpu.run(&mut mem), 6);
+        let cycle = |address, data, access| BusCycle { address, data, access };
+        assert_eq!(mem.take_trace(), vec![
+            cycle(0xC000, 0xCD, BusAccess::Read),
+            cycle(0xC001, 0x34, BusAccess::Read),
+            cycle(0xC002, 0x12, BusAccess::Read),
+            cycle(0xC002, 0x12, BusAccess::Idle),
+            cycle(0xCFFF, 0xC0, BusAccess::Write),
+            cycle(0xCFFE, 0x03, BusAccess::Write),
+        ]);
+        assert_eq!(cpu.pc, 0x1234);
+    }
+
+    #[test]
+    fn halt_bug_repeats_next_byte() {
+        let mut mem = Memory::new(None);
+        let mut cpu = CPU::new();
+        mem.write(0xFFFF, 0b1);
+        mem.write(0xFF0F, 0b1);
+        mem.write(0, 0x76); // HALT
+        mem.write(1, 0x04); // INC B
+        cpu.run(&mut mem);
+        assert!(!cpu.halt);
+        cpu.run(&mut mem);
+        cpu.run(&mut mem);
+        assert_eq!(cpu.get_register_8(&Register8::B), 2);
+        assert_eq!(cpu.pc, 2);
+    }
+
+    #[test]
+    fn stop_waits_for_joypad() {
+        let mut mem = Memory::new(None);
+        let mut cpu = CPU::new();
+        mem.write(0xFF04, 0x12);
+        mem.write(0xFF00, 0xFF);
+        mem.write(0, 0x10);
+        mem.write(1, 0x00);
+        mem.write(2, 0x04);
+        cpu.run(&mut mem);
+        assert_eq!(cpu.pc, 2);
+        assert_eq!(mem.read(0xFF04), 0);
+
+        cpu.run(&mut mem);
+        assert_eq!(cpu.pc, 2);
+        mem.write(0xFF00, 0xFE);
+        cpu.run(&mut mem);
+        assert_eq!(cpu.pc, 3);
+    }
+
+    #[test]
+    fn illegal_opcode_locks_cpu() {
+        let mut mem = Memory::new(None);
+        let mut cpu = CPU::new();
+        cpu.ime = true;
+        mem.write(0, 0x00);
+        mem.write(1, 0xDD);
+        cpu.run(&mut mem);
+        cpu.run(&mut mem);
+        assert!(cpu.locked());
+        assert_eq!(cpu.take_fault(), Some(EmulationFault{ pc: 1, opcode: 0xDD }));
+        assert_eq!(cpu.take_fault(), None);
+
+        // Interrupts don't wake a locked CPU
+        mem.write(0xFFFF, 0b1);
+        mem.write(0xFF0F, 0b1);
+        cpu.run(&mut mem);
+        assert_eq!(cpu.pc, 2);
     }
 
     #[test]
@@ -2459,16 +1442,14 @@ mod cpu_tests {
 
     #[test]
     fn cpu_inc_b() {
-        unsafe {
-            let mut mem = Memory::new(None);
-            let mut cpu = CPU::new();
-            cpu.bc.sub.high = 0xff;
-            mem.write(0, 0x04);
-            cpu.run(&mut mem);
+        let mut mem = Memory::new(None);
+        let mut cpu = CPU::new();
+        cpu.bc.high = 0xff;
+        mem.write(0, 0x04);
+        cpu.run(&mut mem);
 
-            assert_eq!(cpu.bc.sub.high, 0);
-            assert_eq!(cpu.flags.h, 1);
-        }
+        assert_eq!(cpu.bc.high, 0);
+        assert_eq!(cpu.flags.h, 1);
     }
 
     #[test]
@@ -2542,8 +1523,8 @@ mod cpu_tests {
             cpu.simulate_bootloader();
             mem.simulate_bootloader();
             cpu.set_register_16(&Register16::BC, 0xabcd);
-            mem.cart.data[cpu.get_register_16(&Register16::PC) as usize] = 0xC5;
-            mem.cart.data[cpu.get_register_16(&Register16::PC) as usize + 1] = 0xC1;
+            mem.write(cpu.get_register_16(&Register16::PC), 0xC5);
+            mem.write(cpu.get_register_16(&Register16::PC) + 1, 0xC1);
             cpu.run(&mut mem);
             cpu.set_register_16(&Register16::BC, 5);
             assert_eq!(cpu.get_register_16(&Register16::BC), 5);
@@ -2559,20 +1540,20 @@ mod cpu_tests {
         mem.simulate_bootloader();
 
         cpu.set_register_16(&Register16::BC, 0xabcd);
-        mem.cart.data[cpu.get_register_16(&Register16::PC) as usize] = 0xC5;
-        mem.cart.data[cpu.get_register_16(&Register16::PC) as usize + 1] = 0xF1;
+        mem.write(cpu.get_register_16(&Register16::PC), 0xC5);
+        mem.write(cpu.get_register_16(&Register16::PC) + 1, 0xF1);
         
-        mem.cart.data[cpu.get_register_16(&Register16::PC) as usize + 2] = 0xF5;
-        mem.cart.data[cpu.get_register_16(&Register16::PC) as usize + 3] = 0xC1;
+        mem.write(cpu.get_register_16(&Register16::PC) + 2, 0xF5);
+        mem.write(cpu.get_register_16(&Register16::PC) + 3, 0xC1);
         cpu.run(&mut mem); // Push BC
         cpu.run(&mut mem); // Pop AF
         println!("A: {:#x} F: {:#x}", cpu.get_register_8(&Register8::A), cpu.get_register_8(&Register8::F));
         assert_eq!(cpu.get_register_8(&Register8::A), 0xab);
-        assert_eq!(cpu.get_register_8(&Register8::F), 0xcd);
+        assert_eq!(cpu.get_register_8(&Register8::F), 0xc0); // The lower 4 bits of F are always 0
         cpu.set_register_8(&Register8::A, 0xac);
         cpu.run(&mut mem);
         cpu.run(&mut mem);
-        assert_eq!(cpu.get_register_16(&Register16::BC), 0xaccd);
+        assert_eq!(cpu.get_register_16(&Register16::BC), 0xacc0);
         
     }
 
@@ -2583,17 +1564,17 @@ mod cpu_tests {
         cpu.simulate_bootloader();
         mem.simulate_bootloader();
 
-        mem.cart.data[cpu.get_register_16(&Register16::PC) as usize] = 0x01; // LD BC 0x1200
-        mem.cart.data[cpu.get_register_16(&Register16::PC) as usize + 1] = 0x00;
-        mem.cart.data[cpu.get_register_16(&Register16::PC) as usize + 2] = 0x12;
-        mem.cart.data[cpu.get_register_16(&Register16::PC) as usize + 3] = 0xc5; // PUSH BC
-        mem.cart.data[cpu.get_register_16(&Register16::PC) as usize + 4] = 0xf1; // POP AF
-        mem.cart.data[cpu.get_register_16(&Register16::PC) as usize + 5] = 0xf5; // PUSH AF
-        mem.cart.data[cpu.get_register_16(&Register16::PC) as usize + 6] = 0xd1; // POP DE
-        mem.cart.data[cpu.get_register_16(&Register16::PC) as usize + 7] = 0x79; // LD AC
-        mem.cart.data[cpu.get_register_16(&Register16::PC) as usize + 8] = 0xe6; // AND 0xF0
-        mem.cart.data[cpu.get_register_16(&Register16::PC) as usize + 9] = 0xf0;
-        mem.cart.data[cpu.get_register_16(&Register16::PC) as usize + 10] = 0xbb; // CP E
+        mem.write(cpu.get_register_16(&Register16::PC), 0x01); // LD BC 0x1200
+        mem.write(cpu.get_register_16(&Register16::PC) + 1, 0x00);
+        mem.write(cpu.get_register_16(&Register16::PC) + 2, 0x12);
+        mem.write(cpu.get_register_16(&Register16::PC) + 3, 0xc5); // PUSH BC
+        mem.write(cpu.get_register_16(&Register16::PC) + 4, 0xf1); // POP AF
+        mem.write(cpu.get_register_16(&Register16::PC) + 5, 0xf5); // PUSH AF
+        mem.write(cpu.get_register_16(&Register16::PC) + 6, 0xd1); // POP DE
+        mem.write(cpu.get_register_16(&Register16::PC) + 7, 0x79); // LD AC
+        mem.write(cpu.get_register_16(&Register16::PC) + 8, 0xe6); // AND 0xF0
+        mem.write(cpu.get_register_16(&Register16::PC) + 9, 0xf0);
+        mem.write(cpu.get_register_16(&Register16::PC) + 10, 0xbb); // CP E
 
         cpu.run(&mut mem);
         cpu.run(&mut mem);
@@ -0,0 +1,400 @@
+// Breakpoints, watchpoints and stepping around GameBoy::run.
+// Breakpoints stop before the instruction at their address runs, watchpoints stop after the instruction
+// that made the access. Continuing from a break never stops on the same instruction again right away
+
+use std::{error::Error, fmt, str::FromStr};
+
+use wasm_bindgen::prelude::*;
+
+use crate::cpu::CPU;
+use crate::disasm;
+use crate::memory::Memory;
+
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BreakReason {
+    // The frame finished without anything stopping it
+    Frame,
+    Breakpoint,
+    Watchpoint,
+    // A step_into, step_over or step_out finished
+    Step,
+    // The CPU locked up on an illegal opcode
+    Fault,
+}
+
+// Where and why run stopped. id is the breakpoint or watchpoint that was hit, address, value and write
+// describe the access that hit a watchpoint
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Break {
+    pub reason: BreakReason,
+    pub pc: u16,
+    pub id: u32,
+    pub address: u16,
+    pub value: u8,
+    pub write: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Register {
+    A, F, B, C, D, E, H, L,
+    AF, BC, DE, HL, SP, PC,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Comparison {
+    Eq, Ne, Lt, Le, Gt, Ge,
+}
+
+// A register compared against a value, like "A == 0x42" or "HL >= $C000"
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Condition {
+    pub register: Register,
+    pub comparison: Comparison,
+    pub value: u16,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseConditionError(String);
+
+impl fmt::Display for ParseConditionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid breakpoint condition: {}", self.0)
+    }
+}
+
+impl Error for ParseConditionError {}
+
+impl FromStr for Condition {
+    type Err = ParseConditionError;
+
+    fn from_str(s: &str) -> Result<Condition, ParseConditionError> {
+        let error = |reason: &str| ParseConditionError(format!("{} in \"{}\"", reason, s));
+        let parts: Vec<&str> = s.split_whitespace().collect();
+        if parts.len() != 3 {
+            return Err(error("expected <register> <comparison> <value>"))
+        }
+
+        let register = match parts[0].to_uppercase().as_str() {
+            "A" => Register::A, "F" => Register::F, "B" => Register::B, "C" => Register::C,
+            "D" => Register::D, "E" => Register::E, "H" => Register::H, "L" => Register::L,
+            "AF" => Register::AF, "BC" => Register::BC, "DE" => Register::DE, "HL" => Register::HL,
+            "SP" => Register::SP, "PC" => Register::PC,
+            _ => return Err(error("unknown register"))
+        };
+        let comparison = match parts[1] {
+            "==" => Comparison::Eq, "!=" => Comparison::Ne,
+            "<" => Comparison::Lt, "<=" => Comparison::Le,
+            ">" => Comparison::Gt, ">=" => Comparison::Ge,
+            _ => return Err(error("unknown comparison"))
+        };
+        let value = parts[2];
+        let value = if let Some(hex) = value.strip_prefix("0x").or_else(|| value.strip_prefix('$')) {
+            u16::from_str_radix(hex, 16)
+        } else {
+            value.parse()
+        }.map_err(|_| error("bad value"))?;
+        Ok(Condition { register, comparison, value })
+    }
+}
+
+impl Condition {
+    fn holds(&self, cpu: &CPU) -> bool {
+        let actual = cpu.registers().get(self.register);
+        match self.comparison {
+            Comparison::Eq => actual == self.value,
+            Comparison::Ne => actual != self.value,
+            Comparison::Lt => actual < self.value,
+            Comparison::Le => actual <= self.value,
+            Comparison::Gt => actual > self.value,
+            Comparison::Ge => actual >= self.value,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Breakpoint {
+    pub id: u32,
+    pub address: u16,
+    // Only break while this ROM bank is mapped. Ignored outside the ROM
+    pub bank: Option<u16>,
+    pub condition: Option<Condition>,
+}
+
+// Breaks on CPU accesses to start..=end. Execute watchpoints break before an instruction in the range runs
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Watchpoint {
+    pub id: u32,
+    pub start: u16,
+    pub end: u16,
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+}
+
+impl Watchpoint {
+    pub fn contains(&self, address: u16) -> bool {
+        (self.start..=self.end).contains(&address)
+    }
+}
+
+// A read or write by the CPU that matched a watchpoint
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WatchHit {
+    pub id: u32,
+    pub address: u16,
+    pub value: u8,
+    pub write: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum StepMode {
+    Into,
+    // Until execution is back after the CALL or RST, with the stack no deeper than before
+    Over { address: u16, sp: u16 },
+    // Until a return leaves the stack shallower than before
+    Out { sp: u16 },
+}
+
+#[derive(Default)]
+pub struct Debugger {
+    breakpoints: Vec<Breakpoint>,
+    watchpoints: Vec<Watchpoint>,
+    next_id: u32,
+    step: Option<StepMode>,
+    // The opcode at PC before the step, for step out
+    opcode: u8,
+    // Set by a break so continuing doesn't stop on the same instruction again
+    resuming: bool,
+    last_break: Option<Break>,
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger::default()
+    }
+
+    // Whether the hooks have anything to do
+    pub fn active(&self) -> bool {
+        !self.breakpoints.is_empty() || !self.watchpoints.is_empty() || self.step.is_some()
+    }
+
+    pub fn add_breakpoint(&mut self, address: u16, bank: Option<u16>, condition: Option<Condition>) -> u32 {
+        let id = self.new_id();
+        self.breakpoints.push(Breakpoint { id, address, bank, condition });
+        id
+    }
+
+    // The read and write watchpoints have to be passed on to Memory, see GameBoy::add_watchpoint
+    pub fn add_watchpoint(&mut self, start: u16, end: u16, read: bool, write: bool, execute: bool) -> u32 {
+        let id = self.new_id();
+        self.watchpoints.push(Watchpoint { id, start: start.min(end), end: start.max(end), read, write, execute });
+        id
+    }
+
+    fn new_id(&mut self) -> u32 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    // Removes the breakpoint or watchpoint with this id, returns false if there was none
+    pub fn remove(&mut self, id: u32) -> bool {
+        let count = self.breakpoints.len() + self.watchpoints.len();
+        self.breakpoints.retain(|b| b.id != id);
+        self.watchpoints.retain(|w| w.id != id);
+        count != self.breakpoints.len() + self.watchpoints.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.breakpoints.clear();
+        self.watchpoints.clear();
+    }
+
+    pub fn breakpoints(&self) -> &[Breakpoint] {
+        &self.breakpoints
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    pub fn step_into(&mut self) {
+        self.step = Some(StepMode::Into);
+    }
+
+    // Runs a CALL or RST until it returns, anything else is a single step
+    pub fn step_over(&mut self, cpu: &CPU, mem: &Memory) {
+        let pc = cpu.pc();
+        let instruction = disasm::decode(mem, pc);
+        self.step = Some(match instruction.mnemonic().as_str() {
+            "CALL" | "RST" => StepMode::Over {
+                address: pc.wrapping_add(instruction.length as u16),
+                sp: cpu.registers().get(Register::SP),
+            },
+            _ => StepMode::Into
+        });
+    }
+
+    // Runs until the current function returns
+    pub fn step_out(&mut self, cpu: &CPU) {
+        self.step = Some(StepMode::Out { sp: cpu.registers().get(Register::SP) });
+    }
+
+    pub fn last_break(&self) -> Option<Break> {
+        self.last_break
+    }
+
+    // Called before each CPU::run. Checks breakpoints and execute watchpoints at PC
+    pub fn before_step(&mut self, cpu: &CPU, mem: &Memory) -> Option<BreakReason> {
+        let pc = cpu.pc();
+        self.opcode = mem.read(pc);
+        // While halted the CPU stays on the next instruction without running it
+        if std::mem::replace(&mut self.resuming, false) || cpu.halted() || cpu.locked() {
+            return None
+        }
+
+        let bank = mem.rom_bank(pc);
+        let breakpoint = self.breakpoints.iter().find(|b| {
+            b.address == pc
+                && (b.bank.is_none() || bank.is_none() || b.bank == bank)
+                && b.condition.is_none_or(|c| c.holds(cpu))
+        });
+        if let Some(b) = breakpoint {
+            let id = b.id;
+            return Some(self.hit(Break { reason: BreakReason::Breakpoint, pc, id, address: pc, value: self.opcode, write: false }))
+        }
+
+        let watchpoint = self.watchpoints.iter().find(|w| w.execute && w.contains(pc));
+        if let Some(w) = watchpoint {
+            let id = w.id;
+            return Some(self.hit(Break { reason: BreakReason::Watchpoint, pc, id, address: pc, value: self.opcode, write: false }))
+        }
+        None
+    }
+
+    // Called after each CPU::run. Checks read and write watchpoints and whether a step has finished
+    pub fn after_step(&mut self, cpu: &CPU, mem: &mut Memory) -> Option<BreakReason> {
+        let pc = cpu.pc();
+        if let Some(hit) = mem.take_watch_hit() {
+            return Some(self.hit(Break { reason: BreakReason::Watchpoint, pc, id: hit.id, address: hit.address, value: hit.value, write: hit.write }))
+        }
+
+        let sp = cpu.registers().get(Register::SP);
+        let done = match self.step? {
+            StepMode::Into => true,
+            StepMode::Over { address, sp: start } => pc == address && sp >= start,
+            // RET, RETI and the conditional returns
+            StepMode::Out { sp: start } => matches!(self.opcode, 0xC9 | 0xD9 | 0xC0 | 0xC8 | 0xD0 | 0xD8) && sp > start,
+        };
+        if done {
+            return Some(self.hit(Break { reason: BreakReason::Step, pc, id: 0, address: pc, value: 0, write: false }))
+        }
+        None
+    }
+
+    // Records a break, also used by GameBoy::run for faults
+    pub fn hit(&mut self, b: Break) -> BreakReason {
+        self.last_break = Some(b);
+        self.step = None;
+        self.resuming = true;
+        b.reason
+    }
+}
+
+#[cfg(test)]
+mod debugger_tests {
+    use crate::cpu::CPU;
+    use crate::debugger::{BreakReason, Comparison, Condition, Debugger, Register};
+    use crate::memory::Memory;
+    use crate::state::InitialState;
+
+    // At 0xC000: LD A, 3, then a loop of DEC A and JR NZ, then CALL 0xC100 and HALT.
+    // At 0xC100: LD (0xD000), A and RET
+    fn program() -> (CPU, Memory) {
+        let mut mem = Memory::new(None);
+        let code: [(u16, &[u8]); 2] = [
+            (0xC000, &[0x3E, 0x03, 0x3D, 0x20, 0xFD, 0xCD, 0x00, 0xC1, 0x76]),
+            (0xC100, &[0xEA, 0x00, 0xD0, 0xC9]),
+        ];
+        for (address, bytes) in code.iter() {
+            for (i, b) in bytes.iter().enumerate() {
+                mem.write(address + i as u16, *b);
+            }
+        }
+        let mut cpu = CPU::new();
+        cpu.load_state(&InitialState {
+            pc: 0xC000, sp: 0xDFFF, a: 0, b: 0, c: 0, d: 0, e: 0, f: 0, h: 0, l: 0, ime: 0, ie: 0, ram: Vec::new()
+        });
+        (cpu, mem)
+    }
+
+    // Like GameBoy::run without the rest of the machine
+    fn run(debugger: &mut Debugger, cpu: &mut CPU, mem: &mut Memory) -> BreakReason {
+        for _ in 0..1000 {
+            if let Some(reason) = debugger.before_step(cpu, mem) {
+                return reason
+            }
+            cpu.run(mem);
+            if let Some(reason) = debugger.after_step(cpu, mem) {
+                return reason
+            }
+        }
+        BreakReason::Frame
+    }
+
+    #[test]
+    fn parses_conditions() {
+        let condition: Condition = "hl >= $C000".parse().unwrap();
+        assert_eq!(condition, Condition { register: Register::HL, comparison: Comparison::Ge, value: 0xC000 });
+        assert_eq!("A == 17".parse::<Condition>().unwrap().value, 17);
+        assert!("A = 1".parse::<Condition>().is_err());
+        assert!("IX == 1".parse::<Condition>().is_err());
+    }
+
+    #[test]
+    fn conditional_breakpoint() {
+        let (mut cpu, mut mem) = program();
+        let mut debugger = Debugger::new();
+        debugger.add_breakpoint(0xC002, None, Some("A == 1".parse().unwrap()));
+
+        assert_eq!(run(&mut debugger, &mut cpu, &mut mem), BreakReason::Breakpoint);
+        assert_eq!(cpu.registers().get(Register::A), 1);
+        assert_eq!(debugger.last_break().unwrap().pc, 0xC002);
+        // Continuing doesn't stop on the same instruction again
+        assert_eq!(run(&mut debugger, &mut cpu, &mut mem), BreakReason::Frame);
+    }
+
+    #[test]
+    fn write_watchpoint() {
+        let (mut cpu, mut mem) = program();
+        let mut debugger = Debugger::new();
+        let id = debugger.add_watchpoint(0xD000, 0xD0FF, false, true, false);
+        mem.set_watchpoints(debugger.watchpoints());
+
+        assert_eq!(run(&mut debugger, &mut cpu, &mut mem), BreakReason::Watchpoint);
+        let hit = debugger.last_break().unwrap();
+        assert_eq!((hit.id, hit.address, hit.value, hit.write), (id, 0xD000, 0, true));
+        assert_eq!(hit.pc, 0xC103);
+    }
+
+    #[test]
+    fn step_over_and_out() {
+        let (mut cpu, mut mem) = program();
+        let mut debugger = Debugger::new();
+        debugger.add_breakpoint(0xC005, None, None);
+        assert_eq!(run(&mut debugger, &mut cpu, &mut mem), BreakReason::Breakpoint);
+
+        debugger.step_over(&cpu, &mem);
+        assert_eq!(run(&mut debugger, &mut cpu, &mut mem), BreakReason::Step);
+        assert_eq!(cpu.pc(), 0xC008);
+
+        let (mut cpu, mut mem) = program();
+        debugger.clear();
+        debugger.add_breakpoint(0xC100, None, None);
+        assert_eq!(run(&mut debugger, &mut cpu, &mut mem), BreakReason::Breakpoint);
+        debugger.step_out(&cpu);
+        assert_eq!(run(&mut debugger, &mut cpu, &mut mem), BreakReason::Step);
+        assert_eq!(cpu.pc(), 0xC008);
+    }
+}
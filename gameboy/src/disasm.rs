@@ -0,0 +1,280 @@
+// SM83 disassembler. Opcodes are decoded from their bit fields like in the opcode grid
+//   xx yyy zzz, with y split into pp q
+// where x picks the block, z the operation within it and y (or p and q) the register or condition.
+// Timings are in M-cycles, the same unit CPU::run returns
+
+use std::fmt;
+
+use wasm_bindgen::prelude::*;
+
+use crate::memory::Memory;
+
+const R8: [&str; 8] = ["B", "C", "D", "E", "H", "L", "(HL)", "A"];
+// 16-bit registers in LD, INC, DEC and ADD
+const R16: [&str; 4] = ["BC", "DE", "HL", "SP"];
+// 16-bit registers in PUSH and POP
+const R16_STACK: [&str; 4] = ["BC", "DE", "HL", "AF"];
+const R16_MEM: [&str; 4] = ["(BC)", "(DE)", "(HL+)", "(HL-)"];
+const CONDITIONS: [&str; 4] = ["NZ", "Z", "NC", "C"];
+const ALU: [&str; 8] = ["ADD", "ADC", "SUB", "SBC", "AND", "XOR", "OR", "CP"];
+const ACCUMULATOR: [&str; 8] = ["RLCA", "RRCA", "RLA", "RRA", "DAA", "CPL", "SCF", "CCF"];
+const ROTATIONS: [&str; 8] = ["RLC", "RRC", "RL", "RR", "SLA", "SRA", "SWAP", "SRL"];
+
+#[wasm_bindgen]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instruction {
+    pub address: u16,
+    pub length: u8,
+    // M-cycles taken, for conditional instructions when the condition holds
+    pub cycles: u8,
+    // M-cycles when the condition of a conditional instruction fails, otherwise the same as cycles
+    pub cycles_not_taken: u8,
+    bytes: Vec<u8>,
+    mnemonic: &'static str,
+    operands: String,
+}
+
+#[wasm_bindgen]
+impl Instruction {
+    #[wasm_bindgen(getter)]
+    pub fn mnemonic(&self) -> String {
+        self.mnemonic.to_string()
+    }
+
+    // Operands separated by ", ". Relative jumps show the target address
+    #[wasm_bindgen(getter)]
+    pub fn operands(&self) -> String {
+        self.operands.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn bytes(&self) -> Vec<u8> {
+        self.bytes.clone()
+    }
+
+    pub fn text(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.operands.is_empty() {
+            write!(f, "{}", self.mnemonic)
+        } else {
+            write!(f, "{} {}", self.mnemonic, self.operands)
+        }
+    }
+}
+
+// Decodes the instruction at address. Memory is only read, so banked areas show what is currently mapped
+pub fn decode(mem: &Memory, address: u16) -> Instruction {
+    let opcode = mem.read(address);
+    let d8 = mem.read(address.wrapping_add(1));
+    let d16 = u16::from_le_bytes([d8, mem.read(address.wrapping_add(2))]);
+    // Target of a relative jump, counted from the end of the 2 byte instruction
+    let relative = address.wrapping_add(2).wrapping_add(d8 as i8 as u16);
+
+    let x = opcode >> 6;
+    let y = ((opcode >> 3) & 0x07) as usize;
+    let z = opcode & 0x07;
+    let p = y >> 1;
+    let q = y & 0x01;
+    let hl = y == 6;
+
+    let (mnemonic, operands, length, cycles, cycles_not_taken) = match (x, z) {
+        (0, 0) => match y {
+            0 => ("NOP", String::new(), 1, 1, 1),
+            1 => ("LD", format!("(${:04X}), SP", d16), 3, 5, 5),
+            2 => ("STOP", String::new(), 2, 1, 1),
+            3 => ("JR", format!("${:04X}", relative), 2, 3, 3),
+            _ => ("JR", format!("{}, ${:04X}", CONDITIONS[y - 4], relative), 2, 3, 2),
+        },
+        (0, 1) if q == 0 => ("LD", format!("{}, ${:04X}", R16[p], d16), 3, 3, 3),
+        (0, 1) => ("ADD", format!("HL, {}", R16[p]), 1, 2, 2),
+        (0, 2) if q == 0 => ("LD", format!("{}, A", R16_MEM[p]), 1, 2, 2),
+        (0, 2) => ("LD", format!("A, {}", R16_MEM[p]), 1, 2, 2),
+        (0, 3) => (if q == 0 { "INC" } else { "DEC" }, R16[p].to_string(), 1, 2, 2),
+        (0, 4) | (0, 5) => {
+            let mnemonic = if z == 4 { "INC" } else { "DEC" };
+            let cycles = if hl { 3 } else { 1 };
+            (mnemonic, R8[y].to_string(), 1, cycles, cycles)
+        },
+        (0, 6) => {
+            let cycles = if hl { 3 } else { 2 };
+            ("LD", format!("{}, ${:02X}", R8[y], d8), 2, cycles, cycles)
+        },
+        (0, _) => (ACCUMULATOR[y], String::new(), 1, 1, 1),
+        (1, 6) if hl => ("HALT", String::new(), 1, 1, 1),
+        (1, _) => {
+            let cycles = if hl || z == 6 { 2 } else { 1 };
+            ("LD", format!("{}, {}", R8[y], R8[z as usize]), 1, cycles, cycles)
+        },
+        (2, _) => {
+            let cycles = if z == 6 { 2 } else { 1 };
+            (ALU[y], alu_operands(y, R8[z as usize]), 1, cycles, cycles)
+        },
+        (_, 0) => match y {
+            0..=3 => ("RET", CONDITIONS[y].to_string(), 1, 5, 2),
+            4 => ("LDH", format!("(${:04X}), A", 0xFF00 | d8 as u16), 2, 3, 3),
+            5 => ("ADD", format!("SP, {}", d8 as i8), 2, 4, 4),
+            6 => ("LDH", format!("A, (${:04X})", 0xFF00 | d8 as u16), 2, 3, 3),
+            _ => ("LD", format!("HL, SP{:+}", d8 as i8), 2, 3, 3),
+        },
+        (_, 1) if q == 0 => ("POP", R16_STACK[p].to_string(), 1, 3, 3),
+        (_, 1) => match p {
+            0 => ("RET", String::new(), 1, 4, 4),
+            1 => ("RETI", String::new(), 1, 4, 4),
+            2 => ("JP", "HL".to_string(), 1, 1, 1),
+            _ => ("LD", "SP, HL".to_string(), 1, 2, 2),
+        },
+        (_, 2) => match y {
+            0..=3 => ("JP", format!("{}, ${:04X}", CONDITIONS[y], d16), 3, 4, 3),
+            4 => ("LD", "($FF00+C), A".to_string(), 1, 2, 2),
+            5 => ("LD", format!("(${:04X}), A", d16), 3, 4, 4),
+            6 => ("LD", "A, ($FF00+C)".to_string(), 1, 2, 2),
+            _ => ("LD", format!("A, (${:04X})", d16), 3, 4, 4),
+        },
+        (_, 3) => match y {
+            0 => ("JP", format!("${:04X}", d16), 3, 4, 4),
+            1 => return decode_cb(address, d8),
+            6 => ("DI", String::new(), 1, 1, 1),
+            7 => ("EI", String::new(), 1, 1, 1),
+            _ => illegal(opcode),
+        },
+        (_, 4) if y < 4 => ("CALL", format!("{}, ${:04X}", CONDITIONS[y], d16), 3, 6, 3),
+        (_, 5) if q == 0 => ("PUSH", R16_STACK[p].to_string(), 1, 4, 4),
+        (_, 5) if p == 0 => ("CALL", format!("${:04X}", d16), 3, 6, 6),
+        (_, 6) => (ALU[y], alu_operands(y, &format!("${:02X}", d8)), 2, 2, 2),
+        (_, 7) => ("RST", format!("${:02X}", y * 8), 1, 4, 4),
+        _ => illegal(opcode),
+    };
+
+    let bytes = (0..length).map(|i| mem.read(address.wrapping_add(i as u16))).collect();
+    Instruction { address, length, cycles, cycles_not_taken, bytes, mnemonic, operands }
+}
+
+fn decode_cb(address: u16, opcode: u8) -> Instruction {
+    let y = (opcode >> 3) & 0x07;
+    let z = (opcode & 0x07) as usize;
+    let hl = z == 6;
+    let (mnemonic, operands, cycles) = match opcode >> 6 {
+        0 => (ROTATIONS[y as usize], R8[z].to_string(), if hl { 4 } else { 2 }),
+        1 => ("BIT", format!("{}, {}", y, R8[z]), if hl { 3 } else { 2 }),
+        2 => ("RES", format!("{}, {}", y, R8[z]), if hl { 4 } else { 2 }),
+        _ => ("SET", format!("{}, {}", y, R8[z]), if hl { 4 } else { 2 }),
+    };
+    Instruction { address, length: 2, cycles, cycles_not_taken: cycles, bytes: vec![0xCB, opcode], mnemonic, operands }
+}
+
+// ADD, ADC and SBC name the accumulator, the others only their operand
+fn alu_operands(y: usize, operand: &str) -> String {
+    match y {
+        0 | 1 | 3 => format!("A, {}", operand),
+        _ => operand.to_string()
+    }
+}
+
+// Illegal opcodes lock up the CPU, they are shown as data
+fn illegal(opcode: u8) -> (&'static str, String, u8, u8, u8) {
+    ("DB", format!("${:02X}", opcode), 1, 1, 1)
+}
+
+// Decodes count instructions one after the other starting at address
+pub fn disassemble(mem: &Memory, address: u16, count: usize) -> Vec<Instruction> {
+    let mut instructions = Vec::with_capacity(count);
+    let mut address = address;
+    for _ in 0..count {
+        let instruction = decode(mem, address);
+        address = address.wrapping_add(instruction.length as u16);
+        instructions.push(instruction);
+    }
+    instructions
+}
+
+// Up to before instructions leading to pc, then after instructions starting at pc.
+// Decoding backwards is ambiguous, so this starts decoding a few bytes before pc and keeps the first
+// starting point that lands exactly on pc
+pub fn disassemble_around(mem: &Memory, pc: u16, before: usize, after: usize) -> Vec<Instruction> {
+    let mut leading = Vec::new();
+    for back in (1..=(before * 3) as u16).rev() {
+        let mut address = pc.wrapping_sub(back);
+        let mut candidates = Vec::new();
+        while pc.wrapping_sub(address) <= back && address != pc {
+            let instruction = decode(mem, address);
+            address = address.wrapping_add(instruction.length as u16);
+            candidates.push(instruction);
+        }
+        if address == pc && candidates.len() >= before {
+            leading = candidates.split_off(candidates.len() - before);
+            break
+        }
+    }
+    leading.extend(disassemble(mem, pc, after));
+    leading
+}
+
+#[cfg(test)]
+mod disasm_tests {
+    use crate::cpu::CPU;
+    use crate::disasm::{decode, disassemble_around};
+    use crate::memory::Memory;
+    use crate::state::InitialState;
+
+    fn memory_with(address: u16, bytes: &[u8]) -> Memory {
+        let mut mem = Memory::new(None);
+        for (i, b) in bytes.iter().enumerate() {
+            mem.write(address + i as u16, *b);
+        }
+        mem
+    }
+
+    #[test]
+    fn decodes_operands() {
+        let mem = memory_with(0xC000, &[
+            0x3E, 0x42,       // LD A, $42
+            0xEA, 0x00, 0xC1, // LD ($C100), A
+            0x20, 0xFA,       // JR NZ, $C001
+            0xCB, 0x7E,       // BIT 7, (HL)
+            0xF8, 0xFE,       // LD HL, SP-2
+            0xD3,             // Illegal
+        ]);
+        let text: Vec<String> = [0xC000, 0xC002, 0xC005, 0xC007, 0xC009, 0xC00B].iter()
+            .map(|a| decode(&mem, *a).to_string())
+            .collect();
+        assert_eq!(text, vec!["LD A, $42", "LD ($C100), A", "JR NZ, $C001", "BIT 7, (HL)", "LD HL, SP-2", "DB $D3"]);
+        assert_eq!(decode(&mem, 0xC002).bytes(), vec![0xEA, 0x00, 0xC1]);
+    }
+
+    // The timings should agree with what the CPU actually takes, both when a condition holds and when it fails
+    #[test]
+    fn cycles_match_cpu() {
+        for prefixed in [false, true].iter() {
+            for opcode in 0..=0xFFu8 {
+                let bytes = if *prefixed { vec![0xCB, opcode] } else { vec![opcode, 0x00, 0xC0] };
+                let instruction = decode(&memory_with(0x0100, &bytes), 0x0100);
+                let mut taken = Vec::new();
+                for flags in [0x00, 0xF0].iter() {
+                    let mut mem = memory_with(0x0100, &bytes);
+                    let mut cpu = CPU::new();
+                    cpu.load_state(&InitialState {
+                        pc: 0x0100, sp: 0xD000, a: 0, b: 0, c: 0, d: 0, e: 0, f: *flags, h: 0, l: 0, ime: 0, ie: 0, ram: Vec::new()
+                    });
+                    taken.push(cpu.run(&mut mem));
+                }
+                let mut expected = vec![instruction.cycles, instruction.cycles_not_taken];
+                expected.sort_unstable();
+                taken.sort_unstable();
+                assert_eq!(taken, expected, "{:02X} {}", opcode, instruction);
+            }
+        }
+    }
+
+    #[test]
+    fn finds_instructions_before_pc() {
+        let mem = memory_with(0xC000, &[0x00, 0x3E, 0x01, 0xC3, 0x00, 0xC0, 0x00]);
+        let around = disassemble_around(&mem, 0xC006, 2, 1);
+        let addresses: Vec<u16> = around.iter().map(|i| i.address).collect();
+        assert_eq!(addresses, vec![0xC001, 0xC003, 0xC006]);
+    }
+}
@@ -0,0 +1,399 @@
+// GDB remote serial protocol stub, native builds only. Behaviour source: the "Remote Protocol" appendix
+// of the GDB manual.
+// Registers are described by TARGET_XML since GDB has no SM83 target: the register pairs AF, BC, DE, HL,
+// then SP and PC, each 16 bits and little-endian in g and p packets.
+// Memory is accessed through Memory::read and write, so writes to 0x0000-0x7FFF reach the MBC registers.
+// Breakpoints (Z0, Z1) and watchpoints (Z2-Z4) go through the debugger. Ctrl-C stops a continue
+// between frames
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use crate::GameBoy;
+use crate::debugger::{BreakReason, Register};
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.gnu.gdb.sm83.core">
+    <reg name="af" bitsize="16" type="uint16" regnum="0"/>
+    <reg name="bc" bitsize="16" type="uint16"/>
+    <reg name="de" bitsize="16" type="uint16"/>
+    <reg name="hl" bitsize="16" type="uint16"/>
+    <reg name="sp" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+const REGISTERS: [Register; 6] = [Register::AF, Register::BC, Register::DE, Register::HL, Register::SP, Register::PC];
+
+// What the connection loop should do after a packet
+#[derive(Debug, PartialEq)]
+enum Action {
+    Reply(String),
+    Continue,
+    Step,
+    Detach,
+    // Closes the connection without a reply
+    Kill,
+}
+
+// Protocol state that outlives a single packet
+#[derive(Default)]
+pub struct GdbStub {
+    // GDB's breakpoint and watchpoint (type, address) mapped to debugger ids
+    points: HashMap<(u8, u16), u32>,
+}
+
+// Waits for GDB on 127.0.0.1:port and serves one session until it detaches or disconnects
+pub fn serve(gb: &mut GameBoy, port: u16) -> io::Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    let (stream, _) = listener.accept()?;
+    GdbStub::default().session(gb, stream)
+}
+
+impl GdbStub {
+    // Serves packets until GDB detaches or disconnects. Breakpoints set by GDB are removed afterwards,
+    // ones set through GameBoy stay
+    pub fn session(&mut self, gb: &mut GameBoy, stream: TcpStream) -> io::Result<()> {
+        let result = self.serve_packets(gb, stream);
+        for (_, id) in self.points.drain() {
+            gb.remove_breakpoint(id);
+        }
+        result
+    }
+
+    fn serve_packets(&mut self, gb: &mut GameBoy, mut stream: TcpStream) -> io::Result<()> {
+        stream.set_nodelay(true)?;
+        while let Some(packet) = read_packet(&mut stream)? {
+            let action = match packet {
+                Packet::Interrupt => Action::Reply("S02".to_string()),
+                Packet::Data(data) => self.command(gb, &data),
+            };
+            match action {
+                Action::Reply(reply) => write_packet(&mut stream, &reply)?,
+                Action::Step => {
+                    let reason = gb.step_into();
+                    write_packet(&mut stream, &stop_reply(gb, reason))?
+                },
+                Action::Continue => {
+                    let reply = continue_until_stopped(gb, &mut stream)?;
+                    write_packet(&mut stream, &reply)?
+                },
+                Action::Detach => {
+                    write_packet(&mut stream, "OK")?;
+                    break
+                },
+                Action::Kill => break
+            }
+        }
+        Ok(())
+    }
+
+    fn command(&mut self, gb: &mut GameBoy, packet: &str) -> Action {
+        let reply = |s: &str| Action::Reply(s.to_string());
+        let (command, args) = packet.split_at(packet.len().min(1));
+        match command {
+            "?" => reply("S05"),
+            "g" => Action::Reply(REGISTERS.iter().map(|r| hex_u16(gb.cpu.registers().get(*r))).collect()),
+            "G" => {
+                for (i, r) in REGISTERS.iter().enumerate() {
+                    match args.get(i * 4..i * 4 + 4).and_then(parse_u16_le) {
+                        Some(value) => gb.cpu.set_register(*r, value),
+                        None => return reply("E01")
+                    }
+                }
+                reply("OK")
+            },
+            "p" => match usize::from_str_radix(args, 16).ok().and_then(|n| REGISTERS.get(n)) {
+                Some(r) => Action::Reply(hex_u16(gb.cpu.registers().get(*r))),
+                None => reply("E01")
+            },
+            "P" => {
+                let (n, value) = args.split_once('=').unwrap_or(("", ""));
+                let register = usize::from_str_radix(n, 16).ok().and_then(|n| REGISTERS.get(n));
+                match (register, parse_u16_le(value)) {
+                    (Some(r), Some(value)) => {
+                        gb.cpu.set_register(*r, value);
+                        reply("OK")
+                    },
+                    _ => reply("E01")
+                }
+            },
+            "m" => match parse_range(args) {
+                Some((address, length)) => Action::Reply((0..length)
+                    .map(|i| format!("{:02x}", gb.mem.read(address.wrapping_add(i))))
+                    .collect()),
+                None => reply("E01")
+            },
+            "M" => {
+                let (range, data) = args.split_once(':').unwrap_or(("", ""));
+                match (parse_range(range), parse_bytes(data)) {
+                    (Some((address, length)), Some(bytes)) if bytes.len() == length as usize => {
+                        for (i, b) in bytes.iter().enumerate() {
+                            gb.mem.write(address.wrapping_add(i as u16), *b);
+                        }
+                        reply("OK")
+                    },
+                    _ => reply("E01")
+                }
+            },
+            "Z" | "z" => self.breakpoint(gb, command == "Z", args),
+            "c" => Action::Continue,
+            "s" => Action::Step,
+            "D" => Action::Detach,
+            "k" => Action::Kill,
+            "H" => reply("OK"),
+            "q" => query(args),
+            _ => reply("")
+        }
+    }
+
+    // Z<type>,<address>,<kind> inserts, z removes. Types 0 and 1 are breakpoints, 2 write, 3 read
+    // and 4 access watchpoints. Kind is the length for watchpoints
+    fn breakpoint(&mut self, gb: &mut GameBoy, insert: bool, args: &str) -> Action {
+        let parts: Vec<&str> = args.split(',').collect();
+        let (kind, address, length) = match parts.as_slice() {
+            [kind, address, length] => (
+                kind.parse::<u8>().ok(),
+                u16::from_str_radix(address, 16).ok(),
+                u16::from_str_radix(length, 16).ok()
+            ),
+            _ => (None, None, None)
+        };
+        let (kind, address, length) = match (kind, address, length) {
+            (Some(kind), Some(address), Some(length)) if kind <= 4 => (kind, address, length),
+            _ => return Action::Reply(String::new())
+        };
+
+        let key = (kind, address);
+        if !insert {
+            if let Some(id) = self.points.remove(&key) {
+                gb.remove_breakpoint(id);
+            }
+            return Action::Reply("OK".to_string())
+        }
+        if self.points.contains_key(&key) {
+            return Action::Reply("OK".to_string())
+        }
+        let id = match kind {
+            0 | 1 => gb.debugger.add_breakpoint(address, None, None),
+            _ => {
+                let end = address.wrapping_add(length.max(1) - 1);
+                gb.add_watchpoint(address, end, kind != 2, kind != 3, false)
+            }
+        };
+        self.points.insert(key, id);
+        Action::Reply("OK".to_string())
+    }
+}
+
+fn query(args: &str) -> Action {
+    let reply = |s: &str| Action::Reply(s.to_string());
+    if args.starts_with("Supported") {
+        return reply("PacketSize=4000;qXfer:features:read+")
+    }
+    if let Some(rest) = args.strip_prefix("Xfer:features:read:target.xml:") {
+        return match parse_range(rest) {
+            Some((offset, length)) => {
+                let start = (offset as usize).min(TARGET_XML.len());
+                let end = (start + length as usize).min(TARGET_XML.len());
+                let more = if end < TARGET_XML.len() { "m" } else { "l" };
+                Action::Reply(format!("{}{}", more, &TARGET_XML[start..end]))
+            },
+            None => reply("E01")
+        }
+    }
+    match args {
+        "Attached" => reply("1"),
+        "C" => reply("QC1"),
+        "fThreadInfo" => reply("m1"),
+        "sThreadInfo" => reply("l"),
+        _ => reply("")
+    }
+}
+
+// Runs frames until the debugger breaks or GDB sends Ctrl-C
+fn continue_until_stopped(gb: &mut GameBoy, stream: &mut TcpStream) -> io::Result<String> {
+    loop {
+        let reason = gb.run();
+        if reason != BreakReason::Frame {
+            return Ok(stop_reply(gb, reason))
+        }
+
+        stream.set_nonblocking(true)?;
+        let mut byte = [0u8];
+        let polled = stream.read(&mut byte);
+        stream.set_nonblocking(false)?;
+        match polled {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(_) if byte[0] == 0x03 => return Ok("S02".to_string()),
+            Ok(_) => {},
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {},
+            Err(e) => return Err(e)
+        }
+    }
+}
+
+// SIGTRAP for breakpoints and steps, with the address for watchpoints. SIGILL for illegal opcodes
+fn stop_reply(gb: &GameBoy, reason: BreakReason) -> String {
+    match (reason, gb.last_break()) {
+        (BreakReason::Fault, _) => "S04".to_string(),
+        (BreakReason::Watchpoint, Some(b)) if b.address != b.pc => {
+            let kind = if b.write { "watch" } else { "rwatch" };
+            format!("T05{}:{:x};", kind, b.address)
+        },
+        _ => "S05".to_string()
+    }
+}
+
+enum Packet {
+    Data(String),
+    Interrupt,
+}
+
+// Reads the next packet, acknowledging it. Returns None when GDB disconnects
+fn read_packet<S: Read + Write>(stream: &mut S) -> io::Result<Option<Packet>> {
+    let mut byte = [0u8];
+    loop {
+        if stream.read(&mut byte)? == 0 {
+            return Ok(None)
+        }
+        match byte[0] {
+            0x03 => return Ok(Some(Packet::Interrupt)),
+            b'$' => {},
+            // Acks for our replies and anything between packets
+            _ => continue
+        }
+
+        let mut data = Vec::new();
+        loop {
+            if stream.read(&mut byte)? == 0 {
+                return Ok(None)
+            }
+            if byte[0] == b'#' {
+                break
+            }
+            data.push(byte[0]);
+        }
+        let mut checksum = [0u8; 2];
+        stream.read_exact(&mut checksum)?;
+        let expected = std::str::from_utf8(&checksum).ok().and_then(|c| u8::from_str_radix(c, 16).ok());
+        if expected != Some(checksum_of(&data)) {
+            stream.write_all(b"-")?;
+            continue
+        }
+        stream.write_all(b"+")?;
+        return Ok(Some(Packet::Data(String::from_utf8_lossy(&data).into_owned())))
+    }
+}
+
+fn write_packet<S: Write>(stream: &mut S, data: &str) -> io::Result<()> {
+    write!(stream, "${}#{:02x}", data, checksum_of(data.as_bytes()))?;
+    stream.flush()
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+}
+
+fn hex_u16(value: u16) -> String {
+    let [low, high] = value.to_le_bytes();
+    format!("{:02x}{:02x}", low, high)
+}
+
+fn parse_u16_le(s: &str) -> Option<u16> {
+    match parse_bytes(s)?.as_slice() {
+        [low, high] => Some(u16::from_le_bytes([*low, *high])),
+        _ => None
+    }
+}
+
+fn parse_bytes(s: &str) -> Option<Vec<u8>> {
+    if s.len() & 1 != 0 {
+        return None
+    }
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok()).collect()
+}
+
+// "<address>,<length>" in hex
+fn parse_range(s: &str) -> Option<(u16, u16)> {
+    let (address, length) = s.split_once(',')?;
+    Some((u16::from_str_radix(address, 16).ok()?, u16::from_str_radix(length, 16).ok()?))
+}
+
+#[cfg(test)]
+mod gdb_tests {
+    use std::io::{Cursor, Read};
+    use std::net::{TcpListener, TcpStream};
+
+    use crate::{header::test_rom, save::MemoryStore, GameBoy};
+    use crate::gdb::{read_packet, write_packet, Action, GdbStub, Packet};
+
+    fn game_boy() -> GameBoy {
+        GameBoy::from_rom(test_rom(0x00, 0x00, 0x00), "test".to_string(), Box::new(MemoryStore::new())).unwrap()
+    }
+
+    fn reply(stub: &mut GdbStub, gb: &mut GameBoy, packet: &str) -> String {
+        match stub.command(gb, packet) {
+            Action::Reply(r) => r,
+            other => panic!("{} gave {:?}", packet, other)
+        }
+    }
+
+    #[test]
+    fn framing() {
+        let mut out = Vec::new();
+        write_packet(&mut out, "OK").unwrap();
+        assert_eq!(out, b"$OK#9a");
+
+        let mut input = Cursor::new(b"+$g#67".to_vec());
+        match read_packet(&mut input).unwrap() {
+            Some(Packet::Data(data)) => assert_eq!(data, "g"),
+            _ => panic!("Expected a packet")
+        }
+    }
+
+    #[test]
+    fn registers_and_memory() {
+        let mut gb = game_boy();
+        gb.start();
+        let mut stub = GdbStub::default();
+
+        // AF BC DE HL SP PC after the boot ROM, little-endian
+        assert_eq!(reply(&mut stub, &mut gb, "g"), "b0011300d8004d01feff0001");
+        assert_eq!(reply(&mut stub, &mut gb, "P5=5001"), "OK");
+        assert_eq!(reply(&mut stub, &mut gb, "p5"), "5001");
+
+        assert_eq!(reply(&mut stub, &mut gb, "Mc000,2:abcd"), "OK");
+        assert_eq!(reply(&mut stub, &mut gb, "mc000,2"), "abcd");
+        assert_eq!(reply(&mut stub, &mut gb, "Z0,150,1"), "OK");
+        assert_eq!(gb.debugger.breakpoints().len(), 1);
+        assert_eq!(reply(&mut stub, &mut gb, "z0,150,1"), "OK");
+        assert!(gb.debugger.breakpoints().is_empty());
+        assert!(reply(&mut stub, &mut gb, "qXfer:features:read:target.xml:0,1000").starts_with("l<?xml"));
+    }
+
+    #[test]
+    fn kill_keeps_other_breakpoints() {
+        let mut gb = game_boy();
+        gb.start();
+        let own = gb.debugger.add_breakpoint(0x0200, None, None);
+
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        write_packet(&mut client, "Z0,150,1").unwrap();
+        write_packet(&mut client, "k").unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        GdbStub::default().session(&mut gb, stream).unwrap();
+
+        // Only the Z0 gets a reply, the connection is closed after k
+        let mut received = String::new();
+        client.read_to_string(&mut received).unwrap();
+        assert_eq!(received, "+$OK#9a+");
+        let left: Vec<u32> = gb.debugger.breakpoints().iter().map(|b| b.id).collect();
+        assert_eq!(left, vec![own]);
+    }
+}
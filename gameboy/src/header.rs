@@ -0,0 +1,228 @@
+use wasm_bindgen::prelude::*;
+
+use crate::cartridge::LoadError;
+
+// Everything up to and including the global checksum at 0x014E-0x014F
+pub const HEADER_END: usize = 0x0150;
+
+// Cartridge header at 0x0100-0x014F. Layout source: Pandocs
+//   0x0134-0x0143  Title, the last 5 bytes are the manufacturer code and CGB flag on newer carts
+//   0x0144-0x0145  New licensee code, used when the old licensee code is 0x33
+//   0x0146         SGB flag
+//   0x0147         Cartridge type
+//   0x0148         ROM size
+//   0x0149         RAM size
+//   0x014A         Destination code
+//   0x014B         Old licensee code
+//   0x014C         Mask ROM version number
+//   0x014D         Header checksum
+//   0x014E-0x014F  Global checksum, big-endian
+#[wasm_bindgen(getter_with_clone)]
+#[derive(Debug, Clone, Default)]
+pub struct CartridgeHeader {
+    pub title: String,
+    pub manufacturer_code: String,
+    pub cgb_flag: u8,
+    pub sgb_flag: u8,
+    pub new_licensee_code: String,
+    pub old_licensee_code: u8,
+    pub cartridge_type: u8,
+    pub rom_size: u8,
+    pub ram_size: u8,
+    pub destination_code: u8,
+    pub version: u8,
+    pub header_checksum: u8,
+    pub global_checksum: u16,
+
+    // The boot ROM refuses to start a cartridge with a bad header checksum.
+    // The global checksum is never verified by hardware, so it is only reported
+    pub header_checksum_valid: bool,
+    pub global_checksum_valid: bool,
+    computed_header_checksum: u8,
+}
+
+impl CartridgeHeader {
+    pub fn parse(data: &[u8]) -> Result<CartridgeHeader, LoadError> {
+        if data.len() < HEADER_END {
+            return Err(LoadError::TooShort { len: data.len() });
+        }
+
+        let cgb_flag = data[0x0143];
+        let manufacturer = &data[0x013F..=0x0142];
+        // Newer carts shorten the title to make room for the manufacturer code and CGB flag
+        let has_manufacturer = cgb_flag & 0x80 > 0 && manufacturer.iter().all(|c| c.is_ascii_uppercase() || c.is_ascii_digit());
+        let (title, manufacturer_code) = if has_manufacturer {
+            (ascii(&data[0x0134..=0x013E]), ascii(manufacturer))
+        } else if cgb_flag & 0x80 > 0 {
+            (ascii(&data[0x0134..=0x0142]), String::new())
+        } else {
+            (ascii(&data[0x0134..=0x0143]), String::new())
+        };
+
+        let header_checksum = data[0x014D];
+        let computed_header_checksum = compute_header_checksum(data);
+        let global_checksum = ((data[0x014E] as u16) << 8) | data[0x014F] as u16;
+
+        Ok(CartridgeHeader {
+            title,
+            manufacturer_code,
+            cgb_flag,
+            sgb_flag: data[0x0146],
+            new_licensee_code: ascii(&data[0x0144..=0x0145]),
+            old_licensee_code: data[0x014B],
+            cartridge_type: data[0x0147],
+            rom_size: data[0x0148],
+            ram_size: data[0x0149],
+            destination_code: data[0x014A],
+            version: data[0x014C],
+            header_checksum,
+            global_checksum,
+            header_checksum_valid: computed_header_checksum == header_checksum,
+            global_checksum_valid: compute_global_checksum(data) == global_checksum,
+            computed_header_checksum,
+        })
+    }
+
+    // Checks the header the way the boot ROM would
+    pub fn validate(&self) -> Result<(), LoadError> {
+        if !self.header_checksum_valid {
+            return Err(LoadError::BadHeaderChecksum { expected: self.header_checksum, actual: self.computed_header_checksum });
+        }
+        Ok(())
+    }
+
+    // ROM size in bytes, None for unknown size codes
+    pub fn rom_bytes(&self) -> Option<usize> {
+        match self.rom_size {
+            0x00..=0x08 => Some(0x8000 << self.rom_size),
+            _ => None
+        }
+    }
+
+    // External RAM size in bytes as declared in the header, None for unknown size codes
+    pub fn ram_bytes(&self) -> Option<usize> {
+        match self.ram_size {
+            0x00 => Some(0),
+            0x01 => Some(2 * 1024),
+            0x02 => Some(8 * 1024),
+            0x03 => Some(32 * 1024),
+            0x04 => Some(128 * 1024),
+            0x05 => Some(64 * 1024),
+            _ => None
+        }
+    }
+
+    pub fn supports_cgb(&self) -> bool {
+        self.cgb_flag & 0x80 > 0
+    }
+
+    pub fn cgb_only(&self) -> bool {
+        self.cgb_flag == 0xC0
+    }
+
+    pub fn supports_sgb(&self) -> bool {
+        self.sgb_flag == 0x03
+    }
+
+    // The licensee code as shown by most tools: the new code when the old one defers to it
+    pub fn licensee_code(&self) -> String {
+        if self.old_licensee_code == 0x33 {
+            self.new_licensee_code.clone()
+        } else {
+            format!("{:02X}", self.old_licensee_code)
+        }
+    }
+
+    pub fn is_japanese(&self) -> bool {
+        self.destination_code == 0x00
+    }
+}
+
+// Checksum over 0x0134-0x014C, verified by the boot ROM against 0x014D
+pub fn compute_header_checksum(data: &[u8]) -> u8 {
+    data[0x0134..=0x014C].iter().fold(0u8, |x, b| x.wrapping_sub(*b).wrapping_sub(1))
+}
+
+// Sum of every byte in the ROM except the two checksum bytes themselves
+pub fn compute_global_checksum(data: &[u8]) -> u16 {
+    data.iter()
+        .enumerate()
+        .filter(|(i, _)| *i != 0x014E && *i != 0x014F)
+        .fold(0u16, |sum, (_, b)| sum.wrapping_add(*b as u16))
+}
+
+// Header strings are upper case ASCII padded with zeros
+fn ascii(bytes: &[u8]) -> String {
+    bytes.iter()
+        .take_while(|b| **b != 0)
+        .filter(|b| b.is_ascii_graphic() || **b == b' ')
+        .map(|b| *b as char)
+        .collect::<String>()
+        .trim_end()
+        .to_string()
+}
+
+// An empty ROM with a valid header checksum, shared by the tests that need a cartridge
+#[cfg(test)]
+pub fn test_rom(c_type: u8, rom_size: u8, ram_size: u8) -> Vec<u8> {
+    let mut data = vec![0; 0x8000 << rom_size];
+    data[0x0147] = c_type;
+    data[0x0148] = rom_size;
+    data[0x0149] = ram_size;
+    data[0x014D] = compute_header_checksum(&data);
+    data
+}
+
+#[cfg(test)]
+mod header_tests {
+    use crate::header::{compute_global_checksum, compute_header_checksum, test_rom, CartridgeHeader};
+
+    fn rom(title: &[u8], cgb_flag: u8) -> Vec<u8> {
+        let mut data = test_rom(0x13, 0x00, 0x03);
+        data[0x0134..0x0134 + title.len()].copy_from_slice(title);
+        data[0x0143] = cgb_flag;
+        data[0x014B] = 0x33;
+        data[0x0144] = b'0';
+        data[0x0145] = b'1';
+        data[0x014D] = compute_header_checksum(&data);
+        let global = compute_global_checksum(&data);
+        data[0x014E] = (global >> 8) as u8;
+        data[0x014F] = global as u8;
+        data
+    }
+
+    #[test]
+    fn parses_dmg_header() {
+        let header = CartridgeHeader::parse(&rom(b"POKEMON RED", 0x00)).unwrap();
+        assert_eq!(header.title, "POKEMON RED");
+        assert_eq!(header.manufacturer_code, "");
+        assert_eq!(header.cartridge_type, 0x13);
+        assert_eq!(header.ram_bytes(), Some(32 * 1024));
+        assert_eq!(header.rom_bytes(), Some(0x8000));
+        assert_eq!(header.licensee_code(), "01");
+        assert!(!header.supports_cgb());
+        assert!(header.header_checksum_valid);
+        assert!(header.global_checksum_valid);
+        assert!(header.validate().is_ok());
+    }
+
+    #[test]
+    fn parses_manufacturer_code() {
+        let header = CartridgeHeader::parse(&rom(b"POKEMON_SLVAAXE", 0x80)).unwrap();
+        assert_eq!(header.title, "POKEMON_SLV");
+        assert_eq!(header.manufacturer_code, "AAXE");
+        assert!(header.supports_cgb());
+        assert!(!header.cgb_only());
+    }
+
+    #[test]
+    fn detects_bad_checksums() {
+        let mut data = rom(b"TETRIS", 0x00);
+        data[0x014D] ^= 0xFF;
+        data[0x1000] = 0x42;
+        let header = CartridgeHeader::parse(&data).unwrap();
+        assert!(!header.header_checksum_valid);
+        assert!(!header.global_checksum_valid);
+        assert!(header.validate().is_err());
+    }
+}
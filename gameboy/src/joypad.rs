@@ -1,6 +1,8 @@
 
 // Implementation inspired and based on https://github.com/torch2424/wasmboy
 
+use crate::savestate::{StateError, StateReader, StateWriter};
+
 pub struct Joypad {
     up: bool,
     right: bool,
@@ -33,6 +35,30 @@ impl Joypad {
         Joypad { up: false, right: false, down: false, left: false, a: false, b: false, select: false, start: false, is_dpad_type: false, is_button_type: false, joypad_register_flipped: 0 }
     }
 
+    pub fn write_state(&self, w: &mut StateWriter) {
+        for pressed in [self.up, self.right, self.down, self.left, self.a, self.b, self.select, self.start].iter() {
+            w.bool(*pressed);
+        }
+        w.bool(self.is_dpad_type);
+        w.bool(self.is_button_type);
+        w.u8(self.joypad_register_flipped);
+    }
+
+    pub fn read_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.up = r.bool()?;
+        self.right = r.bool()?;
+        self.down = r.bool()?;
+        self.left = r.bool()?;
+        self.a = r.bool()?;
+        self.b = r.bool()?;
+        self.select = r.bool()?;
+        self.start = r.bool()?;
+        self.is_dpad_type = r.bool()?;
+        self.is_button_type = r.bool()?;
+        self.joypad_register_flipped = r.u8()?;
+        Ok(())
+    }
+
     pub fn update_joypad(&mut self, value: u8) {
         self.joypad_register_flipped = value ^ 0xFF;
         self.is_dpad_type = self.joypad_register_flipped & 0b10000 > 0;
@@ -0,0 +1,196 @@
+// LCD timing and status, 0xFF41 STAT, 0xFF44 LY and 0xFF45 LYC. Behaviour source: Pandocs
+// A line takes 114 M-cycles (456 dots). Lines 0-143 go through
+//   Mode 2  OAM scan       20 M-cycles
+//   Mode 3  Drawing        43 M-cycles
+//   Mode 0  HBlank         51 M-cycles
+// and lines 144-153 are VBlank, mode 1. The length of mode 3 is fixed here,
+// on hardware it depends on scrolling, the window and sprites.
+// Pixels are drawn by the PPU a whole line at a time: when mode 3 ends the line is left pending
+// until the PPU renders it
+
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+pub const CYCLES_PER_LINE: u16 = 114;
+pub const CYCLES_PER_FRAME: u32 = CYCLES_PER_LINE as u32 * 154;
+const MODE_2_END: u16 = 20;
+const MODE_3_END: u16 = 63;
+
+pub struct Lcd {
+    enabled: bool,
+    ly: u8,
+    lyc: u8,
+    // STAT interrupt sources, bits 3-6
+    stat: u8,
+    mode: u8,
+    cycles: u16,
+    // The STAT interrupt is requested when any enabled source becomes active
+    stat_line: bool,
+    pending_line: Option<u8>,
+    frame_ready: bool,
+}
+
+impl Lcd {
+    pub fn new() -> Lcd {
+        Lcd {
+            enabled: false,
+            ly: 0,
+            lyc: 0,
+            stat: 0,
+            mode: 0,
+            cycles: 0,
+            stat_line: false,
+            pending_line: None,
+            frame_ready: false,
+        }
+    }
+
+    pub fn read(&self, loc: u16) -> u8 {
+        match loc {
+            0xFF41 => 0x80 | self.stat | ((self.ly == self.lyc) as u8) << 2 | self.mode,
+            0xFF44 => self.ly,
+            0xFF45 => self.lyc,
+            _ => 0xFF
+        }
+    }
+
+    // LY is read only. Returns the interrupts to request
+    pub fn write(&mut self, loc: u16, val: u8) -> u8 {
+        match loc {
+            0xFF41 => self.stat = val & 0x78,
+            0xFF45 => self.lyc = val,
+            _ => {}
+        }
+        self.update_stat_line()
+    }
+
+    // Advances one M-cycle. lcdc is the current LCDC register, returns the interrupts to request
+    pub fn tick(&mut self, lcdc: u8) -> u8 {
+        if lcdc & 0x80 == 0 {
+            // Turning the LCD off resets it to the start of line 0
+            if self.enabled {
+                self.enabled = false;
+                self.ly = 0;
+                self.cycles = 0;
+                self.mode = 0;
+                self.stat_line = false;
+            }
+            return 0
+        }
+        if !self.enabled {
+            self.enabled = true;
+            self.mode = 2;
+        }
+
+        let mut interrupts = 0;
+        self.cycles += 1;
+        if self.cycles == CYCLES_PER_LINE {
+            self.cycles = 0;
+            self.ly = (self.ly + 1) % 154;
+        }
+
+        let mode = if self.ly >= 144 {
+            1
+        } else if self.cycles < MODE_2_END {
+            2
+        } else if self.cycles < MODE_3_END {
+            3
+        } else {
+            0
+        };
+        if mode != self.mode {
+            self.mode = mode;
+            match mode {
+                0 => self.pending_line = Some(self.ly),
+                1 => {
+                    self.frame_ready = true;
+                    interrupts |= 0b1;
+                },
+                _ => {}
+            }
+        }
+        interrupts | self.update_stat_line()
+    }
+
+    fn update_stat_line(&mut self) -> u8 {
+        let line = (self.stat & 0x08 > 0 && self.mode == 0)
+            || (self.stat & 0x10 > 0 && self.mode == 1)
+            || (self.stat & 0x20 > 0 && self.mode == 2)
+            || (self.stat & 0x40 > 0 && self.ly == self.lyc);
+        let rising = line && !self.stat_line;
+        self.stat_line = line;
+        if rising && self.enabled { 0b10 } else { 0 }
+    }
+
+    // The line that finished drawing and still has to be rendered by the PPU
+    pub fn take_pending_line(&mut self) -> Option<u8> {
+        self.pending_line.take()
+    }
+
+    // True once per frame, when VBlank starts
+    pub fn take_frame_ready(&mut self) -> bool {
+        std::mem::replace(&mut self.frame_ready, false)
+    }
+
+    pub fn write_state(&self, w: &mut StateWriter) {
+        w.bool(self.enabled);
+        w.u8(self.ly);
+        w.u8(self.lyc);
+        w.u8(self.stat);
+        w.u8(self.mode);
+        w.u16(self.cycles);
+        w.bool(self.stat_line);
+    }
+
+    pub fn read_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = r.bool()?;
+        self.ly = r.u8()? % 154;
+        self.lyc = r.u8()?;
+        self.stat = r.u8()? & 0x78;
+        self.mode = r.u8()? & 0x03;
+        self.cycles = r.u16()? % CYCLES_PER_LINE;
+        self.stat_line = r.bool()?;
+        self.pending_line = None;
+        self.frame_ready = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod lcd_tests {
+    use crate::lcd::{Lcd, CYCLES_PER_FRAME, CYCLES_PER_LINE};
+
+    #[test]
+    fn frame_timing() {
+        let mut lcd = Lcd::new();
+        let mut vblanks = 0;
+        let mut lines = 0;
+        for _ in 0..CYCLES_PER_FRAME {
+            vblanks += lcd.tick(0x80) & 0b1;
+            if lcd.take_pending_line().is_some() {
+                lines += 1;
+            }
+        }
+        assert_eq!(vblanks, 1);
+        assert_eq!(lines, 144);
+        assert_eq!(lcd.read(0xFF44), 0);
+    }
+
+    #[test]
+    fn lyc_interrupt() {
+        let mut lcd = Lcd::new();
+        lcd.write(0xFF45, 2);
+        lcd.write(0xFF41, 0x40);
+        let mut interrupts = 0;
+        for _ in 0..CYCLES_PER_LINE * 2 {
+            interrupts += (lcd.tick(0x80) & 0b10) >> 1;
+        }
+        assert_eq!(interrupts, 1);
+        assert_eq!(lcd.read(0xFF44), 2);
+        assert_eq!(lcd.read(0xFF41) & 0b100, 0b100);
+
+        for _ in 0..CYCLES_PER_LINE {
+            interrupts += (lcd.tick(0x80) & 0b10) >> 1;
+        }
+        assert_eq!(interrupts, 1);
+    }
+}
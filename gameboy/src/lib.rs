@@ -3,18 +3,45 @@ extern crate core;
 pub mod cpu;
 pub mod memory;
 mod cartridge;
+mod apu;
+mod audio;
+pub mod wav;
 mod ppu;
 mod joypad;
 pub mod state;
-mod save;
+pub mod save;
+mod rtc;
+mod timer;
+pub mod serial;
+pub mod header;
+pub mod savestate;
+mod rewind;
+mod lcd;
+pub mod disasm;
+pub mod debugger;
+pub mod trace;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod gdb;
 
 use wasm_bindgen::prelude::*;
 use web_sys::CanvasRenderingContext2d;
 use web_sys::console;
 use crate::cartridge::Cartridge;
-use crate::cpu::CPU;
+pub use crate::cartridge::LoadError;
+use crate::header::CartridgeHeader;
+use crate::save::{CallbackStore, LocalStorageStore, SaveStore};
+use crate::savestate::{StateError, StateReader, StateWriter};
+use crate::cpu::{CPU, EmulationFault, Registers};
+use crate::disasm::Instruction;
+use crate::debugger::{Break, BreakReason, Debugger};
+use crate::trace::RingBuffer;
 use crate::memory::Memory;
 use crate::ppu::PPU;
+use crate::rewind::Rewind;
+use crate::lcd::CYCLES_PER_FRAME;
+use crate::audio::{AudioOutput, DEFAULT_SAMPLE_RATE};
+use crate::wav::{WavRecorder, WavRecording};
+use crate::serial::{LinkCable, LinkPort};
 
 // When the `wee_alloc` feature is enabled, use `wee_alloc` as the global
 // allocator.
@@ -29,23 +56,194 @@ pub struct GameBoy {
     mem: Memory,
     cpu: CPU,
     ppu: PPU,
-    cnt: i32,
-    div_counter: u16,
-    timer_counter: u16,
+    rewind: Rewind,
+    // Set when the last run finished its frame. Rewind snapshots are only taken at frame starts,
+    // so debugger steps and breaks in the middle of a frame don't count as frames
+    frame_start: bool,
+    audio: AudioOutput,
+    recorder: Option<WavRecorder>,
+    debugger: Debugger,
+    trace_log: Option<RingBuffer>,
 }
 
 #[wasm_bindgen]
 impl GameBoy {
-    pub fn new(data: Vec<u8>, name: String) -> GameBoy {
-        let cart = Cartridge::new(data, name);
-        let mem = Memory::new(Some(cart));
-        GameBoy{ mem, cpu: CPU::new(), ppu: PPU::new(), cnt: 0, timer_counter: 0, div_counter: 0}
+    // Throws a JS error describing why the ROM was rejected. Battery saves go to localStorage
+    pub fn new(data: Vec<u8>, name: String) -> Result<GameBoy, JsError> {
+        Ok(GameBoy::from_rom(data, name, Box::new(LocalStorageStore))?)
+    }
+
+    // Like new, but battery saves are loaded and stored through the given JS functions,
+    // see save::CallbackStore
+    pub fn with_save_callbacks(data: Vec<u8>, name: String, load: js_sys::Function, store: js_sys::Function) -> Result<GameBoy, JsError> {
+        Ok(GameBoy::from_rom(data, name, Box::new(CallbackStore::new(load, store)))?)
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn header(&self) -> CartridgeHeader {
+        self.mem.cart.header.clone()
+    }
+
+    // Set when the stored battery save didn't match the cartridge's RAM size. The game still boots,
+    // with the save padded or cut to fit, so the frontend should let the user know
+    pub fn load_warning(&self) -> Option<String> {
+        self.mem.cart.load_warning().map(|e| e.to_string())
+    }
+
+    // Why the last battery save couldn't be stored, like a full localStorage. None once a later save succeeds
+    pub fn last_save_error(&self) -> Option<String> {
+        self.mem.cart.last_save_error().map(|e| e.to_string())
+    }
+
+    // Battery RAM in the raw .sav layout shared with other emulators
+    pub fn export_save(&self) -> Vec<u8> {
+        self.mem.cart.export_save()
+    }
+
+    // Loads a .sav file from another emulator. The size must match the RAM size in the header,
+    // optionally followed by an RTC footer for MBC3 carts with a timer
+    pub fn import_save(&mut self, data: &[u8]) -> Result<(), JsError> {
+        Ok(self.mem.cart.import_save(data)?)
+    }
+
+    // Snapshot of the whole machine, tied to the loaded ROM. See savestate for the format
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut w = StateWriter::new(self.mem.cart.rom_hash);
+        self.cpu.write_state(&mut w);
+        self.mem.write_state(&mut w);
+        self.ppu.write_state(&mut w);
+        w.finish()
+    }
+
+    // Throws if the state is from another ROM or another version of the format.
+    // A state that fails to load leaves the running game untouched
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), JsError> {
+        Ok(self.try_load_state(data)?)
+    }
+
+    // Goes back at least the given number of frames, limited by how far the buffer reaches. Calling
+    // it again keeps going further back. Returns the number of frames actually rewound
+    pub fn rewind_frames(&mut self, frames: u32) -> u32 {
+        match self.rewind.rewind(frames) {
+            Some((state, travelled)) => {
+                self.read_state(&state).expect("Rewind snapshots are made by this GameBoy");
+                // Snapshots are taken at the start of a frame
+                self.frame_start = true;
+                travelled
+            },
+            None => 0
+        }
+    }
+
+    // Rewinding is off until enabled here. Takes a snapshot every `interval` frames while the buffer
+    // stays under `budget` bytes, a budget of 0 turns it off again. A snapshot every other frame
+    // within 8 MB is roughly a minute of rewind for most games
+    pub fn set_rewind(&mut self, budget: usize, interval: u32) {
+        self.rewind.set_budget(budget);
+        self.rewind.set_interval(interval);
+    }
+
+    // How many frames back the rewind buffer currently reaches
+    #[wasm_bindgen(getter)]
+    pub fn rewind_available(&self) -> u32 {
+        self.rewind.frames_available()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn rewind_memory_used(&self) -> usize {
+        self.rewind.memory_used()
+    }
+
+    // Interleaved stereo samples produced since the last call, at the rate set with set_audio_sample_rate.
+    // Samples are kept in a bounded buffer, the oldest are dropped if they aren't taken in time
+    pub fn take_audio_samples(&mut self) -> Vec<f32> {
+        self.audio.take()
+    }
+
+    // Usually the AudioContext sample rate. Clears any buffered samples
+    pub fn set_audio_sample_rate(&mut self, rate: u32) {
+        self.audio.set_sample_rate(rate)
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn audio_sample_rate(&self) -> u32 {
+        self.audio.sample_rate()
+    }
+
+    // Maximum number of stereo frames kept in the audio buffer
+    pub fn set_audio_buffer_capacity(&mut self, frames: usize) {
+        self.audio.set_capacity(frames)
+    }
+
+    // Stereo frames waiting to be taken. For audio driven pacing, call run until this reaches the
+    // amount the audio callback needs instead of running once per requestAnimationFrame
+    #[wasm_bindgen(getter)]
+    pub fn audio_buffered_frames(&self) -> usize {
+        self.audio.buffered_frames()
+    }
+
+    // Starts recording the audio output at the current sample rate, replacing any recording in progress.
+    // With per_channel the four channels are also recorded to separate files
+    pub fn start_wav_recording(&mut self, per_channel: bool) {
+        self.recorder = Some(WavRecorder::new(self.audio.sample_rate(), per_channel));
+    }
+
+    // Returns the recording as .wav files, undefined if nothing was being recorded
+    pub fn stop_wav_recording(&mut self) -> Option<WavRecording> {
+        self.recorder.take().map(|recorder| recorder.finish())
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn recording_wav(&self) -> bool {
+        self.recorder.is_some()
+    }
+
+    // Bytes sent over the serial port since the last call. Test ROMs print their results this way
+    pub fn take_serial_output(&mut self) -> Vec<u8> {
+        self.mem.serial.take_output()
+    }
+
+    // Connects the link ports of two Game Boys, replacing any earlier connections
+    pub fn link_with(&mut self, other: &mut GameBoy) {
+        let (a, b) = LinkCable::pair();
+        self.mem.serial.connect(Box::new(a));
+        other.mem.serial.connect(Box::new(b));
+    }
+
+    pub fn disconnect_link(&mut self) {
+        self.mem.serial.disconnect()
+    }
+
+    // Set when the CPU has locked up on an illegal opcode. The screen and sound keep running
+    #[wasm_bindgen(getter)]
+    pub fn locked(&self) -> bool {
+        self.cpu.locked()
+    }
+
+    // The illegal opcode that locked the CPU, returned once so the frontend can report it
+    pub fn take_fault(&mut self) -> Option<EmulationFault> {
+        self.cpu.take_fault()
+    }
+
+    // A snapshot of the CPU registers
+    pub fn registers(&self) -> Registers {
+        self.cpu.registers()
+    }
+
+    // Decodes count instructions starting at address, as an array of Instruction
+    pub fn disassemble(&self, address: u16, count: usize) -> js_sys::Array {
+        instruction_array(disasm::disassemble(&self.mem, address, count))
+    }
+
+    // The code around PC for a debugger view: up to before instructions leading to PC, then after
+    // instructions starting at PC
+    pub fn disassemble_around_pc(&self, before: usize, after: usize) -> js_sys::Array {
+        instruction_array(disasm::disassemble_around(&self.mem, self.cpu.pc(), before, after))
     }
 
     pub fn start(&mut self) {
         self.cpu.simulate_bootloader();
         self.mem.simulate_bootloader();
-        self.cnt = 80;
     }
 
     // Runs 3000 frames in batches of 30 frames
@@ -76,112 +274,196 @@ impl GameBoy {
     }
 
 
-     pub fn run(&mut self) {
-
-        let mut count_1 = 0;
+    // Runs until the next VBlank, or for a frame's worth of cycles while the LCD is off.
+    // Stops early when the debugger breaks, see last_break for the details
+    pub fn run(&mut self) -> BreakReason {
+        if self.frame_start && self.rewind.frame_due() {
+            let state = self.save_state();
+            self.rewind.push(state);
+        }
+        let reason = self.run_frame();
+        self.frame_start = reason == BreakReason::Frame;
+        reason
+    }
 
-        loop {
-            let cycle = self.step();
-            self.cnt -= cycle as i32;
-
-            // DIV
-            self.div_counter += cycle as u16;
-            while self.div_counter >= 64 {
-                self.div_counter -= 64;
-                let div = self.mem.read(0xff04);
-                if div == 0xff {
-                    self.mem.write(0xff04, 0);
-                } else {
-                    self.mem.write(0xff04, div + 1);
+    // run without the rewind snapshot
+    fn run_frame(&mut self) -> BreakReason {
+        let mut cycles = 0;
+        while cycles < CYCLES_PER_FRAME {
+            let debugging = self.debugger.active();
+            if debugging {
+                if let Some(reason) = self.debugger.before_step(&self.cpu, &self.mem) {
+                    return reason
                 }
             }
 
-            // Timer
-            let timer_control = self.mem.read(0xFF07);
-            if timer_control & 0b100 > 0 {
-                self.timer_counter += cycle as u16;
-
-                while self.timer_counter >= CPU::get_timer_rate(timer_control) {
-                    self.timer_counter -= CPU::get_timer_rate(timer_control);
-                    let tima = self.mem.read(0xFF05);
-                    if tima == 0xFF {
-                        let i_flags = self.mem.read(0xFF0F);
-                        self.mem.write(0xFF05, self.mem.read(0xFF06));
-                        self.mem.write(0xFF0F, i_flags | 0b100)
-                    } else {
-                        self.mem.write(0xFF05, tima + 1);
-                    }
+            let locked = self.cpu.locked();
+            let cycle = self.step();
+            cycles += cycle as u32;
+
+            let div = self.mem.read(0xFF04);
+            let audio = &mut self.audio;
+            let recorder = &mut self.recorder;
+            self.mem.apu.tick(cycle, div, |apu| {
+                let (left, right) = apu.output();
+                audio.push(left, right);
+                if let Some(recorder) = recorder.as_mut() {
+                    recorder.push(apu);
                 }
-            }
+            });
 
-            let mut stat = self.mem.read(0xFF41);
-            if self.cnt <= 0 {
-                match stat & 0b11 {
-                    0 => { // Going into either VBlank or Searching OAM
-                        if self.mem.read(0xFF44) >= 144{
-                            stat = stat + 1;
-                            self.cnt += 4560;
-                            if stat & 0b10000 > 0{
-                                self.mem.write(0xFF0F, self.mem.read(0xFF0F) | 0b10);
-                            }
-                            self.mem.write(0xFF0F, self.mem.read(0xFF0F) | 0b1);
-                            // self.ppu.draw(ctx)
-                        } else {
-                            stat = stat + 2;
-                            self.cnt += 80;
-                            if stat & 0b100000 > 0{
-                                self.mem.write(0xFF0F, self.mem.read(0xFF0F) | 0b10);
-                            }
-                        }
-                    },
-                    1 => { // Going into Searching OAM, end of frame
-                        stat = stat + 1;
-                        self.cnt += 80;
-                        if stat & 0b100000 > 0{
-                            self.mem.write(0xFF0F, self.mem.read(0xFF0F) | 0b10);
-                        }
-                        self.mem.write(0xFF41, stat);
-                        while self.mem.read(0xFF44) != 0 {
-                            self.advance_line()
-                        }
-                        return;
-                    },
-                    2 => { // Going into Generating picture
-                        stat = stat + 1;
-                        self.cnt += 168;
-                    },
-                    3 => {
-                        self.advance_line();
-                        stat = stat - 3;
-                        self.cnt += 208;
-                        if stat & 0b1000 > 0{
-                            self.mem.write(0xFF0F, self.mem.read(0xFF0F) | 0b10);
-                        }
-                    },
-                    _ => console::error_1(&"Unreachable mode".into())
+            if let Some(ly) = self.mem.lcd.take_pending_line() {
+                self.ppu.render_line(&mut self.mem, ly);
+            }
+            let frame_done = self.mem.lcd.take_frame_ready();
 
+            if !locked && self.cpu.locked() {
+                let pc = self.cpu.pc();
+                return self.debugger.hit(Break { reason: BreakReason::Fault, pc, id: 0, address: pc, value: self.mem.read(pc), write: false })
+            }
+            if debugging {
+                if let Some(reason) = self.debugger.after_step(&self.cpu, &mut self.mem) {
+                    return reason
                 }
-            } else if stat & 0b11 == 1 {
-                if count_1 % 456 == 0 {
-                    self.advance_line()
-                }
-                count_1 += 1;
             }
-            self.mem.write(0xFF41, stat);
+            if frame_done {
+                break
+            }
         }
-        // self.ppu.draw(&mut self.mem, ctx);
-     }
+        BreakReason::Frame
+    }
+
+    // Stops before the instruction at address runs. With a bank, only while that ROM bank is mapped.
+    // The condition compares a register with a value, like "A == 0x42" or "HL >= $C000".
+    // Returns an id for remove_breakpoint
+    pub fn add_breakpoint(&mut self, address: u16, bank: Option<u16>, condition: Option<String>) -> Result<u32, JsError> {
+        let condition = match condition {
+            Some(c) => Some(c.parse()?),
+            None => None
+        };
+        Ok(self.debugger.add_breakpoint(address, bank, condition))
+    }
+
+    // Stops when the CPU reads, writes or executes anything in start..=end. Returns an id for remove_breakpoint
+    pub fn add_watchpoint(&mut self, start: u16, end: u16, read: bool, write: bool, execute: bool) -> u32 {
+        let id = self.debugger.add_watchpoint(start, end, read, write, execute);
+        self.mem.set_watchpoints(self.debugger.watchpoints());
+        id
+    }
+
+    // Removes a breakpoint or watchpoint, returns false if the id is unknown
+    pub fn remove_breakpoint(&mut self, id: u32) -> bool {
+        let removed = self.debugger.remove(id);
+        self.mem.set_watchpoints(self.debugger.watchpoints());
+        removed
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.debugger.clear();
+        self.mem.set_watchpoints(&[]);
+    }
+
+    // Runs a single instruction
+    pub fn step_into(&mut self) -> BreakReason {
+        self.debugger.step_into();
+        self.run()
+    }
+
+    // Runs a CALL or RST until it returns, otherwise a single instruction.
+    // Like the other steps this runs at most a frame, call run again while it returns Frame
+    pub fn step_over(&mut self) -> BreakReason {
+        self.debugger.step_over(&self.cpu, &self.mem);
+        self.run()
+    }
+
+    // Runs until the current function returns
+    pub fn step_out(&mut self) -> BreakReason {
+        self.debugger.step_out(&self.cpu);
+        self.run()
+    }
+
+    // Logs every instruction in the Gameboy Doctor format, keeping the newest lines up to capacity.
+    // LY reads 0x90 until the log is stopped, as the format requires
+    pub fn start_trace_log(&mut self, capacity: usize) {
+        let buffer = RingBuffer::new(capacity);
+        self.cpu.set_trace_sink(Some(Box::new(buffer.clone())));
+        self.mem.set_ly_stub(true);
+        self.trace_log = Some(buffer);
+    }
+
+    // Logged lines since the last call, separated by newlines
+    pub fn take_trace_log(&mut self) -> String {
+        self.trace_log.as_ref().map(|log| log.take().join("\n")).unwrap_or_default()
+    }
+
+    pub fn stop_trace_log(&mut self) {
+        self.cpu.set_trace_sink(None);
+        self.mem.set_ly_stub(false);
+        self.trace_log = None;
+    }
+
+    // Details of the last time run stopped for anything other than the end of a frame
+    pub fn last_break(&self) -> Option<Break> {
+        self.debugger.last_break()
+    }
 
      pub fn set_joypad_state(&mut self, up: i32, right: i32, down: i32, left: i32, a: i32, b: i32, select: i32, start: i32) {
         self.mem.set_joypad_state(up, right, down, left, a, b, select, start)
      }
 
+    // Lets the MBC3 real-time clock follow the host clock instead of emulated cycles
+    pub fn set_rtc_host_clock(&mut self, enabled: bool) {
+        self.mem.cart.set_rtc_host_clock(enabled)
+    }
+
     pub fn step(&mut self) -> u8 {
         self.cpu.run(&mut self.mem)
     }
+}
 
-    pub fn advance_line(&mut self) {
-        self.ppu.advance_line(&mut self.mem);
+impl GameBoy {
+    pub fn from_rom(data: Vec<u8>, name: String, store: Box<dyn SaveStore>) -> Result<GameBoy, LoadError> {
+        let cart = Cartridge::new(data, name, store)?;
+        let mem = Memory::new(Some(cart));
+        Ok(GameBoy{ mem, cpu: CPU::new(), ppu: PPU::new(), rewind: Rewind::new(0, 1), frame_start: true, audio: AudioOutput::new(DEFAULT_SAMPLE_RATE), recorder: None, debugger: Debugger::new(), trace_log: None})
+    }
+
+    // Connects the link port to something other than another GameBoy in this process
+    pub fn connect_link(&mut self, port: Box<dyn LinkPort>) {
+        self.mem.serial.connect(port)
+    }
+
+    // Writes a Gameboy Doctor log of every instruction to a file, until stop_trace_log is called
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn trace_log_to_file<P: AsRef<std::path::Path>>(&mut self, path: P) -> std::io::Result<()> {
+        let sink = trace::FileSink::create(path)?;
+        self.cpu.set_trace_sink(Some(Box::new(sink)));
+        self.mem.set_ly_stub(true);
+        self.trace_log = None;
+        Ok(())
+    }
+
+    pub fn try_load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        // Validate the header before touching anything
+        StateReader::new(data, self.mem.cart.rom_hash)?;
+
+        let backup = self.save_state();
+        let result = self.read_state(data);
+        if result.is_err() {
+            self.read_state(&backup).expect("Backup state should always load");
+        }
+        result
+    }
+
+    fn read_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let mut r = StateReader::new(data, self.mem.cart.rom_hash)?;
+        self.cpu.read_state(&mut r)?;
+        self.mem.read_state(&mut r)?;
+        self.ppu.read_state(&mut r)?;
+        r.finish()
     }
 }
 
+fn instruction_array(instructions: Vec<Instruction>) -> js_sys::Array {
+    instructions.into_iter().map(JsValue::from).collect()
+}
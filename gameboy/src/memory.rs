@@ -1,14 +1,67 @@
-use crate::{cartridge::Cartridge, joypad::Joypad, state::{InitialState, FinalState}, ppu::Tile};
+use crate::{apu::Apu, cartridge::Cartridge, debugger::{Watchpoint, WatchHit}, joypad::Joypad, lcd::Lcd, savestate::{StateError, StateReader, StateWriter}, state::{InitialState, FinalState}, ppu::Tile, serial::Serial, timer::Timer};
 
 pub struct Memory {
     pub mem: [u8; 0x10000],
     pub cart: Cartridge,
     pub new_graphics: bool,
     pub joypad: Joypad,
+    pub apu: Apu,
+    pub timer: Timer,
+    pub serial: Serial,
+    pub lcd: Lcd,
+    dma: Dma,
+    // M-cycles run through the CPU bus, and the accesses made while tracing
+    cycles: u64,
+    trace: Option<Vec<BusCycle>>,
+    last_access: (u16, u8),
+    // Read and write watchpoints from the debugger, the first CPU access to hit one is kept
+    watchpoints: Vec<Watchpoint>,
+    watch_hit: Option<WatchHit>,
+    // LY reads 0x90 for Gameboy Doctor traces
+    ly_stub: bool,
+    // CGB double speed mode and the KEY1 switch request
+    pub double_speed: bool,
+    speed_switch: bool,
     test_mode: bool,
     pub tile_cache: [Option<Tile>; 384]
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BusAccess {
+    Read,
+    Write,
+    // An internal cycle of the CPU
+    Idle,
+}
+
+// One M-cycle of CPU bus activity
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BusCycle {
+    pub address: u16,
+    pub data: u8,
+    pub access: BusAccess,
+}
+
+impl BusCycle {
+    // The read, write and memory request pins, as written in the SM83 tests
+    pub fn pins(&self) -> &'static str {
+        match self.access {
+            BusAccess::Read => "r-m",
+            BusAccess::Write => "-wm",
+            BusAccess::Idle => "---"
+        }
+    }
+}
+
+// OAM DMA copies a byte per M-cycle, starting the cycle after 0xFF46 is written.
+// Meanwhile the CPU can only use HRAM and the IO registers
+struct Dma {
+    source: u16,
+    index: u16,
+    starting: bool,
+    active: bool,
+}
+
 impl Memory {
     // If cart is None, then memory is put into test mode with no limitations
     pub fn new(cart: Option<Cartridge>) -> Memory {
@@ -16,11 +69,11 @@ impl Memory {
         let c: Cartridge = match cart {
             None => {
                 test_mode = true;
-                Cartridge::new(vec![0; 1024 * 32], "test".to_string())},
+                Cartridge::empty()},
             Some(x) => x
         };
         let tile_cache: [Option<Tile>; 384] = [None; 384];
-        return Memory{mem: [0; 0x10000], cart: c, new_graphics: true, joypad: Joypad::new(), test_mode, tile_cache: tile_cache }
+        return Memory{mem: [0; 0x10000], cart: c, new_graphics: true, joypad: Joypad::new(), apu: Apu::new(), timer: Timer::new(), serial: Serial::new(), lcd: Lcd::new(), dma: Dma { source: 0, index: 0, starting: false, active: false }, cycles: 0, trace: None, last_access: (0, 0), watchpoints: Vec::new(), watch_hit: None, ly_stub: false, double_speed: false, speed_switch: false, test_mode, tile_cache: tile_cache }
     }
 
     pub fn load_state(&mut self, state: &InitialState){
@@ -44,6 +97,42 @@ impl Memory {
         return Ok(())
     }
 
+    pub fn write_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.mem);
+        self.joypad.write_state(w);
+        self.apu.write_state(w);
+        self.timer.write_state(w);
+        self.serial.write_state(w);
+        self.lcd.write_state(w);
+        w.u16(self.dma.source);
+        w.u16(self.dma.index);
+        w.bool(self.dma.starting);
+        w.bool(self.dma.active);
+        w.bool(self.double_speed);
+        w.bool(self.speed_switch);
+        self.cart.write_state(w);
+    }
+
+    pub fn read_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.bytes(&mut self.mem)?;
+        self.joypad.read_state(r)?;
+        self.apu.read_state(r)?;
+        self.timer.read_state(r)?;
+        self.serial.read_state(r)?;
+        self.lcd.read_state(r)?;
+        self.dma.source = r.u16()?;
+        self.dma.index = r.u16()?.min(0xA0);
+        self.dma.starting = r.bool()?;
+        self.dma.active = r.bool()?;
+        self.double_speed = r.bool()?;
+        self.speed_switch = r.bool()?;
+        self.cart.read_state(r)?;
+        // Tiles are parsed again from the restored VRAM
+        self.tile_cache = [None; 384];
+        self.new_graphics = true;
+        Ok(())
+    }
+
     pub fn print(&self) {
         for i in 0..(0xFFFFu16 / 16) {
             print!("0x{:04x} | ", i * 16);
@@ -67,6 +156,36 @@ impl Memory {
         if loc == 0xFF00 {
             return self.joypad.get_joypad_state();
         }
+
+        // Serial
+        if loc == 0xFF01 || loc == 0xFF02 {
+            return self.serial.read(loc);
+        }
+
+        // Divider and timer
+        if (0xFF04..=0xFF07).contains(&loc) {
+            return self.timer.read(loc);
+        }
+
+        // Sound
+        if (0xFF10..=0xFF3F).contains(&loc) {
+            return self.apu.read(loc);
+        }
+
+        if loc == 0xFF44 && self.ly_stub {
+            return 0x90;
+        }
+        if loc == 0xFF41 || loc == 0xFF44 || loc == 0xFF45 {
+            return self.lcd.read(loc);
+        }
+        // KEY1, Bit 7 current speed, Bit 0 switch armed. Only present on CGB
+        if loc == 0xFF4D {
+            if !self.cgb_mode() {
+                return 0xFF;
+            }
+            return 0x7E | ((self.double_speed as u8) << 7) | self.speed_switch as u8;
+        }
+
         // println!("Read: 0x{:02x}", v);
         return self.mem[loc as usize]
     }
@@ -104,25 +223,40 @@ impl Memory {
             self.joypad.update_joypad(val)
         }
 
-        if loc == 0xFF46 {
-            let source = (val as usize) << 8;
-            for i in 0..0x100 {
-                self.mem[0xFE00 + i] = self.mem[source + i];
-            }
+        if loc == 0xFF01 || loc == 0xFF02 {
+            self.serial.write(loc, val);
+            return
         }
 
-        // Compare LY and LYC
-        if loc == 0xFF44 {
-            if val == self.mem[0xFF45] {
-                self.mem[0xFF41] = self.mem[0xFF41] | 0x4;
-                // Interrupt
-                if self.mem[0xFF41] & 0b01000000 > 0{
-                    self.mem[0xFF0F] = self.mem[0xFF0F] | 0x2 // Interrupt
-                }
-            } else {
-                self.mem[0xFF41] = self.mem[0xFF41] & 0xFB;
+        if (0xFF04..=0xFF07).contains(&loc) {
+            self.timer.write(loc, val);
+            return
+        }
+
+        if (0xFF10..=0xFF3F).contains(&loc) {
+            self.apu.write(loc, val);
+            return
+        }
+
+        if loc == 0xFF4D {
+            if self.cgb_mode() {
+                self.speed_switch = val & 0x01 > 0;
             }
+            return
         }
+
+        if loc == 0xFF41 || loc == 0xFF44 || loc == 0xFF45 {
+            let interrupts = self.lcd.write(loc, val);
+            self.mem[0xFF0F] |= interrupts;
+            return
+        }
+
+        if loc == 0xFF46 {
+            self.dma.source = (val as u16) << 8;
+            self.dma.index = 0;
+            self.dma.starting = true;
+        }
+
         self.mem[loc as usize] = val
     }
 
@@ -133,47 +267,166 @@ impl Memory {
         self.write(loc + 1, high);
     }
 
+    // Whether the cartridge runs with CGB features, decided by the header CGB flag
+    pub fn cgb_mode(&self) -> bool {
+        !self.test_mode && self.cart.header.supports_cgb()
+    }
+
+    pub fn speed_switch_armed(&self) -> bool {
+        self.cgb_mode() && self.speed_switch
+    }
+
+    // Called by STOP when KEY1 has been armed
+    pub fn switch_speed(&mut self) {
+        self.double_speed = !self.double_speed;
+        self.speed_switch = false;
+    }
+
+    // A read by the CPU, taking one M-cycle
+    pub fn cpu_read(&mut self, loc: u16) -> u8 {
+        let val = if self.dma_blocks(loc) { 0xFF } else { self.read(loc) };
+        if !self.watchpoints.is_empty() {
+            self.watch(loc, val, false);
+        }
+        self.bus_cycle(loc, val, BusAccess::Read);
+        val
+    }
+
+    // Little endian, low byte first
+    pub fn cpu_read_16(&mut self, loc: u16) -> u16 {
+        let low = self.cpu_read(loc);
+        let high = self.cpu_read(loc.wrapping_add(1));
+        ((high as u16) << 8) | low as u16
+    }
+
+    // A write by the CPU, taking one M-cycle
+    pub fn cpu_write(&mut self, loc: u16, val: u8) {
+        if !self.dma_blocks(loc) {
+            self.write(loc, val);
+        }
+        if !self.watchpoints.is_empty() {
+            self.watch(loc, val, true);
+        }
+        self.bus_cycle(loc, val, BusAccess::Write);
+    }
+
+    pub fn cpu_write_16(&mut self, loc: u16, val: u16) {
+        self.cpu_write(loc, val as u8);
+        self.cpu_write(loc.wrapping_add(1), (val >> 8) as u8);
+    }
+
+    // An M-cycle where the CPU doesn't use the bus
+    pub fn cpu_idle(&mut self) {
+        let (address, data) = self.last_access;
+        self.bus_cycle(address, data, BusAccess::Idle);
+    }
+
+    // Total M-cycles taken by the CPU
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    // Records every bus cycle until take_trace is called
+    pub fn start_trace(&mut self) {
+        self.trace = Some(Vec::new());
+    }
+
+    pub fn take_trace(&mut self) -> Vec<BusCycle> {
+        self.trace.take().unwrap_or_default()
+    }
+
+    pub fn set_watchpoints(&mut self, watchpoints: &[Watchpoint]) {
+        self.watchpoints = watchpoints.iter().filter(|w| w.read || w.write).copied().collect();
+        self.watch_hit = None;
+    }
+
+    pub fn take_watch_hit(&mut self) -> Option<WatchHit> {
+        self.watch_hit.take()
+    }
+
+    fn watch(&mut self, address: u16, value: u8, write: bool) {
+        if self.watch_hit.is_some() {
+            return
+        }
+        let hit = self.watchpoints.iter().find(|w| w.contains(address) && if write { w.write } else { w.read });
+        if let Some(w) = hit {
+            self.watch_hit = Some(WatchHit { id: w.id, address, value, write });
+        }
+    }
+
+    pub fn set_ly_stub(&mut self, enabled: bool) {
+        self.ly_stub = enabled;
+    }
+
+    // The ROM bank mapped at loc, None outside the ROM
+    pub fn rom_bank(&self, loc: u16) -> Option<u16> {
+        self.cart.rom_bank(loc)
+    }
+
+    fn dma_blocks(&self, loc: u16) -> bool {
+        self.dma.active && loc < 0xFF00
+    }
+
+    // The access itself happens at the start of the M-cycle, then the rest of the machine catches up
+    fn bus_cycle(&mut self, address: u16, data: u8, access: BusAccess) {
+        if let Some(trace) = self.trace.as_mut() {
+            trace.push(BusCycle { address, data, access });
+        }
+        self.last_access = (address, data);
+        self.cycles += 1;
+        if !self.test_mode {
+            self.tick();
+        }
+    }
+
+    // Advances everything clocked alongside the CPU by one M-cycle
+    fn tick(&mut self) {
+        if self.timer.tick() {
+            self.mem[0xFF0F] |= 0b100;
+        }
+        if self.serial.tick() {
+            self.mem[0xFF0F] |= 0b1000;
+        }
+        self.cart.tick(1);
+        self.tick_dma();
+        let interrupts = self.lcd.tick(self.mem[0xFF40]);
+        self.mem[0xFF0F] |= interrupts;
+    }
+
+    fn tick_dma(&mut self) {
+        if self.dma.starting {
+            self.dma.starting = false;
+            self.dma.active = true;
+            return
+        }
+        if !self.dma.active {
+            return
+        }
+        let val = self.read(self.dma.source + self.dma.index);
+        self.mem[0xFE00 + self.dma.index as usize] = val;
+        self.dma.index += 1;
+        if self.dma.index == 0xA0 {
+            self.dma.active = false;
+        }
+    }
+
     pub fn simulate_bootloader(&mut self) {
         self.write(0xff00, 0xcf);
         self.write(0xff01, 0x00);
         self.write(0xff02, 0x7e);
-        self.write(0xff04, 0xab);
-        self.write(0xff05, 0x00);
-        self.write(0xff06, 0x00);
-        self.write(0xff07, 0xf8);
+        self.timer.simulate_bootloader();
         self.write(0xff0f, 0xe1);
-        self.write(0xff10, 0x80);
-        self.write(0xff11, 0xbf);
-        self.write(0xff12, 0xf3);
-        self.write(0xff13, 0xff);
-        self.write(0xff14, 0xbf);
-        self.write(0xff16, 0x3f);
-        self.write(0xff17, 0x00);
-        self.write(0xff18, 0xff);
-        self.write(0xff19, 0xbf);
-        self.write(0xff1a, 0x7f);
-        self.write(0xff1b, 0xff);
-        self.write(0xff1c, 0x9f);
-        self.write(0xff1d, 0xff);
-        self.write(0xff1e, 0xbf);
-        self.write(0xff20, 0xff);
-        self.write(0xff21, 0x00);
-        self.write(0xff22, 0x00);
-        self.write(0xff23, 0xbf);
-        self.write(0xff24, 0x77);
-        self.write(0xff25, 0xf3);
-        self.write(0xff26, 0xf1);
+        self.apu.simulate_bootloader();
         self.write(0xff40, 0x91);
         self.write(0xff41, 0x86);
         self.write(0xff42, 0x00);
         self.write(0xff43, 0x00);
-        self.write(0xff44, 0x00);
         self.write(0xff45, 0x00);
-        self.write(0xff46, 0xff); // ??
+        // The boot ROM doesn't run a DMA, the register just reads 0xFF
+        self.mem[0xff46] = 0xff;
         self.write(0xff47, 0xfc);
         self.write(0xff4a, 0x00);
         self.write(0xff4b, 0x00);
-        self.write(0xff4d, 0xff);
         self.write(0xff4f, 0xff);
         self.write(0xff51, 0xff);
         self.write(0xff52, 0xff);
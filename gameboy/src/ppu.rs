@@ -1,6 +1,7 @@
 use wasm_bindgen::Clamped;
 use web_sys::{CanvasRenderingContext2d, ImageData, console};
 use crate::memory::Memory;
+use crate::savestate::{StateError, StateReader, StateWriter};
 
 
 const SCREEN_HEIGHT: usize = 144;
@@ -17,29 +18,26 @@ impl PPU {
             window_counter: 0}
     }
 
-    pub fn advance_line(&mut self, mem: &mut Memory) {
-        // println!("Advance");
-        let mut ly = mem.read(0xff44);
+    pub fn write_state(&self, w: &mut StateWriter) {
+        w.u8(self.window_counter);
+        w.bytes(&self.screen);
+    }
+
+    pub fn read_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.window_counter = r.u8()?;
+        r.bytes(&mut self.screen)
+    }
+
+    // Draws line ly once the LCD has finished with it
+    pub fn render_line(&mut self, mem: &mut Memory, ly: u8) {
         let lcdc = mem.read(0xff40);
-        //Draw the line
+        if ly == 0 {
+            self.window_counter = 0;
+        }
         if ly < 144 {
-            // If new graphics, parse them
-            // if mem.new_graphics {
-            //     // self.prepare_tile_map(mem);
-            //     self.tile_cache.clear();
-            //     // self.prepare_bg(mem);
-            //     // self.draw_bg_tilemap(mem, bg_ctx);
-            //     mem.new_graphics = false;
-            // }
-
             self.draw_background_line(mem, ly, lcdc);
             self.draw_sprite_line(mem, ly, lcdc);
-        } else {
-            self.window_counter = 0;
         }
-
-        ly = (ly + 1) % 154;
-        mem.write(0xff44, ly);
     }
 
     // Gets the tile with index tile_index. Uses caching since tiles are usually used multiple times without changing
@@ -55,11 +53,9 @@ impl PPU {
         let mut tile = Tile::new();
         for x in 0..8 {
             let addr = 0x8000 + (tile_index as u16*16) + (x*2);
-            println!("addr: {:#x}", addr);
             let a = mem.read(addr);
             let b = mem.read(addr + 1);
             let row = PPU::count_bits(a, b);
-            println!("{:?}", row);
             for (j, n) in row.iter().enumerate() {
                 tile.data[j + ((x as usize) * 8)] = *n;
                 // self.tile_map[i as usize].data[j + ((x as usize) * 8)] = *n;
@@ -0,0 +1,317 @@
+use std::collections::VecDeque;
+
+// Ring buffer of save states used to rewind gameplay.
+// Only the newest snapshot is kept whole. Every older snapshot is stored as the difference to the
+// snapshot after it: the two states XOR'ed together, with the runs of zero bytes (unchanged memory)
+// run-length encoded. Going back one step decodes the newest delta onto the newest snapshot.
+// When the buffer is over its memory budget the oldest deltas are dropped
+pub struct Rewind {
+    budget: usize,
+    interval: u32,
+    countdown: u32,
+    // Frames started since the newest snapshot was taken, 0 right after rewinding to it
+    ahead: u32,
+    latest: Option<Vec<u8>>,
+    deltas: VecDeque<Vec<u8>>,
+    used: usize,
+}
+
+impl Rewind {
+    pub fn new(budget: usize, interval: u32) -> Rewind {
+        Rewind {
+            budget,
+            interval: interval.max(1),
+            countdown: 0,
+            ahead: 0,
+            latest: None,
+            deltas: VecDeque::new(),
+            used: 0,
+        }
+    }
+
+    // Called once per frame before it runs, true when a snapshot should be taken
+    pub fn frame_due(&mut self) -> bool {
+        if self.budget == 0 {
+            return false
+        }
+        let due = self.countdown == 0;
+        if due {
+            self.countdown = self.interval;
+            self.ahead = 0;
+        }
+        self.ahead += 1;
+        self.countdown -= 1;
+        due
+    }
+
+    pub fn push(&mut self, state: Vec<u8>) {
+        if let Some(latest) = self.latest.take() {
+            let delta = encode_delta(&state, &latest);
+            self.used += delta.len();
+            self.used -= latest.len();
+            self.deltas.push_back(delta);
+        }
+        self.used += state.len();
+        self.latest = Some(state);
+
+        while self.used > self.budget {
+            match self.deltas.pop_front() {
+                Some(delta) => self.used -= delta.len(),
+                None => break
+            }
+        }
+    }
+
+    // Goes back at least the given number of frames from where the emulation is, limited by how far
+    // the buffer reaches. The returned state stays in the buffer as the newest snapshot, so the next
+    // call continues further back from it.
+    // Returns the state and the number of frames gone back, None if there is nothing to rewind to
+    pub fn rewind(&mut self, frames: u32) -> Option<(Vec<u8>, u32)> {
+        if frames == 0 || (self.ahead == 0 && self.deltas.is_empty()) {
+            return None
+        }
+        let mut state = self.latest.take()?;
+        self.used -= state.len();
+        let mut travelled = self.ahead;
+        while travelled < frames {
+            match self.deltas.pop_back() {
+                Some(delta) => {
+                    self.used -= delta.len();
+                    state = apply_delta(&state, &delta);
+                    travelled += self.interval;
+                },
+                None => break
+            }
+        }
+        self.used += state.len();
+        self.latest = Some(state.clone());
+        self.ahead = 0;
+        // The restored state is this frame's snapshot
+        self.countdown = self.interval;
+        Some((state, travelled))
+    }
+
+    // How many frames back the buffer reaches from where the emulation is
+    pub fn frames_available(&self) -> u32 {
+        match self.latest {
+            Some(_) => self.ahead + self.deltas.len() as u32 * self.interval,
+            None => 0
+        }
+    }
+
+    // Number of snapshots in the buffer
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        match self.latest {
+            Some(_) => self.deltas.len() + 1,
+            None => 0
+        }
+    }
+
+    pub fn memory_used(&self) -> usize {
+        self.used
+    }
+
+    // A budget of 0 disables rewinding
+    pub fn set_budget(&mut self, budget: usize) {
+        self.budget = budget;
+        if budget == 0 {
+            self.clear();
+            return
+        }
+        while self.used > self.budget {
+            match self.deltas.pop_front() {
+                Some(delta) => self.used -= delta.len(),
+                None => break
+            }
+        }
+    }
+
+    pub fn set_interval(&mut self, interval: u32) {
+        self.interval = interval.max(1);
+        self.countdown = self.countdown.min(self.interval);
+    }
+
+    pub fn clear(&mut self) {
+        self.latest = None;
+        self.deltas.clear();
+        self.used = 0;
+        self.countdown = 0;
+        self.ahead = 0;
+    }
+}
+
+// Encodes how to get `target` from `base`: the length of target, followed by
+// (zero run, literal count, literal bytes) groups of target XOR base
+fn encode_delta(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    write_varint(&mut out, target.len());
+
+    let xor = |i: usize| target[i] ^ base.get(i).copied().unwrap_or(0);
+    let mut i = 0;
+    while i < target.len() {
+        let zeros_start = i;
+        while i < target.len() && xor(i) == 0 {
+            i += 1;
+        }
+        if i == target.len() {
+            break
+        }
+        let literal_start = i;
+        // A single unchanged byte is cheaper to keep in the literal than to start a new group
+        while i < target.len() && (xor(i) != 0 || (i + 1 < target.len() && xor(i + 1) != 0)) {
+            i += 1;
+        }
+        write_varint(&mut out, literal_start - zeros_start);
+        write_varint(&mut out, i - literal_start);
+        out.extend((literal_start..i).map(xor));
+    }
+    out
+}
+
+fn apply_delta(base: &[u8], delta: &[u8]) -> Vec<u8> {
+    let mut pos = 0;
+    let len = read_varint(delta, &mut pos);
+    let mut out = base.to_vec();
+    out.resize(len, 0);
+
+    let mut i = 0;
+    while pos < delta.len() {
+        i += read_varint(delta, &mut pos);
+        let count = read_varint(delta, &mut pos);
+        for b in &delta[pos..pos + count] {
+            out[i] ^= b;
+            i += 1;
+        }
+        pos += count;
+    }
+    out
+}
+
+// LEB128
+fn write_varint(out: &mut Vec<u8>, mut val: usize) {
+    while val >= 0x80 {
+        out.push((val as u8 & 0x7F) | 0x80);
+        val >>= 7;
+    }
+    out.push(val as u8);
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> usize {
+    let mut val = 0;
+    let mut shift = 0;
+    loop {
+        let b = data[*pos];
+        *pos += 1;
+        val |= ((b & 0x7F) as usize) << shift;
+        if b & 0x80 == 0 {
+            return val
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod rewind_tests {
+    use crate::{debugger::BreakReason, header::test_rom, rewind::{apply_delta, encode_delta, Rewind}, save::MemoryStore, GameBoy};
+
+    #[test]
+    fn delta_round_trip() {
+        let base = vec![0u8; 1000];
+        let mut target = base.clone();
+        target[10] = 1;
+        target[11] = 2;
+        target[13] = 3;
+        target[999] = 4;
+
+        let delta = encode_delta(&base, &target);
+        assert!(delta.len() < 20);
+        assert_eq!(apply_delta(&base, &delta), target);
+        assert_eq!(apply_delta(&target, &encode_delta(&target, &base)), base);
+        assert_eq!(apply_delta(&base, &encode_delta(&base, &[7, 0, 7])), vec![7, 0, 7]);
+    }
+
+    #[test]
+    fn rewinds_in_order() {
+        let mut rewind = Rewind::new(1 << 20, 1);
+        for i in 0..10u8 {
+            assert!(rewind.frame_due());
+            rewind.push(vec![i; 100]);
+        }
+        assert_eq!(rewind.len(), 10);
+        assert_eq!(rewind.rewind(0), None);
+        // The newest snapshot is the start of the frame that ran last, every call goes further back
+        assert_eq!(rewind.rewind(1), Some((vec![9; 100], 1)));
+        assert_eq!(rewind.rewind(1), Some((vec![8; 100], 1)));
+        assert_eq!(rewind.rewind(3), Some((vec![5; 100], 3)));
+        assert_eq!(rewind.len(), 6);
+        assert_eq!(rewind.frames_available(), 5);
+        assert_eq!(rewind.rewind(100), Some((vec![0; 100], 5)));
+        assert_eq!(rewind.len(), 1);
+        assert_eq!(rewind.rewind(1), None);
+    }
+
+    #[test]
+    fn budget_drops_oldest() {
+        let mut rewind = Rewind::new(220, 1);
+        for i in 0..10u8 {
+            let mut state = vec![0; 200];
+            state[i as usize] = i + 1;
+            rewind.push(state);
+        }
+        assert!(rewind.memory_used() <= 220);
+        assert!(rewind.len() < 10);
+        let n = rewind.len();
+        let (oldest, _) = rewind.rewind(n as u32).unwrap();
+        assert_eq!(oldest[10 - n], (11 - n) as u8);
+    }
+
+    #[test]
+    fn interval_spaces_snapshots() {
+        let mut rewind = Rewind::new(1 << 20, 3);
+        let due: Vec<bool> = (0..7).map(|_| rewind.frame_due()).collect();
+        assert_eq!(due, vec![true, false, false, true, false, false, true]);
+    }
+
+    #[test]
+    fn debugger_steps_are_not_frames() {
+        let mut gb = GameBoy::from_rom(test_rom(0, 0, 0), "test".to_string(), Box::new(MemoryStore::new())).unwrap();
+        gb.start();
+        assert_eq!(gb.rewind_available(), 0);
+        gb.set_rewind(1 << 20, 1);
+        for _ in 0..10 {
+            assert_eq!(gb.step_into(), BreakReason::Step);
+        }
+        assert_eq!(gb.rewind_available(), 1);
+        assert_eq!(gb.run(), BreakReason::Frame);
+        assert_eq!(gb.rewind_available(), 1);
+        gb.run();
+        assert_eq!(gb.rewind_available(), 2);
+    }
+
+    #[test]
+    fn repeated_rewinds_go_further_back() {
+        let mut gb = GameBoy::from_rom(test_rom(0, 0, 0), "test".to_string(), Box::new(MemoryStore::new())).unwrap();
+        gb.start();
+        gb.set_rewind(1 << 20, 1);
+        let mut starts = Vec::new();
+        for _ in 0..5 {
+            starts.push(gb.save_state());
+            gb.run();
+        }
+        assert_eq!(gb.rewind_available(), 5);
+        assert_eq!(gb.rewind_frames(0), 0);
+
+        for expected in (0..5).rev() {
+            assert_eq!(gb.rewind_frames(1), 1);
+            assert_eq!(gb.save_state(), starts[expected]);
+            assert_eq!(gb.rewind_available(), expected as u32);
+        }
+        assert_eq!(gb.rewind_frames(1), 0);
+
+        // Running again continues from the restored frame
+        gb.run();
+        assert_eq!(gb.rewind_frames(1), 1);
+        assert_eq!(gb.save_state(), starts[0]);
+    }
+}
@@ -0,0 +1,296 @@
+// Real-time clock found in MBC3 cartridges with a timer (header types 0x0F and 0x10)
+// Register layout source: Pandocs
+//   0x08  Seconds   0-59
+//   0x09  Minutes   0-59
+//   0x0A  Hours     0-23
+//   0x0B  Day counter, lower 8 bits
+//   0x0C  Bit 0: Day counter bit 8, Bit 6: Halt, Bit 7: Day counter carry
+
+use std::convert::TryInto;
+
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+// The CPU runs at 4194304 Hz, which is 1048576 M-cycles per second
+const CYCLES_PER_SECOND: u32 = 1048576;
+
+// Size of the RTC footer appended to battery RAM, as written by VBA-M, BGB and mGBA.
+// The 44 byte variant stores a 32-bit timestamp instead of a 64-bit one
+pub const FOOTER_SIZE: usize = 48;
+pub const FOOTER_SIZE_32: usize = 44;
+
+pub struct Rtc {
+    seconds: u8,
+    minutes: u8,
+    hours: u8,
+    days: u16,
+    halt: bool,
+    carry: bool,
+
+    latched: [u8; 5],
+    latch_armed: bool,
+
+    cycles: u32,
+    // Host time in seconds of the last sync, used to catch up on time passed while the game was off
+    timestamp: u64,
+    host_clock: bool,
+}
+
+impl Rtc {
+    pub fn new() -> Rtc {
+        Rtc {
+            seconds: 0,
+            minutes: 0,
+            hours: 0,
+            days: 0,
+            halt: false,
+            carry: false,
+            latched: [0; 5],
+            latch_armed: false,
+            cycles: 0,
+            timestamp: unix_time(),
+            host_clock: false,
+        }
+    }
+
+    pub fn read(&self, reg: u8) -> u8 {
+        match reg {
+            0x08..=0x0C => self.latched[(reg - 0x08) as usize],
+            _ => 0xFF
+        }
+    }
+
+    pub fn write(&mut self, reg: u8, val: u8) {
+        match reg {
+            0x08 => {
+                self.seconds = val & 0x3F;
+                self.cycles = 0;
+            },
+            0x09 => self.minutes = val & 0x3F,
+            0x0A => self.hours = val & 0x1F,
+            0x0B => self.days = (self.days & 0x100) | val as u16,
+            0x0C => {
+                self.days = (self.days & 0xFF) | ((val as u16 & 0x01) << 8);
+                self.halt = val & 0b1000000 > 0;
+                self.carry = val & 0b10000000 > 0;
+            },
+            _ => return
+        }
+        self.latched[(reg - 0x08) as usize] = self.register(reg);
+    }
+
+    // Writing 0x00 followed by 0x01 to 0x6000-0x7FFF copies the running clock into the readable registers
+    pub fn write_latch(&mut self, val: u8) {
+        if self.latch_armed && val == 0x01 {
+            for reg in 0x08..=0x0C {
+                self.latched[(reg - 0x08) as usize] = self.register(reg);
+            }
+        }
+        self.latch_armed = val == 0x00;
+    }
+
+    // Advances the clock by the given number of emulated M-cycles. When following the host clock
+    // the cycles are only used to decide how often to check the host time
+    pub fn tick(&mut self, cycles: u8) {
+        self.cycles += cycles as u32;
+        if self.cycles < CYCLES_PER_SECOND {
+            return
+        }
+        self.cycles -= CYCLES_PER_SECOND;
+
+        if self.host_clock {
+            self.sync(unix_time());
+        } else {
+            self.advance(1);
+        }
+    }
+
+    // Catches up with the host clock
+    pub fn sync(&mut self, now: u64) {
+        if now > self.timestamp {
+            self.advance(now - self.timestamp);
+        }
+        self.timestamp = now;
+    }
+
+    pub fn set_host_clock(&mut self, enabled: bool) {
+        if enabled && !self.host_clock {
+            self.timestamp = unix_time();
+        }
+        self.host_clock = enabled;
+    }
+
+    fn advance(&mut self, seconds: u64) {
+        if self.halt {
+            return
+        }
+
+        let total = self.seconds as u64
+            + self.minutes as u64 * 60
+            + self.hours as u64 * 3600
+            + self.days as u64 * 86400
+            + seconds;
+
+        self.seconds = (total % 60) as u8;
+        self.minutes = (total / 60 % 60) as u8;
+        self.hours = (total / 3600 % 24) as u8;
+        let days = total / 86400;
+        if days > 0x1FF {
+            self.carry = true;
+        }
+        self.days = (days % 0x200) as u16;
+    }
+
+    fn register(&self, reg: u8) -> u8 {
+        match reg {
+            0x08 => self.seconds,
+            0x09 => self.minutes,
+            0x0A => self.hours,
+            0x0B => (self.days & 0xFF) as u8,
+            0x0C => ((self.days >> 8) as u8 & 0x01) | ((self.halt as u8) << 6) | ((self.carry as u8) << 7),
+            _ => 0xFF
+        }
+    }
+
+    pub fn write_state(&self, w: &mut StateWriter) {
+        w.u8(self.seconds);
+        w.u8(self.minutes);
+        w.u8(self.hours);
+        w.u16(self.days);
+        w.bool(self.halt);
+        w.bool(self.carry);
+        w.bytes(&self.latched);
+        w.bool(self.latch_armed);
+        w.u32(self.cycles);
+        w.u64(self.timestamp);
+    }
+
+    // The host clock setting belongs to the frontend and is kept as is
+    pub fn read_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.seconds = r.u8()?;
+        self.minutes = r.u8()?;
+        self.hours = r.u8()?;
+        self.days = r.u16()?;
+        self.halt = r.bool()?;
+        self.carry = r.bool()?;
+        r.bytes(&mut self.latched)?;
+        self.latch_armed = r.bool()?;
+        self.cycles = r.u32()?;
+        self.timestamp = r.u64()?;
+        if self.host_clock {
+            self.sync(unix_time());
+        }
+        Ok(())
+    }
+
+    // Serializes the clock as the footer stored after battery RAM in .sav files:
+    // five 32-bit registers, five 32-bit latched registers and a 64-bit unix timestamp, all little-endian
+    pub fn to_footer(&self) -> [u8; FOOTER_SIZE] {
+        let mut footer = [0u8; FOOTER_SIZE];
+        for i in 0..5 {
+            footer[i * 4] = self.register(0x08 + i as u8);
+            footer[20 + i * 4] = self.latched[i];
+        }
+        let now = if self.host_clock { self.timestamp } else { unix_time() };
+        footer[40..48].copy_from_slice(&now.to_le_bytes());
+        footer
+    }
+
+    pub fn from_footer(footer: &[u8]) -> Option<Rtc> {
+        let timestamp = match footer.len() {
+            FOOTER_SIZE => u64::from_le_bytes(footer[40..48].try_into().ok()?),
+            FOOTER_SIZE_32 => u32::from_le_bytes(footer[40..44].try_into().ok()?) as u64,
+            _ => return None
+        };
+
+        let mut rtc = Rtc::new();
+        for i in 0..5 {
+            rtc.write(0x08 + i as u8, footer[i * 4]);
+        }
+        for i in 0..5 {
+            rtc.latched[i] = footer[20 + i * 4];
+        }
+        rtc.timestamp = timestamp;
+        Some(rtc)
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+pub fn unix_time() -> u64 {
+    (js_sys::Date::now() / 1000.0) as u64
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn unix_time() -> u64 {
+    use std::time::{SystemTime, UNIX_EPOCH};
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod rtc_tests {
+    use crate::rtc::{Rtc, CYCLES_PER_SECOND};
+
+    #[test]
+    fn latch_freezes_registers() {
+        let mut rtc = Rtc::new();
+        rtc.write(0x08, 58);
+        rtc.write_latch(0);
+        rtc.write_latch(1);
+        for _ in 0..(2 * CYCLES_PER_SECOND / 128) {
+            rtc.tick(128);
+        }
+
+        assert_eq!(rtc.read(0x08), 58);
+        rtc.write_latch(0);
+        rtc.write_latch(1);
+        assert_eq!(rtc.read(0x08), 0);
+        assert_eq!(rtc.read(0x09), 1);
+    }
+
+    #[test]
+    fn day_counter_overflow_sets_carry() {
+        let mut rtc = Rtc::new();
+        rtc.write(0x0B, 0xFF);
+        rtc.write(0x0C, 0x01);
+        rtc.write(0x0A, 23);
+        rtc.write(0x09, 59);
+        rtc.write(0x08, 59);
+        rtc.advance(1);
+        rtc.write_latch(0);
+        rtc.write_latch(1);
+
+        assert_eq!(rtc.read(0x0B), 0);
+        assert_eq!(rtc.read(0x0C), 0b10000000);
+    }
+
+    #[test]
+    fn halt_stops_clock() {
+        let mut rtc = Rtc::new();
+        rtc.write(0x0C, 0b1000000);
+        rtc.advance(100);
+        rtc.write_latch(0);
+        rtc.write_latch(1);
+
+        assert_eq!(rtc.read(0x08), 0);
+    }
+
+    #[test]
+    fn footer_round_trip() {
+        let mut rtc = Rtc::new();
+        rtc.write(0x08, 12);
+        rtc.write(0x09, 34);
+        rtc.write(0x0A, 5);
+        rtc.write(0x0B, 0x42);
+        rtc.timestamp = 1000;
+        rtc.host_clock = true;
+
+        let restored = Rtc::from_footer(&rtc.to_footer()).unwrap();
+        assert_eq!(restored.register(0x08), 12);
+        assert_eq!(restored.register(0x09), 34);
+        assert_eq!(restored.register(0x0A), 5);
+        assert_eq!(restored.register(0x0B), 0x42);
+        assert_eq!(restored.timestamp, 1000);
+
+        assert!(Rtc::from_footer(&rtc.to_footer()[..44]).is_some());
+        assert!(Rtc::from_footer(&[0; 10]).is_none());
+    }
+}
@@ -1,32 +1,159 @@
+use std::{collections::HashMap, error::Error, fmt};
+
 use base64::{engine::general_purpose, Engine as _};
+use wasm_bindgen::JsValue;
+
+// Persists battery RAM between sessions. Keys are the game name given to GameBoy::new
+pub trait SaveStore {
+    fn load(&self, key: &str) -> Option<Vec<u8>>;
+    fn store(&mut self, key: &str, data: &[u8]) -> Result<(), SaveError>;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SaveError(pub String);
+
+impl fmt::Display for SaveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Could not store save data: {}", self.0)
+    }
+}
+
+impl Error for SaveError {}
+
+// Browser localStorage, with the data stored as a base64 string
+pub struct LocalStorageStore;
+
+impl LocalStorageStore {
+    fn storage() -> Option<web_sys::Storage> {
+        web_sys::window()?.local_storage().ok()?
+    }
+}
+
+impl SaveStore for LocalStorageStore {
+    fn load(&self, key: &str) -> Option<Vec<u8>> {
+        let value = LocalStorageStore::storage()?.get_item(key).ok()??;
+        general_purpose::STANDARD.decode(value).ok()
+    }
+
+    fn store(&mut self, key: &str, data: &[u8]) -> Result<(), SaveError> {
+        let storage = LocalStorageStore::storage().ok_or_else(|| SaveError("localStorage is not available".to_string()))?;
+        let s = general_purpose::STANDARD.encode(data);
+        storage.set_item(key, &s).map_err(|e| SaveError(format!("{:?}", e)))
+    }
+}
+
+// Hands the raw bytes to JS functions, so the frontend can keep saves in IndexedDB or anywhere else.
+// load(key) must synchronously return a Uint8Array, or null/undefined if there is no save,
+// store(key, Uint8Array) may persist asynchronously
+pub struct CallbackStore {
+    load: js_sys::Function,
+    store: js_sys::Function,
+}
+
+impl CallbackStore {
+    pub fn new(load: js_sys::Function, store: js_sys::Function) -> CallbackStore {
+        CallbackStore { load, store }
+    }
+}
+
+impl SaveStore for CallbackStore {
+    fn load(&self, key: &str) -> Option<Vec<u8>> {
+        let value = self.load.call1(&JsValue::NULL, &JsValue::from_str(key)).ok()?;
+        if value.is_null() || value.is_undefined() {
+            return None
+        }
+        Some(js_sys::Uint8Array::new(&value).to_vec())
+    }
+
+    fn store(&mut self, key: &str, data: &[u8]) -> Result<(), SaveError> {
+        let bytes = js_sys::Uint8Array::from(data);
+        self.store.call2(&JsValue::NULL, &JsValue::from_str(key), &bytes)
+            .map(|_| ())
+            .map_err(|e| SaveError(format!("{:?}", e)))
+    }
+}
 
-pub fn set_item(name: &str, data: &Vec<u8>) -> Result<(), wasm_bindgen::JsValue> {
-    let window = web_sys::window().unwrap();
-    let local_storage = window.local_storage().unwrap().unwrap();
-
-    let s = general_purpose::STANDARD.encode(data);
-    local_storage.set_item(name, &s)
-}
-
-pub fn get_item(name: &str) -> Option<Vec<u8>> {
-    let window = web_sys::window().unwrap();
-    let local_storage = window.local_storage().unwrap().unwrap();
-
-    let value = match local_storage.get_item(name) {
-        Ok(val) => val,
-        Err(_) => None,
-    };
-
-    let data = match value {
-        Some(s) => {
-            let val = general_purpose::STANDARD.decode(s);
-            match val {
-                Ok(x) => Some(x),
-                Err(_) => None,
-            }
-        },
-        None => None,
-    };
-    
-    return data
-}
\ No newline at end of file
+// Keeps saves in memory only, used by tests and the test mode cartridge
+#[derive(Default)]
+pub struct MemoryStore {
+    saves: HashMap<String, Vec<u8>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+
+    pub fn with_save(key: &str, data: Vec<u8>) -> MemoryStore {
+        let mut store = MemoryStore::new();
+        store.saves.insert(key.to_string(), data);
+        store
+    }
+}
+
+impl SaveStore for MemoryStore {
+    fn load(&self, key: &str) -> Option<Vec<u8>> {
+        self.saves.get(key).cloned()
+    }
+
+    fn store(&mut self, key: &str, data: &[u8]) -> Result<(), SaveError> {
+        self.saves.insert(key.to_string(), data.to_vec());
+        Ok(())
+    }
+}
+
+// Writes <dir>/<key>.sav for native builds
+#[cfg(not(target_arch = "wasm32"))]
+pub struct FileStore {
+    dir: std::path::PathBuf,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl FileStore {
+    pub fn new<P: Into<std::path::PathBuf>>(dir: P) -> FileStore {
+        FileStore { dir: dir.into() }
+    }
+
+    fn path(&self, key: &str) -> std::path::PathBuf {
+        // Game names come from the user, so keep them from escaping the save directory
+        let file_name: String = key.chars()
+            .map(|c| if c == '/' || c == '\\' || c == ':' { '_' } else { c })
+            .collect();
+        self.dir.join(format!("{}.sav", file_name))
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl SaveStore for FileStore {
+    fn load(&self, key: &str) -> Option<Vec<u8>> {
+        std::fs::read(self.path(key)).ok()
+    }
+
+    fn store(&mut self, key: &str, data: &[u8]) -> Result<(), SaveError> {
+        std::fs::create_dir_all(&self.dir).map_err(|e| SaveError(e.to_string()))?;
+        std::fs::write(self.path(key), data).map_err(|e| SaveError(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod save_tests {
+    use crate::save::{FileStore, MemoryStore, SaveStore};
+
+    #[test]
+    fn memory_store_round_trip() {
+        let mut store = MemoryStore::new();
+        assert_eq!(store.load("game"), None);
+        store.store("game", &[1, 2, 3]).unwrap();
+        assert_eq!(store.load("game"), Some(vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn file_store_round_trip() {
+        let dir = std::env::temp_dir().join(format!("gameboy-save-test-{}", std::process::id()));
+        let mut store = FileStore::new(&dir);
+        store.store("../zelda", &[4, 5, 6]).unwrap();
+        assert_eq!(store.load("../zelda"), Some(vec![4, 5, 6]));
+        assert!(dir.join(".._zelda.sav").exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
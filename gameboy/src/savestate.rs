@@ -0,0 +1,230 @@
+use std::{convert::TryInto, error::Error, fmt};
+
+// Save state layout:
+//   0x00  Magic "GBSS"
+//   0x04  Format version, u16
+//   0x06  Hash of the ROM the state was taken from, u64
+//   0x0E  Component data in the order CPU, Memory, PPU
+// All values are little-endian. The version must be bumped whenever a component changes what it writes
+const MAGIC: &[u8; 4] = b"GBSS";
+pub const VERSION: u16 = 7;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StateError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    RomMismatch,
+    Truncated,
+    Corrupt(&'static str),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StateError::BadMagic => write!(f, "Not a save state"),
+            StateError::UnsupportedVersion(v) => write!(f, "Unsupported save state version {} (expected {})", v, VERSION),
+            StateError::RomMismatch => write!(f, "Save state was made with a different ROM"),
+            StateError::Truncated => write!(f, "Save state is truncated"),
+            StateError::Corrupt(what) => write!(f, "Save state is corrupt: {}", what),
+        }
+    }
+}
+
+impl Error for StateError {}
+
+pub struct StateWriter {
+    buf: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new(rom_hash: u64) -> StateWriter {
+        let mut w = StateWriter { buf: Vec::new() };
+        w.bytes(MAGIC);
+        w.u16(VERSION);
+        w.u64(rom_hash);
+        w
+    }
+
+    pub fn u8(&mut self, val: u8) {
+        self.buf.push(val);
+    }
+
+    pub fn bool(&mut self, val: bool) {
+        self.buf.push(val as u8);
+    }
+
+    pub fn u16(&mut self, val: u16) {
+        self.buf.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, val: u32) {
+        self.buf.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn i32(&mut self, val: i32) {
+        self.buf.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, val: u64) {
+        self.buf.extend_from_slice(&val.to_le_bytes());
+    }
+
+    // Fixed size data, the reader must know the length
+    pub fn bytes(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    // Variable size data, prefixed with its length
+    pub fn vec(&mut self, data: &[u8]) {
+        self.u32(data.len() as u32);
+        self.bytes(data);
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    // Checks the header and positions the reader at the first component
+    pub fn new(data: &'a [u8], rom_hash: u64) -> Result<StateReader<'a>, StateError> {
+        let mut r = StateReader { data, pos: 0 };
+        if r.take(4).map_err(|_| StateError::BadMagic)? != MAGIC {
+            return Err(StateError::BadMagic);
+        }
+        let version = r.u16()?;
+        if version != VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+        if r.u64()? != rom_hash {
+            return Err(StateError::RomMismatch);
+        }
+        Ok(r)
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        let end = self.pos.checked_add(len).filter(|&end| end <= self.data.len()).ok_or(StateError::Truncated)?;
+        let slice = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(slice)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, StateError> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> Result<u16, StateError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, StateError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn i32(&mut self) -> Result<i32, StateError> {
+        Ok(i32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, StateError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn bytes(&mut self, out: &mut [u8]) -> Result<(), StateError> {
+        out.copy_from_slice(self.take(out.len())?);
+        Ok(())
+    }
+
+    pub fn vec(&mut self) -> Result<Vec<u8>, StateError> {
+        let len = self.u32()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    // Trailing data means the state doesn't match what the components expect
+    pub fn finish(self) -> Result<(), StateError> {
+        if self.pos != self.data.len() {
+            return Err(StateError::Corrupt("trailing data"));
+        }
+        Ok(())
+    }
+}
+
+// 64-bit FNV-1a, used to tie a save state to the ROM it was made with
+pub fn rom_hash(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325u64, |hash, b| (hash ^ *b as u64).wrapping_mul(0x100000001b3))
+}
+
+#[cfg(test)]
+mod savestate_tests {
+    use crate::{header::test_rom, save::MemoryStore, savestate::{StateError, StateReader, StateWriter}, GameBoy};
+
+    fn game_boy() -> GameBoy {
+        GameBoy::from_rom(test_rom(0x03, 0x00, 0x02), "test".to_string(), Box::new(MemoryStore::new())).unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut w = StateWriter::new(42);
+        w.u8(1);
+        w.bool(true);
+        w.u16(0x1234);
+        w.i32(-5);
+        w.vec(&[9, 8, 7]);
+        let data = w.finish();
+
+        let mut r = StateReader::new(&data, 42).unwrap();
+        assert_eq!(r.u8(), Ok(1));
+        assert_eq!(r.bool(), Ok(true));
+        assert_eq!(r.u16(), Ok(0x1234));
+        assert_eq!(r.i32(), Ok(-5));
+        assert_eq!(r.vec(), Ok(vec![9, 8, 7]));
+        assert_eq!(r.u8(), Err(StateError::Truncated));
+        assert!(r.finish().is_ok());
+    }
+
+    #[test]
+    fn rejects_bad_header() {
+        let data = StateWriter::new(42).finish();
+        assert_eq!(StateReader::new(&data, 43).err(), Some(StateError::RomMismatch));
+        assert_eq!(StateReader::new(b"nope", 42).err(), Some(StateError::BadMagic));
+
+        let mut data = data;
+        data[4] = 0xFF;
+        assert!(matches!(StateReader::new(&data, 42).err(), Some(StateError::UnsupportedVersion(_))));
+    }
+
+    #[test]
+    fn game_boy_round_trip() {
+        let mut gb = game_boy();
+        gb.start();
+        for _ in 0..100 {
+            gb.step();
+        }
+        let state = gb.save_state();
+
+        for _ in 0..100 {
+            gb.step();
+        }
+        assert_ne!(gb.save_state(), state);
+        gb.try_load_state(&state).unwrap();
+        assert_eq!(gb.save_state(), state);
+    }
+
+    #[test]
+    fn failed_load_keeps_running_state() {
+        let mut gb = game_boy();
+        gb.start();
+        let state = gb.save_state();
+
+        assert_eq!(gb.try_load_state(&state[..state.len() - 1]), Err(StateError::Truncated));
+        assert_eq!(gb.save_state(), state);
+    }
+}
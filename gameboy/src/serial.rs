@@ -0,0 +1,246 @@
+// Serial port, 0xFF01-0xFF02. Behaviour source: Pandocs
+//   0xFF01  SB, the byte being shifted out, most significant bit first, while the received bits shift in
+//   0xFF02  SC, Bit 7 transfer start/in progress, Bit 0 clock select (1 = internal)
+// With the internal clock a bit is shifted every 128 M-cycles (8192 Hz). With the external clock the
+// transfer waits for the other Game Boy to clock it. Either way the serial interrupt is requested
+// when all 8 bits have been shifted
+
+use std::{cell::RefCell, rc::Rc};
+
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+const CYCLES_PER_BIT: u16 = 128;
+// Bytes sent are kept for test ROMs that print their results over serial, up to this many
+const OUTPUT_LIMIT: usize = 64 * 1024;
+
+// The other end of the link cable
+pub trait LinkPort {
+    // Makes the byte this side would shift out visible to the other side.
+    // ready is true while this side waits for a transfer on the external clock
+    fn publish(&mut self, out: u8, ready: bool);
+    // Called when this side starts a transfer on the internal clock. Returns the byte the other side
+    // shifts out, or None if it isn't waiting for a transfer
+    fn exchange(&mut self, out: u8) -> Option<u8>;
+    // Called while waiting on the external clock, returns the byte clocked in by the other side
+    fn receive(&mut self) -> Option<u8>;
+}
+
+#[derive(Default)]
+struct Wire {
+    ready: Option<u8>,
+    incoming: Option<u8>,
+}
+
+// Connects two Game Boys running in the same process
+pub struct LinkCable {
+    wires: Rc<RefCell<[Wire; 2]>>,
+    side: usize,
+}
+
+impl LinkCable {
+    pub fn pair() -> (LinkCable, LinkCable) {
+        let wires = Rc::new(RefCell::new(Default::default()));
+        (LinkCable { wires: wires.clone(), side: 0 }, LinkCable { wires, side: 1 })
+    }
+}
+
+impl LinkPort for LinkCable {
+    fn publish(&mut self, out: u8, ready: bool) {
+        self.wires.borrow_mut()[self.side].ready = if ready { Some(out) } else { None };
+    }
+
+    fn exchange(&mut self, out: u8) -> Option<u8> {
+        let mut wires = self.wires.borrow_mut();
+        let peer = &mut wires[1 - self.side];
+        let answer = peer.ready.take()?;
+        peer.incoming = Some(out);
+        Some(answer)
+    }
+
+    fn receive(&mut self) -> Option<u8> {
+        self.wires.borrow_mut()[self.side].incoming.take()
+    }
+}
+
+pub struct Serial {
+    sb: u8,
+    sc: u8,
+    cycles: u16,
+    bits: u8,
+    incoming: u8,
+    output: Vec<u8>,
+    port: Option<Box<dyn LinkPort>>,
+}
+
+impl Default for Serial {
+    fn default() -> Self {
+        Serial::new()
+    }
+}
+
+impl Serial {
+    pub fn new() -> Serial {
+        Serial {
+            sb: 0,
+            sc: 0,
+            cycles: 0,
+            bits: 0,
+            incoming: 0xFF,
+            output: Vec::new(),
+            port: None,
+        }
+    }
+
+    pub fn connect(&mut self, port: Box<dyn LinkPort>) {
+        self.port = Some(port);
+        self.publish();
+    }
+
+    pub fn disconnect(&mut self) {
+        self.port = None;
+    }
+
+    pub fn read(&self, loc: u16) -> u8 {
+        match loc {
+            0xFF01 => self.sb,
+            0xFF02 => self.sc | 0x7E,
+            _ => 0xFF
+        }
+    }
+
+    pub fn write(&mut self, loc: u16, val: u8) {
+        match loc {
+            0xFF01 => self.sb = val,
+            0xFF02 => {
+                self.sc = val & 0x81;
+                if self.transferring() && self.internal_clock() {
+                    self.start_transfer();
+                }
+            },
+            _ => return
+        }
+        self.publish();
+    }
+
+    fn transferring(&self) -> bool {
+        self.sc & 0x80 > 0
+    }
+
+    fn internal_clock(&self) -> bool {
+        self.sc & 0x01 > 0
+    }
+
+    fn publish(&mut self) {
+        let ready = self.transferring() && !self.internal_clock();
+        let sb = self.sb;
+        if let Some(port) = self.port.as_mut() {
+            port.publish(sb, ready);
+        }
+    }
+
+    // The byte is exchanged up front and shifted in bit by bit. Nobody on the other end reads as 0xFF
+    fn start_transfer(&mut self) {
+        self.cycles = 0;
+        self.bits = 0;
+        let sb = self.sb;
+        self.incoming = self.port.as_mut().and_then(|port| port.exchange(sb)).unwrap_or(0xFF);
+        if self.output.len() >= OUTPUT_LIMIT {
+            self.output.drain(..OUTPUT_LIMIT / 2);
+        }
+        self.output.push(sb);
+    }
+
+    // Advances one M-cycle, returns true when the serial interrupt should be requested
+    pub fn tick(&mut self) -> bool {
+        if !self.transferring() {
+            return false
+        }
+
+        if !self.internal_clock() {
+            let received = self.port.as_mut().and_then(|port| port.receive());
+            return match received {
+                Some(byte) => {
+                    self.sb = byte;
+                    self.sc &= 0x7F;
+                    true
+                },
+                None => false
+            }
+        }
+
+        self.cycles += 1;
+        if self.cycles < CYCLES_PER_BIT {
+            return false
+        }
+        self.cycles = 0;
+        self.sb = (self.sb << 1) | ((self.incoming >> (7 - self.bits)) & 0x01);
+        self.bits += 1;
+        if self.bits < 8 {
+            return false
+        }
+        self.sc &= 0x7F;
+        true
+    }
+
+    // Bytes sent since the last call
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+
+    pub fn write_state(&self, w: &mut StateWriter) {
+        w.u8(self.sb);
+        w.u8(self.sc);
+        w.u16(self.cycles);
+        w.u8(self.bits);
+        w.u8(self.incoming);
+    }
+
+    pub fn read_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.sb = r.u8()?;
+        self.sc = r.u8()? & 0x81;
+        self.cycles = r.u16()?;
+        self.bits = r.u8()? % 8;
+        self.incoming = r.u8()?;
+        self.publish();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod serial_tests {
+    use crate::serial::{LinkCable, Serial};
+
+    #[test]
+    fn transfer_without_partner_reads_ff() {
+        let mut serial = Serial::new();
+        serial.write(0xFF01, b'A');
+        serial.write(0xFF02, 0x81);
+
+        let cycles = (0..2000).position(|_| serial.tick()).unwrap();
+        assert_eq!(cycles + 1, 8 * 128);
+        assert_eq!(serial.read(0xFF01), 0xFF);
+        assert_eq!(serial.read(0xFF02), 0x7F);
+        assert_eq!(serial.take_output(), vec![b'A']);
+    }
+
+    #[test]
+    fn link_cable_exchanges_bytes() {
+        let (a, b) = LinkCable::pair();
+        let mut master = Serial::new();
+        let mut slave = Serial::new();
+        master.connect(Box::new(a));
+        slave.connect(Box::new(b));
+
+        slave.write(0xFF01, 0x42);
+        slave.write(0xFF02, 0x80);
+        assert!(!slave.tick());
+
+        master.write(0xFF01, 0x99);
+        master.write(0xFF02, 0x81);
+        assert!(slave.tick());
+        assert_eq!(slave.read(0xFF01), 0x99);
+
+        while !master.tick() {}
+        assert_eq!(master.read(0xFF01), 0x42);
+    }
+}
@@ -1,6 +1,8 @@
 use serde::{Serialize, Deserialize};
 use serde_json::Value;
 
+use crate::memory::{BusAccess, BusCycle};
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct CpuTest {
     pub name: String,
@@ -9,6 +11,32 @@ pub struct CpuTest {
     pub cycles: Vec<Vec<Value>>,
 }
 
+impl CpuTest {
+    // Each expected cycle is [address, data, pins]. Idle cycles only have to match the pins,
+    // the address and data left on the bus in between aren't checked
+    pub fn compare_cycles(&self, trace: &[BusCycle]) -> Result<(), String> {
+        for (i, (expected, actual)) in self.cycles.iter().zip(trace).enumerate() {
+            let pins = expected.get(2).and_then(|v| v.as_str()).unwrap_or("");
+            if pins != actual.pins() {
+                return Err(format!("Expected cycle {} to be {}, actual: {}", i, pins, actual.pins()))
+            }
+            if actual.access == BusAccess::Idle {
+                continue
+            }
+            let address = expected.first().and_then(|v| v.as_u64());
+            let data = expected.get(1).and_then(|v| v.as_u64());
+            if address != Some(actual.address as u64) || data != Some(actual.data as u64) {
+                return Err(format!("Expected cycle {} at {:#x}: {:#x?}, actual at {:#x}: {:#x}",
+                                   i, address.unwrap_or(0), data, actual.address, actual.data))
+            }
+        }
+        if self.cycles.len() != trace.len() {
+            return Err(format!("Expected {} cycles, actual: {}", self.cycles.len(), trace.len()))
+        }
+        Ok(())
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct InitialState {
     pub pc: u16,
@@ -0,0 +1,202 @@
+// Divider and timer, 0xFF04-0xFF07. Behaviour source: Pandocs and the Mooneye timer tests
+//   0xFF04  DIV, upper 8 bits of the 16-bit internal divider. Any write resets the divider
+//   0xFF05  TIMA, incremented when the selected divider bit falls from 1 to 0
+//   0xFF06  TMA, loaded into TIMA one M-cycle after it overflows
+//   0xFF07  TAC, Bit 2 enable, Bits 1-0 select the divider bit
+// TIMA increments on the falling edge of (divider bit AND enable), so resetting the divider or
+// changing TAC can increment TIMA when that signal drops
+
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+pub struct Timer {
+    divider: u16,
+    tima: u8,
+    tma: u8,
+    tac: u8,
+    // TIMA overflowed during the last M-cycle and reads 0x00 until the reload
+    overflow: bool,
+    // TMA was loaded into TIMA this M-cycle. Writes to TIMA are ignored and writes to TMA go through to TIMA
+    reloading: bool,
+}
+
+impl Timer {
+    pub fn new() -> Timer {
+        Timer {
+            divider: 0,
+            tima: 0,
+            tma: 0,
+            tac: 0,
+            overflow: false,
+            reloading: false,
+        }
+    }
+
+    // The divider value the DMG boot ROM leaves behind
+    pub fn simulate_bootloader(&mut self) {
+        self.divider = 0xABCC;
+        self.tima = 0x00;
+        self.tma = 0x00;
+        self.tac = 0x00;
+    }
+
+    pub fn read(&self, loc: u16) -> u8 {
+        match loc {
+            0xFF04 => (self.divider >> 8) as u8,
+            0xFF05 => self.tima,
+            0xFF06 => self.tma,
+            0xFF07 => self.tac | 0xF8,
+            _ => 0xFF
+        }
+    }
+
+    pub fn write(&mut self, loc: u16, val: u8) {
+        match loc {
+            0xFF04 => self.reset_divider(),
+            // Writing during the overflow cycle cancels the reload and the interrupt
+            0xFF05 if !self.reloading => {
+                self.tima = val;
+                self.overflow = false;
+            },
+            0xFF06 => {
+                self.tma = val;
+                if self.reloading {
+                    self.tima = val;
+                }
+            },
+            0xFF07 => {
+                let old = self.signal();
+                self.tac = val & 0x07;
+                if old && !self.signal() {
+                    self.increment();
+                }
+            },
+            _ => {}
+        }
+    }
+
+    pub fn reset_divider(&mut self) {
+        let old = self.signal();
+        self.divider = 0;
+        if old {
+            self.increment();
+        }
+    }
+
+    // Advances one M-cycle, returns true when the timer interrupt should be requested
+    pub fn tick(&mut self) -> bool {
+        self.reloading = false;
+        let mut interrupt = false;
+        if self.overflow {
+            self.tima = self.tma;
+            self.overflow = false;
+            self.reloading = true;
+            interrupt = true;
+        }
+
+        let old = self.signal();
+        self.divider = self.divider.wrapping_add(4);
+        if old && !self.signal() {
+            self.increment();
+        }
+        interrupt
+    }
+
+    fn signal(&self) -> bool {
+        let bit = match self.tac & 0x03 {
+            0 => 9,
+            1 => 3,
+            2 => 5,
+            _ => 7
+        };
+        self.tac & 0x04 > 0 && self.divider & (1 << bit) > 0
+    }
+
+    fn increment(&mut self) {
+        if self.tima == 0xFF {
+            self.tima = 0;
+            self.overflow = true;
+        } else {
+            self.tima += 1;
+        }
+    }
+
+    pub fn write_state(&self, w: &mut StateWriter) {
+        w.u16(self.divider);
+        w.u8(self.tima);
+        w.u8(self.tma);
+        w.u8(self.tac);
+        w.bool(self.overflow);
+        w.bool(self.reloading);
+    }
+
+    pub fn read_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.divider = r.u16()?;
+        self.tima = r.u8()?;
+        self.tma = r.u8()?;
+        self.tac = r.u8()? & 0x07;
+        self.overflow = r.bool()?;
+        self.reloading = r.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod timer_tests {
+    use crate::timer::Timer;
+
+    #[test]
+    fn div_resets_on_write() {
+        let mut timer = Timer::new();
+        for _ in 0..64 {
+            timer.tick();
+        }
+        assert_eq!(timer.read(0xFF04), 1);
+        timer.write(0xFF04, 0x42);
+        assert_eq!(timer.read(0xFF04), 0);
+    }
+
+    #[test]
+    fn overflow_reloads_one_cycle_later() {
+        let mut timer = Timer::new();
+        timer.write(0xFF06, 0x80);
+        timer.write(0xFF05, 0xFF);
+        timer.write(0xFF07, 0x05); // Every 4 M-cycles
+
+        for _ in 0..4 {
+            assert!(!timer.tick());
+        }
+        assert_eq!(timer.read(0xFF05), 0x00);
+        assert!(timer.tick());
+        assert_eq!(timer.read(0xFF05), 0x80);
+    }
+
+    #[test]
+    fn writing_tima_cancels_reload() {
+        let mut timer = Timer::new();
+        timer.write(0xFF06, 0x80);
+        timer.write(0xFF05, 0xFF);
+        timer.write(0xFF07, 0x05);
+        for _ in 0..4 {
+            timer.tick();
+        }
+        timer.write(0xFF05, 0x10);
+        assert!(!timer.tick());
+        assert_eq!(timer.read(0xFF05), 0x10);
+    }
+
+    #[test]
+    fn falling_edges_from_writes_increment_tima() {
+        let mut timer = Timer::new();
+        timer.write(0xFF07, 0x05);
+        timer.tick();
+        timer.tick(); // Divider bit 3 is now set
+        timer.write(0xFF04, 0);
+        assert_eq!(timer.read(0xFF05), 1);
+
+        timer.tick();
+        timer.tick();
+        // Disabling the timer while the selected bit is set also counts as a falling edge
+        timer.write(0xFF07, 0x01);
+        assert_eq!(timer.read(0xFF05), 2);
+    }
+}
@@ -0,0 +1,108 @@
+// Execution trace in the Gameboy Doctor format, one line per instruction with the state before it runs
+//   A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02
+// Gameboy Doctor expects LY to always read 0x90 while tracing, so Memory stubs it.
+// See https://github.com/robert/gameboy-doctor
+
+use std::{cell::RefCell, collections::VecDeque, rc::Rc};
+
+// Where trace lines go, set with CPU::set_trace_sink
+pub trait TraceSink {
+    fn write_line(&mut self, line: &str);
+}
+
+// Keeps the newest lines in memory. Clones share the same buffer, so one can be handed to the CPU
+// while another is used to take the lines
+#[derive(Clone)]
+pub struct RingBuffer {
+    lines: Rc<RefCell<VecDeque<String>>>,
+    capacity: usize,
+}
+
+impl RingBuffer {
+    pub fn new(capacity: usize) -> RingBuffer {
+        RingBuffer { lines: Rc::new(RefCell::new(VecDeque::new())), capacity: capacity.max(1) }
+    }
+
+    // The lines written since the last call, oldest first
+    pub fn take(&self) -> Vec<String> {
+        self.lines.borrow_mut().drain(..).collect()
+    }
+}
+
+impl TraceSink for RingBuffer {
+    fn write_line(&mut self, line: &str) {
+        let mut lines = self.lines.borrow_mut();
+        if lines.len() == self.capacity {
+            lines.pop_front();
+        }
+        lines.push_back(line.to_string());
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use file::FileSink;
+
+#[cfg(not(target_arch = "wasm32"))]
+mod file {
+    use std::{fs::File, io::{self, BufWriter, Write}, path::Path};
+
+    use super::TraceSink;
+
+    // Writes the trace to a file, flushed when the sink is dropped
+    pub struct FileSink {
+        writer: BufWriter<File>,
+    }
+
+    impl FileSink {
+        pub fn create<P: AsRef<Path>>(path: P) -> io::Result<FileSink> {
+            Ok(FileSink { writer: BufWriter::new(File::create(path)?) })
+        }
+    }
+
+    impl TraceSink for FileSink {
+        fn write_line(&mut self, line: &str) {
+            // A trace missing lines is useless for diffing anyway, so a failed write isn't reported
+            let _ = writeln!(self.writer, "{}", line);
+        }
+    }
+}
+
+#[cfg(test)]
+mod trace_tests {
+    use crate::cpu::CPU;
+    use crate::memory::Memory;
+    use crate::state::InitialState;
+    use crate::trace::{RingBuffer, TraceSink};
+
+    #[test]
+    fn ring_buffer_keeps_newest_lines() {
+        let buffer = RingBuffer::new(2);
+        let mut sink = buffer.clone();
+        for line in ["a", "b", "c"].iter() {
+            sink.write_line(line);
+        }
+        assert_eq!(buffer.take(), vec!["b", "c"]);
+        assert!(buffer.take().is_empty());
+    }
+
+    #[test]
+    fn logs_doctor_lines() {
+        let mut mem = Memory::new(None);
+        for (i, b) in [0x00, 0xC3, 0x13, 0x02].iter().enumerate() {
+            mem.write(0x0100 + i as u16, *b);
+        }
+        let mut cpu = CPU::new();
+        cpu.load_state(&InitialState {
+            pc: 0x0100, sp: 0xFFFE, a: 0x01, b: 0x00, c: 0x13, d: 0x00, e: 0xD8, f: 0xB0, h: 0x01, l: 0x4D, ime: 0, ie: 0, ram: Vec::new()
+        });
+        let buffer = RingBuffer::new(16);
+        cpu.set_trace_sink(Some(Box::new(buffer.clone())));
+
+        cpu.run(&mut mem);
+        cpu.run(&mut mem);
+        assert_eq!(buffer.take(), vec![
+            "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02",
+            "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0101 PCMEM:C3,13,02,00",
+        ]);
+    }
+}
@@ -0,0 +1,142 @@
+use wasm_bindgen::prelude::*;
+
+use crate::apu::Apu;
+use crate::audio::Resampler;
+
+// Records the APU output as 16-bit PCM. The mixed output is stereo, the optional per channel
+// recordings are mono and taken before panning and master volume.
+// Resampled the same way as AudioOutput, but without the high-pass filter so the recording matches
+// the APU output exactly
+pub struct WavRecorder {
+    resampler: Resampler,
+    mixed: Vec<i16>,
+    channels: Option<[Vec<i16>; 4]>,
+}
+
+impl WavRecorder {
+    pub fn new(sample_rate: u32, per_channel: bool) -> WavRecorder {
+        WavRecorder {
+            // Mixed left and right, followed by the four channels when they are recorded
+            resampler: Resampler::new(sample_rate, if per_channel { 6 } else { 2 }),
+            mixed: Vec::new(),
+            channels: if per_channel { Some(Default::default()) } else { None },
+        }
+    }
+
+    // Takes the APU output of one M-cycle
+    pub fn push(&mut self, apu: &Apu) {
+        let (left, right) = apu.output();
+        let [ch1, ch2, ch3, ch4] = if self.channels.is_some() { apu.channel_outputs() } else { [0.0; 4] };
+        let out = match self.resampler.push(&[left, right, ch1, ch2, ch3, ch4]) {
+            Some(out) => out,
+            None => return
+        };
+
+        self.mixed.push(to_pcm(out[0]));
+        self.mixed.push(to_pcm(out[1]));
+        if let Some(channels) = self.channels.as_mut() {
+            for (channel, sample) in channels.iter_mut().zip(out[2..].iter()) {
+                channel.push(to_pcm(*sample));
+            }
+        }
+    }
+
+    pub fn finish(self) -> WavRecording {
+        let sample_rate = self.resampler.sample_rate();
+        WavRecording {
+            mixed: encode_wav(&self.mixed, 2, sample_rate),
+            channels: self.channels
+                .map(|channels| channels.iter().map(|samples| encode_wav(samples, 1, sample_rate)).collect())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+// Finished recording as complete .wav files
+#[wasm_bindgen]
+pub struct WavRecording {
+    mixed: Vec<u8>,
+    channels: Vec<Vec<u8>>,
+}
+
+#[wasm_bindgen]
+impl WavRecording {
+    // Stereo output as heard
+    pub fn mixed(&self) -> Vec<u8> {
+        self.mixed.clone()
+    }
+
+    // Mono recording of channel 1-4, undefined if the channels weren't recorded separately
+    pub fn channel(&self, channel: usize) -> Option<Vec<u8>> {
+        self.channels.get(channel.checked_sub(1)?).cloned()
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl WavRecording {
+    // Writes <path>, and <path>_ch1.wav to <path>_ch4.wav when the channels were recorded separately
+    pub fn save(&self, path: &std::path::Path) -> std::io::Result<()> {
+        std::fs::write(path, &self.mixed)?;
+        let stem = path.with_extension("");
+        for (i, channel) in self.channels.iter().enumerate() {
+            std::fs::write(format!("{}_ch{}.wav", stem.display(), i + 1), channel)?;
+        }
+        Ok(())
+    }
+}
+
+fn to_pcm(sample: f32) -> i16 {
+    (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16
+}
+
+// Canonical 44 byte RIFF header followed by the interleaved samples
+fn encode_wav(samples: &[i16], channels: u16, sample_rate: u32) -> Vec<u8> {
+    let data_size = (samples.len() * 2) as u32;
+    let block_align = channels * 2;
+
+    let mut out = Vec::with_capacity(44 + data_size as usize);
+    out.extend_from_slice(b"RIFF");
+    out.extend_from_slice(&(36 + data_size).to_le_bytes());
+    out.extend_from_slice(b"WAVE");
+    out.extend_from_slice(b"fmt ");
+    out.extend_from_slice(&16u32.to_le_bytes());
+    out.extend_from_slice(&1u16.to_le_bytes()); // PCM
+    out.extend_from_slice(&channels.to_le_bytes());
+    out.extend_from_slice(&sample_rate.to_le_bytes());
+    out.extend_from_slice(&(sample_rate * block_align as u32).to_le_bytes());
+    out.extend_from_slice(&block_align.to_le_bytes());
+    out.extend_from_slice(&16u16.to_le_bytes());
+    out.extend_from_slice(b"data");
+    out.extend_from_slice(&data_size.to_le_bytes());
+    for sample in samples {
+        out.extend_from_slice(&sample.to_le_bytes());
+    }
+    out
+}
+
+#[cfg(test)]
+mod wav_tests {
+    use crate::{apu::Apu, audio::APU_SAMPLE_RATE, wav::WavRecorder};
+
+    #[test]
+    fn records_wav_file() {
+        let apu = Apu::new();
+        let mut recorder = WavRecorder::new(32768, true);
+        for _ in 0..APU_SAMPLE_RATE / 32 {
+            recorder.push(&apu);
+        }
+        let recording = recorder.finish();
+
+        let mixed = recording.mixed();
+        assert_eq!(&mixed[0..4], b"RIFF");
+        assert_eq!(&mixed[8..16], b"WAVEfmt ");
+        assert_eq!(u16::from_le_bytes([mixed[22], mixed[23]]), 2);
+        assert_eq!(u32::from_le_bytes([mixed[24], mixed[25], mixed[26], mixed[27]]), 32768);
+        // 1024 stereo frames of 16-bit samples
+        assert_eq!(mixed.len(), 44 + 1024 * 4);
+
+        assert_eq!(recording.channel(1).unwrap().len(), 44 + 1024 * 2);
+        assert!(recording.channel(0).is_none());
+        assert!(recording.channel(5).is_none());
+    }
+}
@@ -0,0 +1,125 @@
+// Runs the SM83 single step tests in tests/v1, see tests/README.MD.
+// Every test of every opcode file is run, then a table of passed and failed tests per opcode is printed.
+// Set SM83_OPCODES to a comma separated list of opcode prefixes to only run some files,
+// for example SM83_OPCODES="cb 4,c3" runs CB 40-4F and C3
+//   cargo test --test sm83 -- --nocapture
+
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use gameboy::cpu::CPU;
+use gameboy::memory::Memory;
+use gameboy::state::CpuTest;
+
+const TEST_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/v1");
+const FILTER_VAR: &str = "SM83_OPCODES";
+
+struct OpcodeResult {
+    opcode: String,
+    passed: usize,
+    failed: usize,
+    first_failure: Option<String>,
+}
+
+#[test]
+fn sm83_single_step_tests() {
+    let filter = opcode_filter();
+    let files = test_files(&filter);
+    assert!(!files.is_empty(), "No test files in {} match {}", TEST_DIR, FILTER_VAR);
+
+    let results: Vec<OpcodeResult> = files.iter().map(|path| run_file(path)).collect();
+    print_summary(&results);
+
+    let failures: Vec<String> = results.iter()
+        .filter_map(|r| r.first_failure.as_ref().map(|f| format!("{} ({} failed): {}", r.opcode, r.failed, f)))
+        .collect();
+    assert!(failures.is_empty(), "{} of {} opcodes failed\n{}", failures.len(), results.len(), failures.join("\n"));
+}
+
+// Lowercase opcode prefixes from SM83_OPCODES, empty to run everything
+fn opcode_filter() -> Vec<String> {
+    std::env::var(FILTER_VAR)
+        .unwrap_or_default()
+        .split(',')
+        .map(|s| s.trim().to_lowercase())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+fn test_files(filter: &[String]) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = fs::read_dir(TEST_DIR)
+        .expect("SM83 test directory should exist")
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .filter(|path| filter.is_empty() || filter.iter().any(|f| opcode_name(path).starts_with(f.as_str())))
+        .collect();
+    files.sort();
+    files
+}
+
+// The file stem, like "3e" or "cb 7c"
+fn opcode_name(path: &Path) -> String {
+    path.file_stem().unwrap().to_string_lossy().to_lowercase()
+}
+
+fn run_file(path: &Path) -> OpcodeResult {
+    let mut result = OpcodeResult { opcode: opcode_name(path).to_uppercase(), passed: 0, failed: 0, first_failure: None };
+    let tests = match read_json_file(path) {
+        Ok(tests) => tests,
+        Err(e) => {
+            result.failed = 1;
+            result.first_failure = Some(format!("Couldn't read {}: {}", path.display(), e));
+            return result
+        }
+    };
+
+    for test in &tests {
+        match run_test(test) {
+            Ok(()) => result.passed += 1,
+            Err(e) => {
+                result.failed += 1;
+                if result.first_failure.is_none() {
+                    result.first_failure = Some(format!("{}: {}", test.name, e));
+                }
+            }
+        }
+    }
+    result
+}
+
+fn run_test(test: &CpuTest) -> Result<(), String> {
+    let mut cpu = CPU::new();
+    let mut mem = Memory::new(None);
+    cpu.load_state(&test.initial);
+    mem.load_state(&test.initial);
+
+    mem.start_trace();
+    let mut cycles = 0;
+    while cycles < test.cycles.len() {
+        cycles += cpu.run(&mut mem) as usize;
+    }
+    let trace = mem.take_trace();
+
+    test.compare_cycles(&trace)?;
+    cpu.compare_state(&test.r#final)?;
+    mem.compare_state(&test.r#final)
+}
+
+fn print_summary(results: &[OpcodeResult]) {
+    println!("{:<8} {:>7} {:>7}", "Opcode", "Passed", "Failed");
+    for r in results {
+        let mark = if r.failed == 0 { "" } else { "  <--" };
+        println!("{:<8} {:>7} {:>7}{}", r.opcode, r.passed, r.failed, mark);
+    }
+    let passed: usize = results.iter().map(|r| r.passed).sum();
+    let failed: usize = results.iter().map(|r| r.failed).sum();
+    println!("{:<8} {:>7} {:>7}", "Total", passed, failed);
+}
+
+fn read_json_file(path: &Path) -> Result<Vec<CpuTest>, Box<dyn Error>> {
+    let file = File::open(path)?;
+    let reader = BufReader::new(file);
+    Ok(serde_json::from_reader(reader)?)
+}
@@ -54,7 +54,12 @@ function App() {
     }, [dynamic])
 
     let chooseGame = (g: Game) => {
-        setGb(GameBoy.new(g.data, g.name))
+        try {
+            setGb(GameBoy.new(g.data, g.name))
+        } catch (e) {
+            alert(`Could not load ${g.name}: ${e instanceof Error ? e.message : e}`)
+            return
+        }
         setShowGameSelect(false)
     }
 
@@ -278,6 +283,11 @@ function App() {
             speedCountRef.current -= 1
         }
 
+        let fault = gb?.take_fault()
+        if (fault) {
+            alert(`The game crashed: illegal instruction 0x${fault.opcode.toString(16)} at 0x${fault.pc.toString(16)}`)
+        }
+
         gb?.draw_frame(ctx)
         animationRef.current = requestAnimationFrame(loop)
     }